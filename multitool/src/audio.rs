@@ -5,6 +5,7 @@ use rubato::{FftFixedIn, Resampler};
 use std::fs::File;
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::Mutex;
 use std::sync::{
     Arc,
@@ -235,6 +236,19 @@ impl AudioBuffer {
         })
     }
 
+    /// Build a buffer from raw mono samples
+    pub fn from_samples(
+        sample_rate: u32,
+        samples: Vec<i16>,
+        loop_sample: Option<u32>,
+    ) -> AudioBuffer {
+        AudioBuffer {
+            sample_rate,
+            samples,
+            loop_sample,
+        }
+    }
+
     pub fn samples(&self) -> &[i16] {
         &self.samples
     }
@@ -286,80 +300,234 @@ impl AudioBuffer {
 
     /// Play the audio on the system's default output device
     pub fn playback(&self) -> Result<()> {
-        let host = cpal::default_host();
-        let device = host
-            .default_output_device()
-            .ok_or_else(|| anyhow!("No output device available"))?;
-        let config = device.default_output_config()?.config();
+        play_samples(
+            self.sample_rate,
+            1,
+            &self.samples,
+            self.loop_sample.map(|ls| ls as usize),
+        )
+    }
 
-        info!(
-            "Initiating playback on `{}` (sample rate: {}Hz)",
-            device.name()?,
-            config.sample_rate.0
-        );
+    /// Set up the loop as requested in `opts`
+    pub fn apply_loop_options(&mut self, opts: &LoopOptions) -> Result<()> {
+        let rate = self.sample_rate;
 
-        let buf = self.resample(config.sample_rate.0)?;
+        if opts.disable {
+            self.clear_loop();
+        }
 
-        let channels = config.channels as usize;
+        if opts.start.is_some() || opts.end.is_some() || opts.auto {
+            let end = opts.end.map_or(self.samples.len(), |e| e.to_sample(rate));
 
-        let finished = Arc::new(AtomicBool::new(false));
-        let sample_index = Arc::new(Mutex::new(0));
+            let start = match opts.start {
+                Some(s) => s.to_sample(rate),
+                None if opts.auto => {
+                    self.set_loop(0, end)?;
 
-        let finished_clone = Arc::clone(&finished);
+                    let min_len = AudioPos::Seconds(opts.min_len).to_sample(rate);
 
-        let stream = device.build_output_stream(
-            &config,
-            move |data: &mut [i16], _: &cpal::OutputCallbackInfo| {
-                let mut index = sample_index.lock().unwrap();
+                    let Some((start, error)) = self.find_loop_start(min_len) else {
+                        bail!("Audio is too short to look for a loop");
+                    };
 
-                for frame in data.chunks_mut(channels) {
-                    if *index >= buf.samples.len() {
-                        match buf.loop_sample {
-                            Some(ls) if (ls as usize) < buf.samples.len() => *index = ls as usize,
-                            _ => {
-                                finished_clone.store(true, Ordering::SeqCst);
-                                for sample_out in frame.iter_mut() {
-                                    *sample_out = 0;
-                                }
-                                continue;
-                            }
-                        }
-                    }
-                    let sample = buf.samples[*index];
-                    for sample_out in frame.iter_mut() {
-                        *sample_out = sample;
-                    }
-                    *index += 1;
+                    info!("Found loop start at sample {start} (error {error:.04})");
+
+                    start
                 }
-            },
-            |err| eprintln!("Stream error: {err}"),
-            None,
-        )?;
+                // Only the end was given: keep the file's loop start if any
+                None => match self.loop_sample {
+                    Some(ls) => ls as usize,
+                    None => bail!("A loop end requires a loop start"),
+                },
+            };
+
+            self.set_loop(start, end)?;
+        }
 
-        stream.play()?;
+        if let Some(len) = opts.crossfade {
+            self.crossfade_loop(AudioPos::Seconds(len).to_sample(rate));
+        }
+
+        Ok(())
+    }
 
-        while !finished.load(Ordering::SeqCst) {
-            thread::sleep(Duration::from_millis(100));
+    /// Loop the buffer from `start` to `end`. Everything past `end` is dropped.
+    pub fn set_loop(&mut self, start: usize, end: usize) -> Result<()> {
+        if start >= end || end > self.samples.len() {
+            bail!(
+                "Invalid loop {start}..{end} for a buffer of {} samples",
+                self.samples.len()
+            );
         }
-        thread::sleep(Duration::from_millis(300));
+
+        self.samples.truncate(end);
+        self.loop_sample = Some(start as u32);
 
         Ok(())
     }
 
-    pub fn dump_nrad<W: Write>(&self, w: &mut W) -> Result<()> {
-        w.write_all(b"NRAD")?;
+    pub fn clear_loop(&mut self) {
+        self.loop_sample = None;
+    }
+
+    /// Look for the loop start that gives the smoothest transition when jumping back from the end
+    /// of the buffer, by comparing the waveform leading to the end of the buffer with the one
+    /// leading to each candidate start. The loop will be at least `min_len` samples long.
+    ///
+    /// Returns the loop start and its matching error: 0.0 for a perfect match, 1.0 for
+    /// uncorrelated signals.
+    pub fn find_loop_start(&self, min_len: usize) -> Option<(usize, f32)> {
+        // An empty loop would always be a perfect match
+        let min_len = min_len.max(1);
+        let end = self.samples.len();
+        let window = LOOP_SEARCH_WINDOW.min(end / 4);
+
+        if window == 0 || end < min_len + window {
+            return None;
+        }
+
+        let target: Vec<f32> = self.samples[end - window..]
+            .iter()
+            .map(|&s| f32::from(s))
+            .collect();
+
+        let target_energy: f32 = target.iter().map(|s| s * s).sum();
+
+        let error = |start: usize| -> f32 {
+            let candidate = &self.samples[start - window..start];
 
+            let mut diff = 0.;
+            let mut energy = target_energy;
+
+            for (&t, &c) in target.iter().zip(candidate) {
+                let c = f32::from(c);
+
+                diff += (t - c) * (t - c);
+                energy += c * c;
+            }
+
+            if energy > 0. { diff / energy } else { 0. }
+        };
+
+        let candidates = window..=(end - min_len);
+
+        // Coarse search first, then refine around the best match
+        let coarse = candidates
+            .clone()
+            .step_by(LOOP_SEARCH_STEP)
+            .map(|start| (start, error(start)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))?;
+
+        let fine = (coarse.0.saturating_sub(LOOP_SEARCH_STEP)..=coarse.0 + LOOP_SEARCH_STEP)
+            .filter(|start| candidates.contains(start))
+            .map(|start| (start, error(start)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))?;
+
+        Some(fine)
+    }
+
+    /// Crossfade the last `len` samples of the buffer with the ones leading to the loop start,
+    /// to smooth out the transition when jumping back
+    pub fn crossfade_loop(&mut self, len: usize) {
+        let Some(start) = self.loop_sample.map(|ls| ls as usize) else {
+            return;
+        };
+
+        let end = self.samples.len();
+        let len = len.min(start).min(end - start);
+
+        for i in 0..len {
+            let fade_in = (i + 1) as f32 / (len + 1) as f32;
+
+            let s = f32::from(self.samples[end - len + i]) * (1. - fade_in)
+                + f32::from(self.samples[start - len + i]) * fade_in;
+
+            self.samples[end - len + i] = s.round() as i16;
+        }
+    }
+
+    /// The SPU can only loop on ADPCM block boundaries. Modify the buffer so that the loop starts
+    /// and ends on a block boundary while sounding the same.
+    ///
+    /// The loop length is fixed by unrolling the loop if that's cheap enough, otherwise by
+    /// resampling the buffer and adjusting the sample rate to preserve the pitch. The loop start
+    /// is then moved to the next block boundary by appending the beginning of the loop at the end.
+    pub fn align_loop(&mut self) {
+        let Some(start) = self.loop_sample.map(|ls| ls as usize) else {
+            return;
+        };
+
+        let mut start = start;
+        let mut loop_len = self.samples.len() - start;
+
+        if !loop_len.is_multiple_of(ADPCM_BLOCK_SAMPLES) {
+            let repeat = ADPCM_BLOCK_SAMPLES / gcd(loop_len, ADPCM_BLOCK_SAMPLES);
+
+            if loop_len * repeat <= LOOP_UNROLL_MAX {
+                let body = self.samples[start..].to_vec();
+
+                for _ in 1..repeat {
+                    self.samples.extend_from_slice(&body);
+                }
+
+                loop_len *= repeat;
+            } else {
+                let target_len = (loop_len + ADPCM_BLOCK_SAMPLES / 2) / ADPCM_BLOCK_SAMPLES
+                    * ADPCM_BLOCK_SAMPLES;
+                let ratio = target_len as f64 / loop_len as f64;
+
+                self.samples = stretch(&self.samples, start, ratio);
+                self.sample_rate = (f64::from(self.sample_rate) * ratio).round() as u32;
+
+                start = self.samples.len() - target_len;
+                loop_len = target_len;
+
+                debug!(
+                    "Loop resampled by {ratio:.05}, new sample rate {}Hz",
+                    self.sample_rate
+                );
+            }
+        }
+
+        let shift = (ADPCM_BLOCK_SAMPLES - start % ADPCM_BLOCK_SAMPLES) % ADPCM_BLOCK_SAMPLES;
+
+        for i in 0..shift {
+            self.samples.push(self.samples[start + i]);
+        }
+
+        start += shift;
+
+        debug_assert_eq!(start % ADPCM_BLOCK_SAMPLES, 0);
+        debug_assert_eq!(self.samples.len() - start, loop_len);
+
+        self.loop_sample = Some(start as u32);
+    }
+
+    /// SPU step value to play the buffer at its sample rate
+    pub fn spu_step(&self) -> u16 {
         // The NovaRave SPU runs at 44.1kHz and uses 12 fractional bits when stepping.
         let spu_base: u32 = 44_100;
 
         // Divider to reach the sample rate
         let spu_step = ((self.sample_rate << 12) + spu_base / 2) / spu_base;
 
-        let spu_step = if spu_step > 0x3fff {
-            0x3fff
-        } else {
-            spu_step as u16
-        };
+        spu_step.min(0x3fff) as u16
+    }
+
+    /// Scale all the samples by `gain`, saturating if necessary
+    pub fn amplify(&mut self, gain: f32) {
+        for s in self.samples.iter_mut() {
+            *s = (f32::from(*s) * gain)
+                .round()
+                .clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        }
+    }
+
+    pub fn dump_nrad<W: Write>(&self, w: &mut W, opts: &EncodeOptions) -> Result<()> {
+        w.write_all(b"NRAD")?;
+
+        let spu_base: u32 = 44_100;
+        let spu_step = self.spu_step();
 
         info!(
             "SPU_STEP will be 0x{:x} ({:.03}) resulting in a true sample rate of {}Hz",
@@ -372,62 +540,47 @@ impl AudioBuffer {
         w.write_u16::<LittleEndian>(0)?;
         w.write_u16::<LittleEndian>(spu_step)?;
 
+        self.dump_adpcm(w, opts)
+    }
+
+    /// Encode the samples as raw ADPCM blocks, without the NRAD header. The buffer must not be
+    /// empty.
+    pub fn dump_adpcm<W: Write>(&self, w: &mut W, opts: &EncodeOptions) -> Result<()> {
+        if self.samples.is_empty() {
+            bail!("Can't encode an empty audio buffer");
+        }
+
         // We encode blocks of 28 samples. Each encoded sample will be 4 bits, plus 2B header for a
         // total of 16B per block
-        let block_len = 28;
+        let blocks: Vec<[i16; ADPCM_BLOCK_SAMPLES]> = self
+            .samples
+            .chunks(ADPCM_BLOCK_SAMPLES)
+            .map(|block| {
+                // Make sure the last block is full by copying the last sample as padding
+                let mut samples = [*block.last().unwrap(); ADPCM_BLOCK_SAMPLES];
+                samples[..block.len()].copy_from_slice(block);
+
+                samples
+            })
+            .collect();
 
-        let nblocks = self.samples.len().div_ceil(block_len);
+        let encoded = if opts.is_greedy() {
+            adpcm_encode_greedy(&blocks)
+        } else {
+            adpcm_encode_beam(&blocks, opts)
+        };
 
-        let mut start = true;
+        let nblocks = blocks.len();
 
         let mut prev_samples = [0, 0];
-
         let mut total_error = 0;
+        let mut signal_energy = 0f64;
+        let mut noise_energy = 0f64;
 
-        for (i, block) in self.samples.chunks(block_len).enumerate() {
+        for (i, (block, e)) in blocks.iter().zip(encoded.iter()).enumerate() {
             let stop = (i + 1) == nblocks;
 
-            let mut samples: Vec<i16> = block.to_vec();
-
-            // Make sure the last block is full by copying the last sample as padding
-            samples.resize(block_len, *samples.last().unwrap());
-
-            let mut filter = 0;
-
-            let (mut encoded, mut shift) = adpcm_encode_block(&samples, prev_samples, filter);
-
-            let mut decoded = adpcm_decode_block(&encoded, prev_samples, filter, shift);
-
-            // Try the other filters to see if we get a better match.
-            let mut error = adpcm_error(&samples, &decoded);
-
-            // If we're (re)starting, we don't know what's in prev_samples, therefore we cannot use
-            // any filter besides 0 (that ignores the previous samples)
-            if start {
-                start = false;
-            } else if error > 0 {
-                for f in 1..FILTER_WEIGHTS.len() {
-                    let (fencoded, fshift) = adpcm_encode_block(&samples, prev_samples, f);
-
-                    let fdecoded = adpcm_decode_block(&fencoded, prev_samples, f, fshift);
-
-                    let ferror = adpcm_error(&samples, &fdecoded);
-
-                    if ferror < error {
-                        filter = f;
-                        error = ferror;
-                        shift = fshift;
-                        encoded = fencoded;
-                        decoded = fdecoded;
-
-                        if error == 0 {
-                            break;
-                        }
-                    }
-                }
-            }
-
-            let mut header = ((stop as u16) << 8) | ((filter as u16) << 4) | (shift as u16);
+            let mut header = ((stop as u16) << 8) | ((e.filter as u16) << 4) | (e.shift as u16);
             if let Some(ls) = self.loop_sample {
                 // In my experience this bit is set on all samples when a sample loops, even though
                 // it's only useful on the "end" sample?
@@ -441,14 +594,24 @@ impl AudioBuffer {
 
             w.write_u16::<LittleEndian>(header)?;
 
-            for e in encoded {
+            for &e in e.data.iter() {
                 w.write_u16::<LittleEndian>(e)?;
             }
 
-            total_error += error;
+            let decoded = adpcm_decode_block(&e.data, prev_samples, e.filter, e.shift);
 
-            prev_samples[0] = decoded[block_len - 1];
-            prev_samples[1] = decoded[block_len - 2];
+            total_error += adpcm_error(block, &decoded);
+
+            // Don't count the padding in the SNR
+            let len = (self.samples.len() - i * ADPCM_BLOCK_SAMPLES).min(ADPCM_BLOCK_SAMPLES);
+
+            for (&s, &d) in block.iter().zip(decoded.iter()).take(len) {
+                signal_energy += f64::from(s).powi(2);
+                noise_energy += (f64::from(s) - f64::from(d)).powi(2);
+            }
+
+            prev_samples[0] = decoded[ADPCM_BLOCK_SAMPLES - 1];
+            prev_samples[1] = decoded[ADPCM_BLOCK_SAMPLES - 2];
         }
 
         info!(
@@ -456,10 +619,268 @@ impl AudioBuffer {
             (total_error as f32) / (self.samples.len() as f32)
         );
 
+        if noise_energy > 0. {
+            info!(
+                "SNR: {:.02}dB",
+                10. * (signal_energy / noise_energy).log10()
+            );
+        } else {
+            info!("SNR: lossless");
+        }
+
         Ok(())
     }
 }
 
+/// ADPCM encoder settings
+pub struct EncodeOptions {
+    /// Number of candidate encodings kept at each block by the beam search. 1 means that we use
+    /// the fast, greedy encoder.
+    pub beam_width: usize,
+    /// Feed the quantization error back into the encoder to push the noise towards the high
+    /// frequencies. Only supported by the beam search encoder.
+    pub noise_shaping: bool,
+}
+
+impl EncodeOptions {
+    pub fn new() -> EncodeOptions {
+        EncodeOptions {
+            beam_width: 1,
+            noise_shaping: false,
+        }
+    }
+
+    pub fn beam_width(&mut self, width: usize) -> &mut Self {
+        self.beam_width = width.max(1);
+
+        self
+    }
+
+    pub fn noise_shaping(&mut self, shaping: bool) -> &mut Self {
+        self.noise_shaping = shaping;
+
+        self
+    }
+
+    fn is_greedy(&self) -> bool {
+        self.beam_width <= 1 && !self.noise_shaping
+    }
+}
+
+/// An encoded ADPCM block
+#[derive(Clone)]
+struct EncodedBlock {
+    filter: usize,
+    /// Shift value as stored in the block header
+    shift: u8,
+    data: Vec<u16>,
+}
+
+/// Encode `blocks` one at a time, picking the filter giving the smallest error for each of them
+fn adpcm_encode_greedy(blocks: &[[i16; ADPCM_BLOCK_SAMPLES]]) -> Vec<EncodedBlock> {
+    let mut start = true;
+
+    let mut prev_samples = [0, 0];
+
+    let mut res = Vec::with_capacity(blocks.len());
+
+    for samples in blocks {
+        let mut filter = 0;
+
+        let (mut encoded, mut shift) = adpcm_encode_block(samples, prev_samples, filter);
+
+        let mut decoded = adpcm_decode_block(&encoded, prev_samples, filter, shift);
+
+        // Try the other filters to see if we get a better match.
+        let mut error = adpcm_error(samples, &decoded);
+
+        // If we're (re)starting, we don't know what's in prev_samples, therefore we cannot use
+        // any filter besides 0 (that ignores the previous samples)
+        if start {
+            start = false;
+        } else if error > 0 {
+            for f in 1..FILTER_WEIGHTS.len() {
+                let (fencoded, fshift) = adpcm_encode_block(samples, prev_samples, f);
+
+                let fdecoded = adpcm_decode_block(&fencoded, prev_samples, f, fshift);
+
+                let ferror = adpcm_error(samples, &fdecoded);
+
+                if ferror < error {
+                    filter = f;
+                    error = ferror;
+                    shift = fshift;
+                    encoded = fencoded;
+                    decoded = fdecoded;
+
+                    if error == 0 {
+                        break;
+                    }
+                }
+            }
+        }
+
+        prev_samples[0] = decoded[ADPCM_BLOCK_SAMPLES - 1];
+        prev_samples[1] = decoded[ADPCM_BLOCK_SAMPLES - 2];
+
+        res.push(EncodedBlock {
+            filter,
+            shift,
+            data: encoded,
+        });
+    }
+
+    res
+}
+
+/// Encode `blocks` using a beam search: for every block we try all the filters and a range of
+/// shift values on each of the best `beam_width` encodings found so far, and keep the best
+/// `beam_width` results. This lets us pick a locally worse encoding for a block if it leaves the
+/// decoder in a better state for the following ones.
+fn adpcm_encode_beam(
+    blocks: &[[i16; ADPCM_BLOCK_SAMPLES]],
+    opts: &EncodeOptions,
+) -> Vec<EncodedBlock> {
+    struct Node {
+        /// Index of the node for the previous block in `nodes`
+        parent: Option<usize>,
+        block: EncodedBlock,
+        state: EncoderState,
+        /// Accumulated squared error
+        cost: f64,
+    }
+
+    let shaping = if opts.noise_shaping {
+        NOISE_SHAPING_COEF
+    } else {
+        0.
+    };
+
+    let mut nodes: Vec<Node> = Vec::new();
+    // Indices of the nodes for the current block in `nodes`
+    let mut beam: Vec<usize> = Vec::new();
+
+    for (i, samples) in blocks.iter().enumerate() {
+        let mut candidates = Vec::new();
+
+        let parents: Vec<Option<usize>> = if i == 0 {
+            vec![None]
+        } else {
+            beam.iter().map(|&n| Some(n)).collect()
+        };
+
+        for parent in parents {
+            let (state, cost) = match parent {
+                Some(p) => (nodes[p].state, nodes[p].cost),
+                None => (EncoderState::default(), 0.),
+            };
+
+            // The first block can only use filter 0 since we don't know what's in the decoder's
+            // history
+            let filters = if i == 0 {
+                0..1
+            } else {
+                0..FILTER_WEIGHTS.len()
+            };
+
+            for filter in filters {
+                let prev = [state.prev[0] as i16, state.prev[1] as i16];
+                let base_shift = i32::from(adpcm_shift(samples, prev, filter));
+
+                for shift in (base_shift - 1).max(0)..=(base_shift + 1).min(12) {
+                    let (block, state, error) =
+                        adpcm_encode_block_search(samples, state, filter, shift as u8, shaping);
+
+                    candidates.push(Node {
+                        parent,
+                        block,
+                        state,
+                        cost: cost + error,
+                    });
+                }
+            }
+        }
+
+        candidates.sort_by(|a, b| a.cost.total_cmp(&b.cost));
+        candidates.truncate(opts.beam_width.max(1));
+
+        beam.clear();
+
+        for c in candidates {
+            beam.push(nodes.len());
+            nodes.push(c);
+        }
+    }
+
+    // Walk back from the best final node
+    let mut res = Vec::with_capacity(blocks.len());
+    let mut cur = beam.first().copied();
+
+    while let Some(n) = cur {
+        res.push(nodes[n].block.clone());
+        cur = nodes[n].parent;
+    }
+
+    res.reverse();
+
+    res
+}
+
+/// Decoder history plus the noise shaping feedback
+#[derive(Clone, Copy, Default)]
+struct EncoderState {
+    /// Last two decoded samples
+    prev: [i32; 2],
+    /// Last quantization error
+    error: f32,
+}
+
+/// Encode a block with the given filter and shift (in encoder terms: how many bits the difference
+/// is shifted right), rounding each difference to the nearest value. Returns the block, the
+/// state after decoding it and its squared error.
+fn adpcm_encode_block_search(
+    samples: &[i16],
+    mut state: EncoderState,
+    filter: usize,
+    shift: u8,
+    shaping: f32,
+) -> (EncodedBlock, EncoderState, f64) {
+    let (wp, wn) = FILTER_WEIGHTS[filter];
+    let wp = i32::from(wp);
+    let wn = i32::from(wn);
+
+    let mut data = vec![0u16; samples.len() / 4];
+    let mut error = 0f64;
+
+    for (i, &s) in samples.iter().enumerate() {
+        let target = f32::from(s) - shaping * state.error;
+
+        let predicted = ((state.prev[0] * wp) >> 6) + ((state.prev[1] * wn) >> 6);
+
+        let diff = ((target - predicted as f32) / (1 << shift) as f32)
+            .round()
+            .clamp(-8., 7.) as i32;
+
+        let decoded = (predicted + (diff << shift)).clamp(i32::from(i16::MIN), i32::from(i16::MAX));
+
+        let e = decoded as f32 - target;
+        error += f64::from(e) * f64::from(e);
+
+        state.error = e;
+        state.prev = [decoded, state.prev[0]];
+
+        data[i / 4] |= ((diff as u16) & 0xf) << ((i & 3) * 4);
+    }
+
+    let block = EncodedBlock {
+        filter,
+        shift: 12 - shift,
+        data,
+    };
+
+    (block, state, error)
+}
+
 /// Encodes `samples` with the given `filter`. Returns the encoded buffer and the shift value used.
 ///
 /// If filter is not 0 then `prev_samples` should be the last two *decoded* samples from the
@@ -473,6 +894,52 @@ fn adpcm_encode_block(samples: &[i16], prev_samples: [i16; 2], filter: usize) ->
     let wp = wp as i32;
     let wn = wn as i32;
 
+    let shift = adpcm_shift(samples, prev_samples, filter);
+
+    // Now that we have the shift, we can encode properly
+    let mut ps = [i32::from(prev_samples[0]), i32::from(prev_samples[1])];
+
+    let mut e = 0u16;
+    let mut res = Vec::with_capacity(samples.len() / 4);
+
+    for (i, &s) in samples.iter().enumerate() {
+        let s = i32::from(s);
+
+        let mut predicted = 0;
+
+        predicted += (ps[0] * wp) >> 6;
+        predicted += (ps[1] * wn) >> 6;
+
+        let diff = (s - predicted) >> shift;
+
+        let diff = diff.clamp(-8, 7);
+
+        predicted += diff << shift;
+        predicted = predicted.clamp(i32::from(i16::MIN), i32::from(i16::MAX));
+
+        ps[1] = ps[0];
+        ps[0] = predicted;
+
+        let encoded = (diff as u16) & 0xf;
+
+        let bpos = (i & 3) * 4;
+        e |= encoded << bpos;
+        if bpos == 12 {
+            res.push(e);
+            e = 0;
+        }
+    }
+
+    (res, 12 - shift)
+}
+
+/// Find the shift value (how many bits the difference is shifted right) to encode `samples` with
+/// the given `filter`
+fn adpcm_shift(samples: &[i16], prev_samples: [i16; 2], filter: usize) -> u8 {
+    let (wp, wn) = FILTER_WEIGHTS[filter];
+    let wp = wp as i32;
+    let wn = wn as i32;
+
     let mut diff_max = 0;
     let mut diff_min = -1;
 
@@ -514,43 +981,7 @@ fn adpcm_encode_block(samples: &[i16], prev_samples: [i16; 2], filter: usize) ->
     let shift_pos = significant_bit_pos - 4;
     let shift_neg = significant_bit_neg - 4;
 
-    let shift = shift_pos.max(shift_neg).clamp(0, 12) as u8;
-
-    // Now that we have the shift, we can encode properly
-    let mut ps = [i32::from(prev_samples[0]), i32::from(prev_samples[1])];
-
-    let mut e = 0u16;
-    let mut res = Vec::with_capacity(samples.len() / 4);
-
-    for (i, &s) in samples.iter().enumerate() {
-        let s = i32::from(s);
-
-        let mut predicted = 0;
-
-        predicted += (ps[0] * wp) >> 6;
-        predicted += (ps[1] * wn) >> 6;
-
-        let diff = (s - predicted) >> shift;
-
-        let diff = diff.clamp(-8, 7);
-
-        predicted += diff << shift;
-        predicted = predicted.clamp(i32::from(i16::MIN), i32::from(i16::MAX));
-
-        ps[1] = ps[0];
-        ps[0] = predicted;
-
-        let encoded = (diff as u16) & 0xf;
-
-        let bpos = (i & 3) * 4;
-        e |= encoded << bpos;
-        if bpos == 12 {
-            res.push(e);
-            e = 0;
-        }
-    }
-
-    (res, 12 - shift)
+    shift_pos.max(shift_neg).clamp(0, 12) as u8
 }
 
 fn adpcm_decode_block(
@@ -603,6 +1034,192 @@ fn adpcm_error(source: &[i16], decoded: &[i16]) -> u32 {
         .sum()
 }
 
+/// Loop settings for an audio buffer
+pub struct LoopOptions {
+    /// Loop start. Overrides the loop found in the input file, if any.
+    pub start: Option<AudioPos>,
+    /// Loop end, everything after it is dropped. Defaults to the end of the buffer.
+    pub end: Option<AudioPos>,
+    /// Look for the loop start that gives the smoothest transition from the loop end
+    pub auto: bool,
+    /// Minimum loop length for the automatic search, in seconds
+    pub min_len: f32,
+    /// Length of the crossfade applied at the end of the loop, in seconds
+    pub crossfade: Option<f32>,
+    /// Ignore the loop found in the input file
+    pub disable: bool,
+}
+
+/// A position in an audio buffer, either as a sample index or as a time in seconds (with an `s`
+/// suffix on the command line)
+#[derive(Clone, Copy, Debug)]
+pub enum AudioPos {
+    Sample(usize),
+    Seconds(f32),
+}
+
+impl AudioPos {
+    pub fn to_sample(self, sample_rate: u32) -> usize {
+        match self {
+            AudioPos::Sample(s) => s,
+            AudioPos::Seconds(t) => (t * sample_rate as f32).round() as usize,
+        }
+    }
+}
+
+impl FromStr for AudioPos {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<AudioPos, String> {
+        let pos = match s.strip_suffix('s') {
+            Some(t) => t.parse().ok().map(AudioPos::Seconds),
+            None => s.parse().ok().map(AudioPos::Sample),
+        };
+
+        pos.ok_or_else(|| format!("Invalid audio position '{s}'"))
+    }
+}
+
+/// Resample `samples` by `ratio` using cubic interpolation, keeping the loop region starting at
+/// `loop_start` exactly `round((len - loop_start) * ratio)` long
+fn stretch(samples: &[i16], loop_start: usize, ratio: f64) -> Vec<i16> {
+    let at = |i: isize| -> f64 {
+        let i = i.clamp(0, samples.len() as isize - 1) as usize;
+
+        f64::from(samples[i])
+    };
+
+    // Position 0 of the new buffer maps to the loop start so that it lands on an output sample
+    let loop_start_out = (loop_start as f64 * ratio).round() as isize;
+    let out_len =
+        loop_start_out as usize + ((samples.len() - loop_start) as f64 * ratio).round() as usize;
+
+    (0..out_len)
+        .map(|o| {
+            let pos = loop_start as f64 + (o as isize - loop_start_out) as f64 / ratio;
+
+            let i = pos.floor() as isize;
+            let t = pos - pos.floor();
+
+            let (p0, p1, p2, p3) = (at(i - 1), at(i), at(i + 1), at(i + 2));
+
+            // Catmull-Rom spline
+            let s = p1
+                + 0.5
+                    * t
+                    * (p2 - p0
+                        + t * (2. * p0 - 5. * p1 + 4. * p2 - p3 + t * (3. * (p1 - p2) + p3 - p0)));
+
+            s.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16
+        })
+        .collect()
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// Number of samples in an ADPCM block
+pub const ADPCM_BLOCK_SAMPLES: usize = 28;
+
+/// Loops shorter than this (once unrolled to a multiple of ADPCM_BLOCK_SAMPLES) are unrolled
+/// instead of being resampled
+const LOOP_UNROLL_MAX: usize = 4096;
+
+/// Length of the waveform compared when looking for a loop point
+const LOOP_SEARCH_WINDOW: usize = 1024;
+
+/// Granularity of the coarse loop point search
+const LOOP_SEARCH_STEP: usize = 4;
+
+/// Quantization error feedback used for noise shaping. The noise spectrum ends up shaped by
+/// `1 - NOISE_SHAPING_COEF * z^-1`, i.e. pushed towards the high frequencies.
+const NOISE_SHAPING_COEF: f32 = 0.5;
+
 /// Weights used for ADPCM encoding. The first weight is applied to the previous sample, the 2nd to
 /// the penultimate
 const FILTER_WEIGHTS: [(i8, i8); 5] = [(0, 0), (60, 0), (115, -52), (98, -55), (122, -60)];
+
+/// Play the interleaved `samples` made of `channels` channels on the system's default output
+/// device. If `loop_frame` is set the playback loops from that frame forever.
+pub fn play_samples(
+    sample_rate: u32,
+    channels: usize,
+    samples: &[i16],
+    loop_frame: Option<usize>,
+) -> Result<()> {
+    let host = cpal::default_host();
+    let device = host
+        .default_output_device()
+        .ok_or_else(|| anyhow!("No output device available"))?;
+    let config = device.default_output_config()?.config();
+
+    info!(
+        "Initiating playback on `{}` (sample rate: {}Hz)",
+        device.name()?,
+        config.sample_rate.0
+    );
+
+    // Resample each channel separately
+    let resampled = (0..channels)
+        .map(|c| {
+            let buf = AudioBuffer {
+                sample_rate,
+                samples: samples.iter().skip(c).step_by(channels).copied().collect(),
+                loop_sample: loop_frame.map(|l| l as u32),
+            };
+
+            buf.resample(config.sample_rate.0)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let nframes = resampled.iter().map(|b| b.samples.len()).min().unwrap_or(0);
+    let loop_frame = resampled[0].loop_sample.map(|l| l as usize);
+
+    let out_channels = config.channels as usize;
+
+    let finished = Arc::new(AtomicBool::new(false));
+    let frame_index = Arc::new(Mutex::new(0));
+
+    let finished_clone = Arc::clone(&finished);
+
+    let stream = device.build_output_stream(
+        &config,
+        move |data: &mut [i16], _: &cpal::OutputCallbackInfo| {
+            let mut index = frame_index.lock().unwrap();
+
+            for frame in data.chunks_mut(out_channels) {
+                if *index >= nframes {
+                    match loop_frame {
+                        Some(lf) if lf < nframes => *index = lf,
+                        _ => {
+                            finished_clone.store(true, Ordering::SeqCst);
+                            for sample_out in frame.iter_mut() {
+                                *sample_out = 0;
+                            }
+                            continue;
+                        }
+                    }
+                }
+
+                // If the output has more channels than the input, the extra ones repeat the last
+                // input channel
+                for (c, sample_out) in frame.iter_mut().enumerate() {
+                    *sample_out = resampled[c.min(channels - 1)].samples[*index];
+                }
+                *index += 1;
+            }
+        },
+        |err| eprintln!("Stream error: {err}"),
+        None,
+    )?;
+
+    stream.play()?;
+
+    while !finished.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_millis(100));
+    }
+    thread::sleep(Duration::from_millis(300));
+
+    Ok(())
+}
@@ -0,0 +1,313 @@
+//! Sound banks: many samples packed into a single SPU RAM image, with a directory to find them.
+//!
+//! The samples are described in a TOML manifest:
+//!
+//! ```toml
+//! # Settings applied to every sample unless overridden
+//! [defaults]
+//! sample_rate = 22050
+//!
+//! [[sample]]
+//! name = "jump"
+//! file = "sfx/jump.wav"
+//! volume = 0.8
+//!
+//! [[sample]]
+//! name = "theme"
+//! file = "music/theme.ogg"
+//! auto_loop = true
+//! loop_crossfade = 0.02
+//! hq = true
+//! ```
+//!
+//! File paths are relative to the manifest. The available settings mirror the `audio` command's
+//! options.
+//!
+//! NRSB file layout (all values little endian):
+//!
+//! ```text
+//! Header:
+//!   "NRSB"
+//!   u16 entry count
+//!   u16 reserved (0)
+//!   u32 image offset in the file
+//!   u32 image length in bytes
+//! Directory, 32 bytes per entry:
+//!   [u8; 20] name, NUL-padded
+//!   u32 offset of the sample in the image, in bytes (always a multiple of 16)
+//!   u32 length of the sample, in bytes
+//!   u16 SPU step to play the sample at its native rate
+//!   u16 flags: bit 0 set if the sample loops
+//! Image: the ADPCM data, meant to be copied as-is to a 16-byte aligned SPU RAM address
+//! ```
+
+use crate::audio::{AudioBuffer, AudioPos, EncodeOptions, LoopOptions};
+use crate::utils::format_size;
+use anyhow::{Context, Result};
+use byteorder::{LittleEndian, WriteBytesExt};
+use nr32_common::regs;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    #[serde(default)]
+    defaults: SampleDesc,
+    #[serde(default)]
+    sample: Vec<SampleDesc>,
+}
+
+/// A sample in the manifest. Every field except `name` and `file` can also be set in the
+/// defaults.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct SampleDesc {
+    name: Option<String>,
+    file: Option<String>,
+    sample_rate: Option<u32>,
+    channel: Option<usize>,
+    start: Option<f32>,
+    /// Gain applied to the samples before encoding
+    volume: Option<f32>,
+    loop_start: Option<PosDesc>,
+    loop_end: Option<PosDesc>,
+    auto_loop: Option<bool>,
+    min_loop_len: Option<f32>,
+    loop_crossfade: Option<f32>,
+    no_loop: Option<bool>,
+    hq: Option<bool>,
+    beam_width: Option<usize>,
+    noise_shaping: Option<bool>,
+}
+
+/// Position in samples, or a string using the same syntax as the command line
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+enum PosDesc {
+    Sample(usize),
+    Str(String),
+}
+
+impl PosDesc {
+    fn to_pos(&self) -> Result<AudioPos> {
+        match self {
+            PosDesc::Sample(s) => Ok(AudioPos::Sample(*s)),
+            PosDesc::Str(s) => AudioPos::from_str(s).map_err(|e| anyhow!(e)),
+        }
+    }
+}
+
+impl SampleDesc {
+    /// Fill the settings that aren't set with the ones from `defaults`
+    fn with_defaults(self, defaults: &SampleDesc) -> SampleDesc {
+        SampleDesc {
+            name: self.name,
+            file: self.file,
+            sample_rate: self.sample_rate.or(defaults.sample_rate),
+            channel: self.channel.or(defaults.channel),
+            start: self.start.or(defaults.start),
+            volume: self.volume.or(defaults.volume),
+            loop_start: self.loop_start.or_else(|| defaults.loop_start.clone()),
+            loop_end: self.loop_end.or_else(|| defaults.loop_end.clone()),
+            auto_loop: self.auto_loop.or(defaults.auto_loop),
+            min_loop_len: self.min_loop_len.or(defaults.min_loop_len),
+            loop_crossfade: self.loop_crossfade.or(defaults.loop_crossfade),
+            no_loop: self.no_loop.or(defaults.no_loop),
+            hq: self.hq.or(defaults.hq),
+            beam_width: self.beam_width.or(defaults.beam_width),
+            noise_shaping: self.noise_shaping.or(defaults.noise_shaping),
+        }
+    }
+
+    fn loop_options(&self) -> Result<LoopOptions> {
+        Ok(LoopOptions {
+            start: self.loop_start.as_ref().map(|p| p.to_pos()).transpose()?,
+            end: self.loop_end.as_ref().map(|p| p.to_pos()).transpose()?,
+            auto: self.auto_loop.unwrap_or(false),
+            min_len: self.min_loop_len.unwrap_or(0.1),
+            crossfade: self.loop_crossfade,
+            disable: self.no_loop.unwrap_or(false),
+        })
+    }
+
+    fn encode_options(&self) -> EncodeOptions {
+        let mut opts = EncodeOptions::new();
+
+        if self.hq.unwrap_or(false) {
+            opts.beam_width(self.beam_width.unwrap_or(8))
+                .noise_shaping(self.noise_shaping.unwrap_or(false));
+        }
+
+        opts
+    }
+}
+
+struct Entry {
+    name: String,
+    offset: u32,
+    len: u32,
+    step: u16,
+    looped: bool,
+}
+
+pub struct SoundBank {
+    entries: Vec<Entry>,
+    image: Vec<u8>,
+}
+
+impl SoundBank {
+    pub fn from_manifest<P: AsRef<Path>>(manifest_path: P) -> Result<SoundBank> {
+        let manifest_path = manifest_path.as_ref();
+        let dir = manifest_path.parent().unwrap_or(Path::new("."));
+
+        let manifest = fs::read_to_string(manifest_path)?;
+        let manifest: Manifest = toml::from_str(&manifest)
+            .with_context(|| format!("Invalid manifest {}", manifest_path.display()))?;
+
+        if manifest.defaults.name.is_some() || manifest.defaults.file.is_some() {
+            bail!("The name and file can't be set in the defaults");
+        }
+
+        let mut bank = SoundBank {
+            entries: Vec::with_capacity(manifest.sample.len()),
+            image: Vec::new(),
+        };
+
+        let mut names = HashSet::new();
+
+        for desc in manifest.sample {
+            let desc = desc.with_defaults(&manifest.defaults);
+
+            let (Some(name), Some(file)) = (desc.name.clone(), desc.file.clone()) else {
+                bail!("All samples need a name and a file");
+            };
+
+            if name.len() > NAME_LEN {
+                bail!("Sample name '{name}' is longer than {NAME_LEN} bytes");
+            }
+
+            if !names.insert(name.clone()) {
+                bail!("Duplicate sample name '{name}'");
+            }
+
+            bank.add(name.clone(), &dir.join(&file), &desc)
+                .with_context(|| format!("Converting '{name}' ({file})"))?;
+        }
+
+        Ok(bank)
+    }
+
+    fn add(&mut self, name: String, path: &Path, desc: &SampleDesc) -> Result<()> {
+        let mut buf = AudioBuffer::from_path(path, desc.channel, desc.start, desc.sample_rate)?;
+
+        buf.apply_loop_options(&desc.loop_options()?)?;
+
+        if let Some(volume) = desc.volume {
+            buf.amplify(volume);
+        }
+
+        if let Some(sample_rate) = desc.sample_rate {
+            buf = buf.resample(sample_rate)?;
+        }
+
+        // The SPU can only loop on ADPCM block boundaries
+        buf.align_loop();
+
+        let offset = self.image.len();
+
+        buf.dump_adpcm(&mut self.image, &desc.encode_options())?;
+
+        let len = self.image.len() - offset;
+
+        info!(
+            "{name}: {} @ 0x{offset:x}, {}Hz{}",
+            format_size(len as u64),
+            buf.sample_rate(),
+            if buf.loop_sample().is_some() {
+                ", looped"
+            } else {
+                ""
+            }
+        );
+
+        self.entries.push(Entry {
+            name,
+            offset: offset as u32,
+            len: len as u32,
+            step: buf.spu_step(),
+            looped: buf.loop_sample().is_some(),
+        });
+
+        Ok(())
+    }
+
+    /// Size of the image in SPU RAM
+    pub fn len(&self) -> usize {
+        self.image.len()
+    }
+
+    /// Log the SPU RAM usage and make sure the bank can be loaded at `sram_offset`
+    pub fn check_budget(&self, sram_offset: usize) -> Result<()> {
+        let ram_size = regs::spu::RAM_SIZE as usize;
+
+        if !sram_offset.is_multiple_of(16) {
+            bail!("Sound bank offset {sram_offset} is not aligned to a 16-byte ADPCM block");
+        }
+
+        info!(
+            "Sound bank uses {} ({:.01}%) of SPU RAM ({} samples total), {} left",
+            format_size(self.len() as u64),
+            (self.len() as f32 * 100.) / ram_size as f32,
+            self.entries.len(),
+            format_size(ram_size.saturating_sub(sram_offset + self.len()) as u64),
+        );
+
+        if sram_offset + self.len() > ram_size {
+            bail!("Not enough memory to store the sound bank at offset {sram_offset}");
+        }
+
+        Ok(())
+    }
+
+    pub fn dump<W: Write>(&self, w: &mut W) -> Result<()> {
+        const HEADER_LEN: usize = 16;
+        const ENTRY_LEN: usize = NAME_LEN + 12;
+
+        let Ok(count) = u16::try_from(self.entries.len()) else {
+            bail!(
+                "Too many samples in the sound bank ({}, max {})",
+                self.entries.len(),
+                u16::MAX
+            );
+        };
+
+        w.write_all(b"NRSB")?;
+        w.write_u16::<LittleEndian>(count)?;
+        w.write_u16::<LittleEndian>(0)?;
+        w.write_u32::<LittleEndian>((HEADER_LEN + self.entries.len() * ENTRY_LEN) as u32)?;
+        w.write_u32::<LittleEndian>(self.image.len() as u32)?;
+
+        for e in &self.entries {
+            let mut name = [0u8; NAME_LEN];
+            name[..e.name.len()].copy_from_slice(e.name.as_bytes());
+
+            w.write_all(&name)?;
+            w.write_u32::<LittleEndian>(e.offset)?;
+            w.write_u32::<LittleEndian>(e.len)?;
+            w.write_u16::<LittleEndian>(e.step)?;
+            w.write_u16::<LittleEndian>(e.looped as u16)?;
+        }
+
+        w.write_all(&self.image)?;
+
+        Ok(())
+    }
+}
+
+/// Maximum length of a sample name in the directory
+const NAME_LEN: usize = 20;
@@ -4,9 +4,12 @@ extern crate log;
 extern crate anyhow;
 
 mod audio;
+mod bank;
 mod cart;
+mod midi;
 mod model;
 mod score;
+mod tracker;
 mod utils;
 
 use anyhow::Result;
@@ -82,6 +85,57 @@ enum Commands {
         /// Start offset in the input file (in seconds)
         #[arg(long)]
         start: Option<f32>,
+
+        /// Loop start, in samples or in seconds with an `s` suffix (relative to --start).
+        /// Overrides the loop found in the input file, if any.
+        #[arg(long, conflicts_with_all = ["auto_loop", "no_loop"])]
+        loop_start: Option<audio::AudioPos>,
+
+        /// Loop end, in samples or in seconds with an `s` suffix (relative to --start). Everything
+        /// after it is dropped. Defaults to the end of the input.
+        #[arg(long, conflicts_with = "no_loop")]
+        loop_end: Option<audio::AudioPos>,
+
+        /// Look for the loop start that gives the smoothest transition from the loop end
+        #[arg(long, default_value_t = false, conflicts_with = "no_loop")]
+        auto_loop: bool,
+
+        /// Minimum loop length for --auto-loop (in seconds)
+        #[arg(long, default_value_t = 0.1)]
+        min_loop_len: f32,
+
+        /// Length of the crossfade applied at the end of the loop (in seconds)
+        #[arg(long)]
+        loop_crossfade: Option<f32>,
+
+        /// Ignore the loop found in the input file
+        #[arg(long, default_value_t = false)]
+        no_loop: bool,
+
+        /// Use the slower, higher quality ADPCM encoder
+        #[arg(long, default_value_t = false)]
+        hq: bool,
+
+        /// Number of candidate encodings considered at each block by the high quality encoder
+        #[arg(long, default_value_t = 8)]
+        beam_width: usize,
+
+        /// Shape the quantization noise of the high quality encoder towards the high frequencies
+        #[arg(long, default_value_t = false, requires = "hq")]
+        noise_shaping: bool,
+    },
+    /// Converts a set of audio files described in a TOML manifest into a sound bank
+    Bank {
+        /// The manifest listing the samples
+        manifest: PathBuf,
+
+        /// Offset (in bytes) of the SPU RAM where the bank will be loaded, to check that it fits
+        #[arg(long, default_value_t = 0)]
+        sram_offset: usize,
+
+        /// NRSB file to dump the sound bank
+        #[arg(short, long)]
+        output: Option<PathBuf>,
     },
     /// Processes music score files
     Score {
@@ -101,6 +155,62 @@ enum Commands {
         /// The resulting score file
         #[arg(short, long)]
         output: Option<PathBuf>,
+
+        /// Play the score on the system speaker, rendered through the emulator's SPU
+        #[arg(long, default_value_t = false)]
+        playback: bool,
+
+        /// Render the score through the emulator's SPU and dump the result as a WAV file
+        #[arg(long)]
+        wav: Option<PathBuf>,
+    },
+    /// Converts Standard MIDI Files into NRAS scores
+    Midi {
+        /// The MIDI file to convert
+        input_file: PathBuf,
+
+        /// TOML file mapping the MIDI programs and percussions to NRAD samples
+        #[arg(short, long)]
+        instruments: PathBuf,
+
+        /// Offset (in bytes) to skip from the start of the SPU RAM before storing the first sample
+        /// data
+        #[arg(long, default_value_t = 0)]
+        sram_offset: usize,
+
+        /// The resulting score file
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Play the score on the system speaker, rendered through the emulator's SPU
+        #[arg(long, default_value_t = false)]
+        playback: bool,
+
+        /// Render the score through the emulator's SPU and dump the result as a WAV file
+        #[arg(long)]
+        wav: Option<PathBuf>,
+    },
+    /// Converts tracker modules (MOD, S3M, XM) into NRAS scores
+    Module {
+        /// The module file to convert
+        input_file: PathBuf,
+
+        /// Offset (in bytes) to skip from the start of the SPU RAM before storing the first sample
+        /// data
+        #[arg(long, default_value_t = 0)]
+        sram_offset: usize,
+
+        /// The resulting score file
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Play the score on the system speaker, rendered through the emulator's SPU
+        #[arg(long, default_value_t = false)]
+        playback: bool,
+
+        /// Render the score through the emulator's SPU and dump the result as a WAV file
+        #[arg(long)]
+        wav: Option<PathBuf>,
     },
     /// Build cartridge image
     Cart {
@@ -163,6 +273,15 @@ fn main() -> Result<()> {
             playback,
             output,
             start,
+            loop_start,
+            loop_end,
+            auto_loop,
+            min_loop_len,
+            loop_crossfade,
+            no_loop,
+            hq,
+            beam_width,
+            noise_shaping,
         } => {
             let mut buf = audio::AudioBuffer::from_path(input_file, channel, start, sample_rate)?;
 
@@ -173,6 +292,15 @@ fn main() -> Result<()> {
                 buf.loop_sample(),
             );
 
+            buf.apply_loop_options(&audio::LoopOptions {
+                start: loop_start,
+                end: loop_end,
+                auto: auto_loop,
+                min_len: min_loop_len,
+                crossfade: loop_crossfade,
+                disable: no_loop,
+            })?;
+
             if let Some(sample_rate) = sample_rate {
                 if sample_rate != buf.sample_rate() {
                     info!(
@@ -187,6 +315,9 @@ fn main() -> Result<()> {
                 }
             }
 
+            // The SPU can only loop on ADPCM block boundaries
+            buf.align_loop();
+
             if playback {
                 buf.playback()?;
             }
@@ -194,7 +325,27 @@ fn main() -> Result<()> {
             if let Some(out) = output {
                 info!("Dumping audio to {}", out.display());
                 let mut out = BufWriter::new(File::create(out)?);
-                buf.dump_nrad(&mut out)?
+                buf.dump_nrad(
+                    &mut out,
+                    audio::EncodeOptions::new()
+                        .beam_width(if hq { beam_width } else { 1 })
+                        .noise_shaping(noise_shaping),
+                )?
+            }
+        }
+        Commands::Bank {
+            manifest,
+            sram_offset,
+            output,
+        } => {
+            let bank = bank::SoundBank::from_manifest(manifest)?;
+
+            bank.check_budget(sram_offset)?;
+
+            if let Some(out) = output {
+                info!("Dumping sound bank to {}", out.display());
+                let mut out = BufWriter::new(File::create(out)?);
+                bank.dump(&mut out)?;
             }
         }
         Commands::Score {
@@ -202,6 +353,8 @@ fn main() -> Result<()> {
             optimize,
             sram_offset,
             output,
+            playback,
+            wav,
         } => {
             let _ = optimize;
             let _ = output;
@@ -213,6 +366,43 @@ fn main() -> Result<()> {
                 let mut out = BufWriter::new(File::create(out)?);
                 score.dump_nras(&mut out, sram_offset)?
             }
+
+            render_score(&score, sram_offset, playback, wav)?;
+        }
+        Commands::Midi {
+            input_file,
+            instruments,
+            sram_offset,
+            output,
+            playback,
+            wav,
+        } => {
+            let score = midi::convert(input_file, instruments)?;
+
+            if let Some(out) = output {
+                info!("Dumping NRAS to {}", out.display());
+                let mut out = BufWriter::new(File::create(out)?);
+                score.dump_nras(&mut out, sram_offset)?
+            }
+
+            render_score(&score, sram_offset, playback, wav)?;
+        }
+        Commands::Module {
+            input_file,
+            sram_offset,
+            output,
+            playback,
+            wav,
+        } => {
+            let score = tracker::convert(input_file)?;
+
+            if let Some(out) = output {
+                info!("Dumping NRAS to {}", out.display());
+                let mut out = BufWriter::new(File::create(out)?);
+                score.dump_nras(&mut out, sram_offset)?
+            }
+
+            render_score(&score, sram_offset, playback, wav)?;
         }
         Commands::Cart {
             boot_elf,
@@ -244,3 +434,33 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// Render `score` through the emulator's SPU if it needs to be played back or dumped as WAV
+fn render_score(
+    score: &score::Score,
+    sram_offset: usize,
+    playback: bool,
+    wav: Option<PathBuf>,
+) -> Result<()> {
+    if !playback && wav.is_none() {
+        return Ok(());
+    }
+
+    let rec = score.render(sram_offset)?;
+
+    info!(
+        "Rendered {:.02}s of audio",
+        rec.mix().len() as f32 / (2. * 44_100.)
+    );
+
+    if let Some(wav) = wav {
+        info!("Dumping WAV to {}", wav.display());
+        rec.write_wav(BufWriter::new(File::create(wav)?))?;
+    }
+
+    if playback {
+        audio::play_samples(44_100, 2, rec.mix(), None)?;
+    }
+
+    Ok(())
+}
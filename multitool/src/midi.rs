@@ -0,0 +1,661 @@
+//! Standard MIDI File to NRAS conversion.
+//!
+//! The instruments are described in a TOML mapping file that associates MIDI programs (and
+//! percussion keys on channel 10) with NRAD samples:
+//!
+//! ```toml
+//! # Used for all the programs not listed below
+//! [default]
+//! sample = "piano.nrad"
+//!
+//! [program.33]
+//! sample = "bass.nrad"
+//! # MIDI key that plays the sample at its native rate (60, middle C, by default)
+//! base_note = 36
+//! volume = 0.8
+//! adsr = { attack_shift = 10, release_shift = 18, release_exp = true }
+//!
+//! # Percussions are mapped by key. By default they play at the sample's native rate.
+//! [drums.36]
+//! sample = "kick.nrad"
+//! ```
+//!
+//! Sample paths are relative to the mapping file.
+
+use crate::score::{Op, Score, ScoreBuilder, VOICE_COUNT};
+use anyhow::{Context, Result};
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+use nr32_common::regs::spu::Adsr;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Mapping {
+    default: Option<InstrumentDesc>,
+    #[serde(default)]
+    program: BTreeMap<String, InstrumentDesc>,
+    #[serde(default)]
+    drums: BTreeMap<String, InstrumentDesc>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InstrumentDesc {
+    /// NRAD file for this instrument
+    sample: PathBuf,
+    /// MIDI key at which the sample plays at its native rate
+    base_note: Option<f32>,
+    /// Volume multiplier applied on top of the note velocity
+    #[serde(default = "default_volume")]
+    volume: f32,
+    #[serde(default)]
+    adsr: AdsrDesc,
+}
+
+fn default_volume() -> f32 {
+    1.
+}
+
+/// Envelope description, same parameters as `regs::spu::Adsr`. The default is an instant
+/// attack and release with full level sustain.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct AdsrDesc {
+    attack_shift: u8,
+    attack_step: u8,
+    attack_exp: bool,
+    decay_shift: u8,
+    sustain_level: u8,
+    sustain_shift: u8,
+    sustain_step: u8,
+    sustain_exp: bool,
+    sustain_decrease: bool,
+    release_shift: u8,
+    release_exp: bool,
+}
+
+impl Default for AdsrDesc {
+    fn default() -> AdsrDesc {
+        AdsrDesc {
+            attack_shift: 0,
+            attack_step: 0,
+            attack_exp: false,
+            decay_shift: 0,
+            sustain_level: 15,
+            sustain_shift: 31,
+            sustain_step: 3,
+            sustain_exp: false,
+            sustain_decrease: false,
+            release_shift: 0,
+            release_exp: false,
+        }
+    }
+}
+
+impl AdsrDesc {
+    /// Pack the envelope in the VOICE_ADSR register format
+    fn raw(&self) -> Result<u32> {
+        // The builder silently masks out of range values
+        let check = |name: &str, v: u8, max: u8| -> Result<u8> {
+            if v > max {
+                bail!("ADSR {name} is out of range ({v} > {max})");
+            }
+
+            Ok(v)
+        };
+
+        let adsr = Adsr::new()
+            .attack(
+                check("attack_shift", self.attack_shift, 31)?,
+                check("attack_step", self.attack_step, 3)?,
+                self.attack_exp,
+            )
+            .decay(check("decay_shift", self.decay_shift, 15)?)
+            .sustain_level(check("sustain_level", self.sustain_level, 15)?)
+            .sustain(
+                check("sustain_shift", self.sustain_shift, 31)?,
+                check("sustain_step", self.sustain_step, 3)?,
+                self.sustain_exp,
+                self.sustain_decrease,
+            )
+            .release(
+                check("release_shift", self.release_shift, 31)?,
+                self.release_exp,
+            );
+
+        Ok(adsr.raw())
+    }
+}
+
+/// An instrument with its sample added to the score
+#[derive(Clone, Copy)]
+struct Instrument {
+    sample: usize,
+    /// Step of the sample at its native rate
+    step: u16,
+    base_note: f32,
+    volume: f32,
+    adsr: u32,
+}
+
+struct Instruments {
+    default: Option<Instrument>,
+    programs: HashMap<u8, Instrument>,
+    drums: HashMap<u8, Instrument>,
+}
+
+impl Instruments {
+    fn load<P: AsRef<Path>>(mapping_path: P, score: &mut Score) -> Result<Instruments> {
+        let mapping_path = mapping_path.as_ref();
+        let dir = mapping_path.parent().unwrap_or(Path::new("."));
+
+        let mapping = fs::read_to_string(mapping_path)?;
+        let mapping: Mapping = toml::from_str(&mapping)
+            .with_context(|| format!("Invalid mapping file {}", mapping_path.display()))?;
+
+        // Several instruments can share the same sample, Score::add_sample takes care of that
+        let mut load = |desc: &InstrumentDesc, default_base_note: f32| -> Result<Instrument> {
+            let path = dir.join(&desc.sample);
+
+            let nrad = fs::read(&path)?;
+
+            if nrad.len() < 8 || &nrad[0..4] != b"NRAD" {
+                bail!("{} is not a valid NRAD file", path.display());
+            }
+
+            let step = u16::from_le_bytes([nrad[6], nrad[7]]);
+
+            Ok(Instrument {
+                sample: score.add_sample(nrad[8..].to_vec()),
+                step,
+                base_note: desc.base_note.unwrap_or(default_base_note),
+                volume: desc.volume,
+                adsr: desc.adsr.raw()?,
+            })
+        };
+
+        let parse_key = |k: &str| -> Result<u8> {
+            match k.parse::<u8>() {
+                Ok(k) if k < 128 => Ok(k),
+                _ => bail!("Invalid program or key number '{k}'"),
+            }
+        };
+
+        let default = match &mapping.default {
+            Some(desc) => Some(load(desc, 60.)?),
+            None => None,
+        };
+
+        let mut programs = HashMap::new();
+
+        for (k, desc) in &mapping.program {
+            programs.insert(parse_key(k)?, load(desc, 60.)?);
+        }
+
+        let mut drums = HashMap::new();
+
+        for (k, desc) in &mapping.drums {
+            let key = parse_key(k)?;
+
+            drums.insert(key, load(desc, f32::from(key))?);
+        }
+
+        Ok(Instruments {
+            default,
+            programs,
+            drums,
+        })
+    }
+
+    fn get(&self, channel: u8, program: u8, key: u8) -> Option<Instrument> {
+        if channel == DRUM_CHANNEL {
+            self.drums.get(&key).copied()
+        } else {
+            self.programs.get(&program).copied().or(self.default)
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Channel {
+    program: u8,
+    volume: u8,
+    expression: u8,
+    pan: u8,
+    /// Pitch bend in semitones
+    bend: f32,
+    sustain: bool,
+}
+
+impl Channel {
+    fn new() -> Channel {
+        Channel {
+            program: 0,
+            volume: 100,
+            expression: 127,
+            pan: 64,
+            bend: 0.,
+            sustain: false,
+        }
+    }
+
+    fn reset_controllers(&mut self) {
+        *self = Channel {
+            program: self.program,
+            volume: self.volume,
+            pan: self.pan,
+            ..Channel::new()
+        };
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Note {
+    channel: u8,
+    key: u8,
+    velocity: u8,
+    instrument: Instrument,
+    /// True if the note has been released while the sustain pedal was down
+    held: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Voice {
+    note: Option<Note>,
+    /// Time of the last key on or key off, used to pick which voice to reuse
+    since: u64,
+    sample: Option<usize>,
+    adsr: Option<u32>,
+}
+
+/// Tracks the MIDI state and allocates the SPU voices
+struct Converter {
+    builder: ScoreBuilder,
+    voices: [Voice; VOICE_COUNT],
+    channels: [Channel; 16],
+    /// Current time in 4410Hz ticks
+    now: u64,
+    stolen: usize,
+}
+
+impl Converter {
+    fn new(score: Score) -> Converter {
+        Converter {
+            builder: ScoreBuilder::new(score),
+            voices: [Voice {
+                note: None,
+                since: 0,
+                sample: None,
+                adsr: None,
+            }; VOICE_COUNT],
+            channels: [Channel::new(); 16],
+            now: 0,
+            stolen: 0,
+        }
+    }
+
+    fn advance(&mut self, now: u64) {
+        if now > self.now {
+            self.flush();
+            self.now = now;
+        }
+    }
+
+    fn flush(&mut self) {
+        self.builder.flush(self.now);
+    }
+
+    fn note_on(&mut self, channel: u8, key: u8, velocity: u8, instrument: Instrument) {
+        // Retriggering a note that's still playing
+        self.note_off(channel, key, true);
+
+        // Reuse the voice released the longest ago, or steal the oldest note if they're all busy
+        let free = (0..VOICE_COUNT)
+            .filter(|&v| self.voices[v].note.is_none())
+            .min_by_key(|&v| self.voices[v].since);
+
+        let v = match free {
+            Some(v) => v,
+            None => {
+                self.stolen += 1;
+
+                (0..VOICE_COUNT)
+                    .min_by_key(|&v| self.voices[v].since)
+                    .unwrap()
+            }
+        };
+
+        let voice = v as u8;
+
+        if self.voices[v].sample != Some(instrument.sample) {
+            self.voices[v].sample = Some(instrument.sample);
+            self.builder.setup(Op::Sample {
+                voice,
+                index: instrument.sample,
+            });
+        }
+
+        if self.voices[v].adsr != Some(instrument.adsr) {
+            self.voices[v].adsr = Some(instrument.adsr);
+            self.builder.setup(Op::Adsr {
+                voice,
+                envelope: instrument.adsr,
+            });
+        }
+
+        let note = Note {
+            channel,
+            key,
+            velocity,
+            instrument,
+            held: false,
+        };
+
+        self.voices[v].note = Some(note);
+        self.voices[v].since = self.now;
+
+        self.update_step(v);
+        self.update_volume(v);
+
+        self.builder.trigger(voice);
+    }
+
+    /// Release the note. If `force` is false the note is kept playing while the channel's sustain
+    /// pedal is down.
+    fn note_off(&mut self, channel: u8, key: u8, force: bool) {
+        let sustain = self.channels[usize::from(channel)].sustain && !force;
+
+        for v in 0..VOICE_COUNT {
+            let Some(note) = &mut self.voices[v].note else {
+                continue;
+            };
+
+            if note.channel != channel || note.key != key || note.held {
+                continue;
+            }
+
+            if sustain {
+                note.held = true;
+            } else {
+                self.release(v);
+            }
+        }
+    }
+
+    fn release(&mut self, v: usize) {
+        self.voices[v].note = None;
+        self.voices[v].since = self.now;
+
+        self.builder.release(v as u8);
+    }
+
+    /// Release all the notes of `channel` matching `filter`
+    fn release_channel<F>(&mut self, channel: u8, filter: F)
+    where
+        F: Fn(&Note) -> bool,
+    {
+        for v in 0..VOICE_COUNT {
+            if self.voices[v]
+                .note
+                .is_some_and(|n| n.channel == channel && filter(&n))
+            {
+                self.release(v);
+            }
+        }
+    }
+
+    fn release_all(&mut self) {
+        for v in 0..VOICE_COUNT {
+            if self.voices[v].note.is_some() {
+                self.release(v);
+            }
+        }
+    }
+
+    /// Update the volume or step of all the notes currently playing on `channel`
+    fn update_channel(&mut self, channel: u8, step: bool) {
+        for v in 0..VOICE_COUNT {
+            if self.voices[v].note.is_some_and(|n| n.channel == channel) {
+                if step {
+                    self.update_step(v);
+                } else {
+                    self.update_volume(v);
+                }
+            }
+        }
+    }
+
+    fn update_step(&mut self, v: usize) {
+        let Some(note) = self.voices[v].note else {
+            return;
+        };
+
+        let channel = &self.channels[usize::from(note.channel)];
+        let ins = note.instrument;
+
+        let semitones = f32::from(note.key) - ins.base_note + channel.bend;
+        let step = f32::from(ins.step) * (semitones / 12.).exp2();
+
+        self.builder.step(v as u8, step);
+    }
+
+    fn update_volume(&mut self, v: usize) {
+        let Some(note) = self.voices[v].note else {
+            return;
+        };
+
+        let channel = &self.channels[usize::from(note.channel)];
+
+        let vol = note.instrument.volume
+            * (f32::from(note.velocity) / 127.)
+            * (f32::from(channel.volume) / 127.)
+            * (f32::from(channel.expression) / 127.);
+
+        let pan = f32::from(channel.pan) / 127.;
+
+        self.builder.volume(v as u8, vol, pan);
+    }
+
+    fn controller(&mut self, channel: u8, controller: u8, value: u8) {
+        let c = usize::from(channel);
+
+        match controller {
+            // Channel volume
+            7 => {
+                self.channels[c].volume = value;
+                self.update_channel(channel, false);
+            }
+            // Pan
+            10 => {
+                self.channels[c].pan = value;
+                self.update_channel(channel, false);
+            }
+            // Expression
+            11 => {
+                self.channels[c].expression = value;
+                self.update_channel(channel, false);
+            }
+            // Sustain pedal
+            64 => {
+                self.channels[c].sustain = value >= 64;
+
+                if !self.channels[c].sustain {
+                    self.release_channel(channel, |n| n.held);
+                }
+            }
+            // Reset all controllers
+            121 => {
+                self.channels[c].reset_controllers();
+                self.release_channel(channel, |n| n.held);
+                self.update_channel(channel, false);
+                self.update_channel(channel, true);
+            }
+            // All sound off, all notes off
+            120 | 123 => self.release_channel(channel, |_| true),
+            _ => debug!("Ignoring MIDI controller {controller} on channel {channel}"),
+        }
+    }
+}
+
+/// Convert the Standard MIDI File at `midi_path` using the instruments in `mapping_path`
+pub fn convert<P: AsRef<Path>, Q: AsRef<Path>>(midi_path: P, mapping_path: Q) -> Result<Score> {
+    let mut score = Score::default();
+
+    let instruments = Instruments::load(mapping_path, &mut score)?;
+
+    let midi = fs::read(midi_path)?;
+    let smf = Smf::parse(&midi)?;
+
+    // Merge all the tracks in a single, time-ordered list of events
+    let mut events = Vec::new();
+
+    for track in &smf.tracks {
+        let mut tick = 0u64;
+
+        for ev in track {
+            tick += u64::from(ev.delta.as_int());
+
+            events.push((tick, ev.kind));
+        }
+    }
+
+    // Stable sort to preserve the order of events within each track
+    events.sort_by_key(|&(tick, _)| tick);
+
+    // Length of a tick in seconds. For metrical timing it depends on the current tempo.
+    let tick_len = |tempo_us: u32| -> f64 {
+        match smf.header.timing {
+            Timing::Metrical(tpb) => f64::from(tempo_us) / (1e6 * f64::from(tpb.as_int())),
+            Timing::Timecode(fps, subframes) => {
+                1. / (f64::from(fps.as_f32()) * f64::from(subframes))
+            }
+        }
+    };
+
+    let mut conv = Converter::new(score);
+
+    // 120 BPM
+    let mut tempo_us = 500_000;
+    let mut last_tick = 0;
+    let mut secs = 0f64;
+
+    let mut unmapped = HashSet::new();
+
+    for (tick, kind) in events {
+        secs += (tick - last_tick) as f64 * tick_len(tempo_us);
+        last_tick = tick;
+
+        conv.advance((secs * 4410.).round() as u64);
+
+        match kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(t)) => tempo_us = t.as_int(),
+            TrackEventKind::Midi { channel, message } => {
+                let channel = channel.as_int();
+                let c = usize::from(channel);
+
+                match message {
+                    MidiMessage::NoteOn { key, vel } if vel > 0 => {
+                        let key = key.as_int();
+                        let program = conv.channels[c].program;
+
+                        match instruments.get(channel, program, key) {
+                            Some(ins) => conv.note_on(channel, key, vel.as_int(), ins),
+                            None => {
+                                let what = if channel == DRUM_CHANNEL {
+                                    format!("percussion key {key}")
+                                } else {
+                                    format!("program {program}")
+                                };
+
+                                if unmapped.insert(what.clone()) {
+                                    warn!("No instrument for {what}, notes will be skipped");
+                                }
+                            }
+                        }
+                    }
+                    MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                        conv.note_off(channel, key.as_int(), false)
+                    }
+                    MidiMessage::ProgramChange { program } => {
+                        conv.channels[c].program = program.as_int()
+                    }
+                    MidiMessage::Controller { controller, value } => {
+                        conv.controller(channel, controller.as_int(), value.as_int())
+                    }
+                    MidiMessage::PitchBend { bend } => {
+                        conv.channels[c].bend = bend.as_f32() * PITCH_BEND_RANGE;
+                        conv.update_channel(channel, true);
+                    }
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+    }
+
+    conv.flush();
+    conv.release_all();
+
+    let score = conv.builder.finish(conv.now);
+
+    info!(
+        "Converted {:.02}s of MIDI data",
+        score.duration_4410hz() as f32 / 4410.
+    );
+
+    if conv.stolen > 0 {
+        warn!(
+            "Ran out of voices, {} notes were cut short to make room",
+            conv.stolen
+        );
+    }
+
+    Ok(score)
+}
+
+/// MIDI channel 10 is reserved for percussions
+const DRUM_CHANNEL: u8 = 9;
+
+/// Pitch bend range in semitones (the General MIDI default)
+const PITCH_BEND_RANGE: f32 = 2.;
+
+#[test]
+fn test_adsr_layout() {
+    assert_eq!(AdsrDesc::default().raw().unwrap(), Adsr::new().raw());
+
+    let desc = AdsrDesc {
+        attack_shift: 13,
+        attack_step: 2,
+        attack_exp: true,
+        decay_shift: 9,
+        sustain_level: 11,
+        sustain_shift: 22,
+        sustain_step: 1,
+        sustain_exp: true,
+        sustain_decrease: true,
+        release_shift: 27,
+        release_exp: true,
+    };
+
+    // Every field must survive the builder regardless of the order of the calls
+    let adsr = Adsr::from_raw(!0)
+        .sustain_level(11)
+        .attack(13, 2, true)
+        .decay(9)
+        .sustain(22, 1, true, true)
+        .release(27, true);
+
+    assert_eq!(adsr.raw() & 0x7fff_ffff, desc.raw().unwrap());
+
+    let adsr = Adsr::from_raw(0)
+        .release(27, true)
+        .sustain_level(11)
+        .sustain(22, 1, true, true)
+        .decay(9)
+        .attack(13, 2, true);
+
+    assert_eq!(adsr.raw(), desc.raw().unwrap());
+}
@@ -1,5 +1,6 @@
 use anyhow::Result;
 use byteorder::{LittleEndian, WriteBytesExt};
+use nr32_common::regs::gpu;
 use std::io::Write;
 use std::path::Path;
 
@@ -359,17 +360,14 @@ impl Model {
             let m = 0;
 
             // Matrix identity
-            wu32(w, (0x10 << 24) | (m << 12))?;
+            wu32(w, gpu::matrix_reset(m))?;
 
             // Translation factor
             for (row, &t) in self.origin.iter().enumerate() {
                 let fpt = (t * 65536.).round().clamp(i32::MIN as f32, i32::MAX as f32) as i32;
 
                 if fpt != 0 {
-                    wu32(
-                        w,
-                        (0x10 << 24) | (1 << 16) | (m << 12) | (3 << 4) | (row as u32),
-                    )?;
+                    wu32(w, gpu::matrix_set(m, 3, row as u32))?;
 
                     wu32(w, fpt as u32)?;
                 }
@@ -382,7 +380,7 @@ impl Model {
                 .clamp(1., i32::MAX as f32) as u32;
             if iscale != 1 {
                 for p in 0..3 {
-                    wu32(w, (0x10 << 24) | (1 << 16) | (m << 12) | (p << 4) | p)?;
+                    wu32(w, gpu::matrix_set(m, p, p))?;
                     wu32(w, iscale)?;
                 }
             }
@@ -392,7 +390,7 @@ impl Model {
             let ma = 1;
             let mb = 0;
 
-            wu32(w, (0x10 << 24) | (0x02 << 16) | (mo << 12) | (ma << 4) | mb)?;
+            wu32(w, gpu::matrix_multiply(mo, ma, mb))?;
         }
 
         // Add an empty word (NOP for the GPU) to delineate the start of the vertex data. This way
@@ -506,7 +504,7 @@ impl Model {
                         Ok(())
                     };
 
-                    let cmd = (0x40 << 24) | (blend_mode << 25) | c0;
+                    let cmd = gpu::triangle(blend_mode, c0);
                     wu32(w, cmd)?;
                     if has_normals {
                         normal(w, v0)?;
@@ -1,10 +1,12 @@
 use anyhow::Result;
 use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+use novarave32::{AudioRecording, NoRa32};
+use nr32_common::regs;
 use std::fs::File;
 use std::io::{ErrorKind, Read, Write};
 use std::path::Path;
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct Score {
     samples: Vec<Vec<u8>>,
     ops: Vec<Op>,
@@ -31,7 +33,7 @@ impl Score {
 
         let mut samples: Vec<Vec<u8>> = Vec::new();
 
-        let mut voice_start = [0u16; 24];
+        let mut voice_start = [0u16; VOICE_COUNT];
 
         loop {
             let b = match nras.read_u8() {
@@ -43,7 +45,7 @@ impl Score {
             let voice = || -> Result<u8> {
                 let v = b & 0x1f;
 
-                if v >= 24 {
+                if usize::from(v) >= VOICE_COUNT {
                     bail!("Invalid NRAS voice number {}", v);
                 }
 
@@ -159,7 +161,7 @@ impl Score {
                     }
                     // SPU RAM load
                     2 => {
-                        // start and len are in 16B blocks
+                        // start and len - 1 are in 8B units
                         let bstart = nras.read_u16::<LittleEndian>()?;
                         let blen = nras.read_u16::<LittleEndian>()?;
 
@@ -184,10 +186,57 @@ impl Score {
         Ok(Score { samples, ops })
     }
 
+    /// Add the raw ADPCM `data` to the samples used by the score and return its index for
+    /// [`Op::Sample`]. Identical samples are only stored once.
+    pub fn add_sample(&mut self, data: Vec<u8>) -> usize {
+        match self.samples.iter().position(|s| *s == data) {
+            Some(p) => p,
+            None => {
+                self.samples.push(data);
+
+                self.samples.len() - 1
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    pub fn push(&mut self, op: Op) {
+        self.ops.push(op)
+    }
+
+    /// Total length of the delays in the score, in 4410Hz ticks
+    pub fn duration_4410hz(&self) -> u64 {
+        self.ops
+            .iter()
+            .map(|op| match *op {
+                Op::Delay { delay_4410hz } => u64::from(delay_4410hz),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Push a delay of `delay_4410hz` ticks, split in as many [`Op::Delay`] as necessary
+    pub fn delay(&mut self, mut delay_4410hz: u32) {
+        while delay_4410hz > 0 {
+            let d = delay_4410hz.min(u32::from(DELAY_MAX));
+
+            self.push(Op::Delay {
+                delay_4410hz: d as u16,
+            });
+
+            delay_4410hz -= d;
+        }
+    }
+
     pub fn dump_nras<W: Write>(&self, w: &mut W, offset: usize) -> Result<()> {
-        // Samples are always 8-byte aligned due to addressing constraints
+        // NRAS addresses are in 8-byte units but the voices can only start on a 16-byte ADPCM block
+        // boundary
         fn sram_align(len: usize) -> usize {
-            (len + 7) & (!7)
+            (len + 15) & (!15)
         }
 
         let offset = sram_align(offset);
@@ -225,21 +274,27 @@ impl Score {
             for s in self.samples.iter() {
                 w.write_all(s)?;
 
-                let align = s.len() & 7;
+                let align = s.len() & 15;
 
                 if align > 0 {
-                    for _ in align..8 {
+                    for _ in align..16 {
                         w.write_u8(0)?;
                     }
                 }
             }
         }
 
-        let mut voice_start = [0u16; 24];
+        let mut voice_start = [0u16; VOICE_COUNT];
 
         for op in self.ops.iter() {
             match *op {
-                Op::Delay { delay_4410hz } => w.write_u16::<BigEndian>(delay_4410hz)?,
+                Op::Delay { delay_4410hz } => {
+                    if delay_4410hz > DELAY_MAX {
+                        bail!("NRAS delay {delay_4410hz} is too long");
+                    }
+
+                    w.write_u16::<BigEndian>(delay_4410hz)?
+                }
                 Op::Step { voice, step } => {
                     w.write_u8((1u8 << 5) | voice)?;
                     w.write_u16::<LittleEndian>(step)?;
@@ -266,21 +321,270 @@ impl Score {
                 }
                 Op::Release { mask } => {
                     w.write_u8(7u8 << 5)?;
-                    w.write_u32::<LittleEndian>(mask)?;
+                    w.write_u24::<LittleEndian>(mask)?;
                 }
                 Op::Trigger { mask } => {
                     w.write_u8((7u8 << 5) | 1)?;
-                    w.write_u32::<LittleEndian>(mask)?;
+                    w.write_u24::<LittleEndian>(mask)?;
                 }
             }
         }
 
         Ok(())
     }
+
+    /// Render the score through the emulator's SPU, the output is exactly what the console would
+    /// play (with the main volume and the song volume at their maximum)
+    pub fn render(&self, sram_offset: usize) -> Result<AudioRecording> {
+        let mut nras = Vec::new();
+
+        self.dump_nras(&mut nras, sram_offset)?;
+
+        render_nras(&nras)
+    }
+}
+
+/// Builds a score in time order, batching the key on and key off that happen at the same time.
+/// Used by the format converters.
+pub struct ScoreBuilder {
+    score: Score,
+    /// Time of the last op pushed to the score, in 4410Hz ticks
+    last_flush: u64,
+    releases: u32,
+    setup: Vec<Op>,
+    triggers: u32,
+    /// Voices triggered and released at the same time, they'll be released at the next flush
+    deferred_releases: u32,
+    clamped_steps: usize,
+}
+
+impl ScoreBuilder {
+    pub fn new(score: Score) -> ScoreBuilder {
+        ScoreBuilder {
+            score,
+            last_flush: 0,
+            releases: 0,
+            setup: Vec::new(),
+            triggers: 0,
+            deferred_releases: 0,
+            clamped_steps: 0,
+        }
+    }
+
+    /// See [`Score::add_sample`]
+    pub fn add_sample(&mut self, data: Vec<u8>) -> usize {
+        self.score.add_sample(data)
+    }
+
+    /// Queue `op` to be pushed at the next flush, before the voices are triggered
+    pub fn setup(&mut self, op: Op) {
+        self.setup.push(op)
+    }
+
+    /// Set the step of `voice`, clamped to the range supported by the SPU
+    pub fn step(&mut self, voice: u8, step: f32) {
+        let step = step.round();
+
+        let step = if step > f32::from(STEP_MAX) || step < 1. {
+            self.clamped_steps += 1;
+            step.clamp(1., f32::from(STEP_MAX))
+        } else {
+            step
+        };
+
+        self.setup(Op::Step {
+            voice,
+            step: step as u16,
+        });
+    }
+
+    /// Set the volume of `voice`. `volume` is between 0.0 and 1.0, `pan` goes from 0.0 (left) to
+    /// 1.0 (right). The center is at full volume on both sides.
+    pub fn volume(&mut self, voice: u8, volume: f32, pan: f32) {
+        let to_u8 = |v: f32| (v * 255.).round().clamp(0., 255.) as u8;
+
+        self.setup(Op::Volume {
+            voice,
+            l: to_u8(volume * ((1. - pan) * 2.).min(1.)),
+            r: to_u8(volume * (pan * 2.).min(1.)),
+        });
+    }
+
+    /// Key on `voice` at the next flush
+    pub fn trigger(&mut self, voice: u8) {
+        let mask = 1u32 << voice;
+
+        // We'll retrigger this voice, no need to release it
+        self.releases &= !mask;
+        self.deferred_releases &= !mask;
+        self.triggers |= mask;
+    }
+
+    /// Key off `voice` at the next flush
+    pub fn release(&mut self, voice: u8) {
+        let mask = 1u32 << voice;
+
+        if self.triggers & mask != 0 {
+            // The note hasn't started playing yet, let it run until the next event
+            self.deferred_releases |= mask;
+        } else {
+            self.releases |= mask;
+        }
+    }
+
+    /// Push all the queued ops at time `now`
+    pub fn flush(&mut self, now: u64) {
+        if self.releases == 0 && self.setup.is_empty() && self.triggers == 0 {
+            return;
+        }
+
+        self.score.delay((now - self.last_flush) as u32);
+        self.last_flush = now;
+
+        if self.releases != 0 {
+            self.score.push(Op::Release {
+                mask: self.releases,
+            });
+        }
+
+        for op in self.setup.drain(..) {
+            self.score.push(op);
+        }
+
+        if self.triggers != 0 {
+            self.score.push(Op::Trigger {
+                mask: self.triggers,
+            });
+        }
+
+        self.releases = self.deferred_releases;
+        self.deferred_releases = 0;
+        self.triggers = 0;
+    }
+
+    /// Flush the remaining ops at time `now`, including the deferred releases, and return the
+    /// score
+    pub fn finish(mut self, now: u64) -> Score {
+        self.flush(now);
+        self.flush(now);
+
+        if self.clamped_steps > 0 {
+            warn!(
+                "{} notes were out of the SPU's pitch range and have been clamped",
+                self.clamped_steps
+            );
+        }
+
+        self.score
+    }
+}
+
+/// Run the NRAS score in `nras` through the emulator's SPU and return the recording of its output
+fn render_nras(mut nras: &[u8]) -> Result<AudioRecording> {
+    let mut nr = NoRa32::new();
+
+    let magic = nras.read_u32::<LittleEndian>()?;
+    if magic != 0x5341524e {
+        bail!("Invalid NRAS magic");
+    }
+
+    let _flags = nras.read_u32::<LittleEndian>()?;
+
+    let spu_volume = |l: u16, r: u16| (u32::from(l) << 16) | u32::from(r);
+
+    nr.start_audio_recording(false);
+    nr.spu_store(regs::spu::MAIN_VOLUME, spu_volume(0x7fff, 0x7fff));
+
+    let voice_reg = |b: u8, reg: u32| regs::spu::voice_reg(u32::from(b & 0x1f), reg);
+
+    while let Ok(b) = nras.read_u8() {
+        match b >> 5 {
+            // Wait
+            0 => {
+                let lo = nras.read_u8()?;
+                let delay_4410hz = u16::from_le_bytes([lo, b]);
+
+                nr.run_spu_cycles(usize::from(delay_4410hz) * 10);
+            }
+            // Step
+            1 => {
+                let step = nras.read_u16::<LittleEndian>()?;
+
+                nr.spu_store(voice_reg(b, regs::spu::VOICE_STEP), u32::from(step));
+            }
+            // Volume
+            2 => {
+                // Same scaling as the guest sequencer
+                let l = (u32::from(nras.read_u8()?) * 0x7fff / 0xff) as u16;
+                let r = (u32::from(nras.read_u8()?) * 0x7fff / 0xff) as u16;
+
+                nr.spu_store(voice_reg(b, regs::spu::VOICE_VOLUME), spu_volume(l, r));
+            }
+            // ADSR
+            3 => {
+                let envelope = nras.read_u32::<LittleEndian>()?;
+
+                nr.spu_store(voice_reg(b, regs::spu::VOICE_ADSR), envelope);
+            }
+            // Sample
+            4 => {
+                let addr = u32::from(nras.read_u16::<LittleEndian>()?) << 3;
+
+                nr.spu_store(voice_reg(b, regs::spu::VOICE_START_BLOCK), addr >> 4);
+            }
+            // Loop
+            5 => {
+                let addr = u32::from(nras.read_u16::<LittleEndian>()?) << 3;
+
+                nr.spu_store(voice_reg(b, regs::spu::VOICE_LOOP_BLOCK), addr >> 4);
+            }
+            7 => match b & 0x1f {
+                // Release
+                0 => {
+                    let mask = nras.read_u24::<LittleEndian>()?;
+
+                    nr.spu_store(regs::spu::VOICE_OFF, mask);
+                }
+                // Trigger
+                1 => {
+                    let mask = nras.read_u24::<LittleEndian>()?;
+
+                    nr.spu_store(regs::spu::VOICE_ON, mask);
+                }
+                // SPU RAM load
+                2 => {
+                    let start = u32::from(nras.read_u16::<LittleEndian>()?) << 3;
+                    let len = (usize::from(nras.read_u16::<LittleEndian>()?) + 1) << 3;
+
+                    nr.spu_store(regs::spu::RAM_ADDR, start);
+
+                    for _ in 0..(len / 4) {
+                        let w = nras.read_u32::<LittleEndian>()?;
+
+                        nr.spu_store(regs::spu::RAM_DATA, w);
+                    }
+                }
+                sub => bail!("Unhandled NRAS subcode {}", sub),
+            },
+            op => bail!("Unhandled NRAS opcode {}", op),
+        }
+    }
+
+    // Let the voices still running play until the end of their release, unless they loop forever
+    for _ in 0..(RENDER_TAIL_MAX_CYCLES / 441) {
+        if nr.spu_load(regs::spu::ACTIVE_VOICES) == 0 {
+            break;
+        }
+
+        nr.run_spu_cycles(441);
+    }
+
+    nr.stop_audio_recording()
+        .ok_or_else(|| anyhow!("Audio recording missing"))
 }
 
-#[derive(Debug, Clone)]
-enum Op {
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
     Delay { delay_4410hz: u16 },
     Step { voice: u8, step: u16 },
     Volume { voice: u8, l: u8, r: u8 },
@@ -291,5 +595,17 @@ enum Op {
     Trigger { mask: u32 },
 }
 
+/// Number of SPU voices available to the score
+pub const VOICE_COUNT: usize = 24;
+
+/// Highest possible VOICE_STEP value
+pub const STEP_MAX: u16 = 0x3fff;
+
+/// Longest delay that can be encoded in a single NRAS op, since the top 3 bits hold the opcode
+const DELAY_MAX: u16 = 0x1fff;
+
+/// Maximum length of the output rendered after the end of the score, while the voices release
+const RENDER_TAIL_MAX_CYCLES: usize = 44_100 * 5;
+
 /// SPU RAM size
-const SPU_RAM_SIZE_BYTE: usize = 512 * 1024;
+const SPU_RAM_SIZE_BYTE: usize = regs::spu::RAM_SIZE as usize;
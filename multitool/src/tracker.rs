@@ -0,0 +1,1366 @@
+//! Tracker module (MOD, S3M and XM) to NRAS conversion.
+//!
+//! Each tracker channel is mapped to its own SPU voice. Only the features that map directly to
+//! NRAS ops are supported: notes, instruments, volumes (including volume slides), note cut and
+//! key off, speed/tempo changes, pattern breaks and position jumps. Pitch effects (portamento,
+//! vibrato, arpeggio...) and envelopes are ignored.
+
+use crate::audio::{ADPCM_BLOCK_SAMPLES, AudioBuffer, EncodeOptions};
+use crate::score::{Op, Score, ScoreBuilder, VOICE_COUNT};
+use anyhow::{Context, Result};
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
+use nr32_common::regs::spu::Adsr;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+/// A tracker module, normalized from whatever format it was loaded from
+struct Module {
+    channels: usize,
+    /// Default panning for each channel, 0.0 is full left, 1.0 full right
+    pans: Vec<f32>,
+    orders: Vec<usize>,
+    patterns: Vec<Pattern>,
+    instruments: Vec<Instrument>,
+    speed: u8,
+    bpm: u8,
+}
+
+/// Rows of cells, one per channel
+type Pattern = Vec<Vec<Cell>>;
+
+#[derive(Clone, Copy, Default)]
+struct Cell {
+    note: Note,
+    /// Instrument number, starting at 1. 0 if the cell doesn't have one.
+    instrument: u8,
+    /// Volume in 0..=64
+    volume: Option<u8>,
+    /// Effect and parameter, using the MOD numbering
+    effect: u8,
+    param: u8,
+}
+
+#[derive(Clone, Copy, Default, PartialEq)]
+enum Note {
+    #[default]
+    None,
+    /// Note in semitones relative to C-4
+    On(f32),
+    Off,
+}
+
+struct Instrument {
+    samples: Vec<Sample>,
+    /// Sample to use for each note, starting at C-0
+    keymap: [u8; 96],
+}
+
+impl Instrument {
+    fn single(sample: Sample) -> Instrument {
+        Instrument {
+            samples: vec![sample],
+            keymap: [0; 96],
+        }
+    }
+
+    fn sample_index(&self, note: f32) -> Option<usize> {
+        let key = (note.round() + 48.).clamp(0., 95.) as usize;
+        let index = usize::from(self.keymap[key]);
+
+        self.samples
+            .get(index)
+            .filter(|s| !s.pcm.is_empty())
+            .map(|_| index)
+    }
+}
+
+#[derive(Default)]
+struct Sample {
+    pcm: Vec<i16>,
+    /// Loop start and end offsets in `pcm`
+    loop_range: Option<(usize, usize)>,
+    pingpong: bool,
+    /// Default volume in 0..=64
+    volume: u8,
+    /// Playback frequency for C-4
+    c4_freq: f32,
+    /// Panning, overrides the channel's if set
+    pan: Option<f32>,
+}
+
+impl Sample {
+    /// Build the sample data with the loop (if any) starting and ending on an ADPCM block
+    /// boundary. Ping-pong loops are unrolled into forward loops.
+    fn to_audio_buffer(&self) -> AudioBuffer {
+        let rate = self.c4_freq.round().max(1.) as u32;
+
+        let Some((start, end)) = self.loop_range else {
+            return AudioBuffer::from_samples(rate, self.pcm.clone(), None);
+        };
+
+        let mut pcm = self.pcm[..end].to_vec();
+
+        if self.pingpong && end - start > 2 {
+            pcm.extend(self.pcm[start + 1..end - 1].iter().rev());
+        }
+
+        let mut buf = AudioBuffer::from_samples(rate, pcm, Some(start as u32));
+
+        buf.align_loop();
+
+        buf
+    }
+}
+
+/// Load a module, guessing the format from its contents
+fn load_module(data: &[u8]) -> Result<Module> {
+    if data.starts_with(b"Extended Module: ") {
+        parse_xm(data).context("Invalid XM file")
+    } else if data.get(0x2c..0x30) == Some(b"SCRM") {
+        parse_s3m(data).context("Invalid S3M file")
+    } else {
+        parse_mod(data).context("Invalid MOD file")
+    }
+}
+
+fn parse_mod(data: &[u8]) -> Result<Module> {
+    let Some(sig) = data.get(1080..1084) else {
+        bail!("File is too short");
+    };
+
+    let digit = |b: u8| b.is_ascii_digit().then(|| usize::from(b - b'0'));
+
+    let channels = match *sig {
+        [b'M', b'.', b'K', b'.'] | [b'M', b'!', b'K', b'!'] | [b'F', b'L', b'T', b'4'] => 4,
+        [b'F', b'L', b'T', b'8'] | [b'O', b'C', b'T', b'A'] => 8,
+        [n, b'C', b'H', b'N'] if digit(n).is_some() => digit(n).unwrap(),
+        [a, b, b'C', b'H'] if digit(a).is_some() && digit(b).is_some() => {
+            digit(a).unwrap() * 10 + digit(b).unwrap()
+        }
+        _ => bail!(
+            "Unsupported MOD signature {:?}",
+            String::from_utf8_lossy(sig)
+        ),
+    };
+
+    let mut r = Cursor::new(data);
+
+    struct Header {
+        len: usize,
+        finetune: i8,
+        volume: u8,
+        loop_start: usize,
+        loop_len: usize,
+    }
+
+    let mut headers = Vec::with_capacity(31);
+
+    for i in 0..31 {
+        r.set_position(20 + i * 30 + 22);
+
+        let len = usize::from(r.read_u16::<BigEndian>()?) * 2;
+        // Signed nibble
+        let finetune = ((r.read_u8()? << 4) as i8) >> 4;
+        let volume = r.read_u8()?.min(64);
+        let loop_start = usize::from(r.read_u16::<BigEndian>()?) * 2;
+        let loop_len = usize::from(r.read_u16::<BigEndian>()?) * 2;
+
+        headers.push(Header {
+            len,
+            finetune,
+            volume,
+            loop_start,
+            loop_len,
+        });
+    }
+
+    let order_count = usize::from(data[950]).min(128);
+    let orders: Vec<usize> = data[952..952 + order_count]
+        .iter()
+        .map(|&o| usize::from(o))
+        .collect();
+    // All 128 entries count to figure out how many patterns are stored
+    let pattern_count = data[952..1080]
+        .iter()
+        .map(|&o| usize::from(o))
+        .max()
+        .unwrap()
+        + 1;
+
+    r.set_position(1084);
+
+    let mut patterns = Vec::with_capacity(pattern_count);
+
+    for _ in 0..pattern_count {
+        let mut rows = Vec::with_capacity(64);
+
+        for _ in 0..64 {
+            let mut row = Vec::with_capacity(channels);
+
+            for _ in 0..channels {
+                let mut c = [0u8; 4];
+                r.read_exact(&mut c)?;
+
+                let period = (u16::from(c[0] & 0xf) << 8) | u16::from(c[1]);
+
+                let note = if period > 0 {
+                    Note::On(12. * (MOD_C4_PERIOD / f32::from(period)).log2())
+                } else {
+                    Note::None
+                };
+
+                row.push(Cell {
+                    note,
+                    instrument: (c[0] & 0xf0) | (c[2] >> 4),
+                    volume: None,
+                    effect: c[2] & 0xf,
+                    param: c[3],
+                });
+            }
+
+            rows.push(row);
+        }
+
+        patterns.push(rows);
+    }
+
+    let mut instruments = Vec::with_capacity(31);
+
+    for h in headers {
+        let mut raw = vec![0u8; h.len];
+        // Some modules are truncated, keep what we have
+        let read = r.read(&mut raw)?;
+        raw.truncate(read);
+
+        let pcm: Vec<i16> = raw.iter().map(|&b| i16::from(b as i8) << 8).collect();
+
+        let loop_range = (h.loop_len > 2 && h.loop_start < pcm.len())
+            .then(|| (h.loop_start, (h.loop_start + h.loop_len).min(pcm.len())));
+
+        instruments.push(Instrument::single(Sample {
+            pcm,
+            loop_range,
+            pingpong: false,
+            volume: h.volume,
+            c4_freq: AMIGA_PAL_CLOCK / MOD_C4_PERIOD * (f32::from(h.finetune) / 96.).exp2(),
+            pan: None,
+        }));
+    }
+
+    // Amiga LRRL panning, but not as hard as on the real thing
+    let pans = (0..channels)
+        .map(|c| if matches!(c % 4, 0 | 3) { 0.25 } else { 0.75 })
+        .collect();
+
+    Ok(Module {
+        channels,
+        pans,
+        orders,
+        patterns,
+        instruments,
+        speed: 6,
+        bpm: 125,
+    })
+}
+
+fn parse_s3m(data: &[u8]) -> Result<Module> {
+    let mut r = Cursor::new(data);
+
+    r.set_position(0x20);
+    let order_count = usize::from(r.read_u16::<LittleEndian>()?);
+    let instrument_count = usize::from(r.read_u16::<LittleEndian>()?);
+    let pattern_count = usize::from(r.read_u16::<LittleEndian>()?);
+
+    r.set_position(0x2a);
+    let unsigned_samples = r.read_u16::<LittleEndian>()? == 2;
+
+    r.set_position(0x31);
+    let speed = r.read_u8()?;
+    let bpm = r.read_u8()?;
+
+    // Only keep the enabled channels, S3M channels 0-7 are on the left, 8-15 on the right
+    let mut channel_map = [None; 32];
+    let mut pans = Vec::new();
+
+    for (c, &setting) in data[0x40..0x60].iter().enumerate() {
+        if setting < 16 {
+            channel_map[c] = Some(pans.len());
+            pans.push(if setting < 8 { 0.25 } else { 0.75 });
+        }
+    }
+
+    let channels = pans.len();
+
+    r.set_position(0x60);
+    let mut orders = Vec::with_capacity(order_count);
+
+    for _ in 0..order_count {
+        match r.read_u8()? {
+            // End of song
+            255 => break,
+            // Marker
+            254 => (),
+            o => orders.push(usize::from(o)),
+        }
+    }
+
+    r.set_position(0x60 + order_count as u64);
+
+    let mut parapointer = || -> Result<u64> { Ok(u64::from(r.read_u16::<LittleEndian>()?) * 16) };
+
+    let instrument_ptrs = (0..instrument_count)
+        .map(|_| parapointer())
+        .collect::<Result<Vec<_>>>()?;
+    let pattern_ptrs = (0..pattern_count)
+        .map(|_| parapointer())
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut instruments = Vec::with_capacity(instrument_count);
+
+    for ptr in instrument_ptrs {
+        r.set_position(ptr);
+
+        // Only type 1 instruments are PCM samples, the rest is Adlib stuff
+        if r.read_u8()? != 1 {
+            instruments.push(Instrument::single(Sample::default()));
+            continue;
+        }
+
+        r.set_position(ptr + 0x0d);
+        let memseg_hi = u64::from(r.read_u8()?);
+        let memseg_lo = u64::from(r.read_u16::<LittleEndian>()?);
+        let len = r.read_u32::<LittleEndian>()? as usize;
+        let loop_start = r.read_u32::<LittleEndian>()? as usize;
+        let loop_end = r.read_u32::<LittleEndian>()? as usize;
+        let volume = r.read_u8()?.min(64);
+        let _reserved = r.read_u8()?;
+        let _pack = r.read_u8()?;
+        let flags = r.read_u8()?;
+        let c2spd = r.read_u32::<LittleEndian>()?;
+
+        let is_16bit = flags & 4 != 0;
+
+        r.set_position(((memseg_hi << 16) | memseg_lo) * 16);
+
+        let mut pcm = Vec::with_capacity(len);
+
+        // For stereo samples we only keep the left channel, which comes first
+        for _ in 0..len {
+            let s = if is_16bit {
+                r.read_u16::<LittleEndian>()?
+            } else {
+                u16::from(r.read_u8()?) << 8
+            };
+
+            let s = if unsigned_samples { s ^ 0x8000 } else { s };
+
+            pcm.push(s as i16);
+        }
+
+        let loop_range = (flags & 1 != 0 && loop_start < loop_end && loop_end <= len)
+            .then_some((loop_start, loop_end));
+
+        instruments.push(Instrument::single(Sample {
+            pcm,
+            loop_range,
+            pingpong: false,
+            volume,
+            c4_freq: c2spd as f32,
+            pan: None,
+        }));
+    }
+
+    let mut patterns = Vec::with_capacity(pattern_count);
+
+    for ptr in pattern_ptrs {
+        let mut rows = vec![vec![Cell::default(); channels]; 64];
+
+        if ptr == 0 {
+            // Empty pattern
+            patterns.push(rows);
+            continue;
+        }
+
+        // Skip the packed length
+        r.set_position(ptr + 2);
+
+        for row in rows.iter_mut() {
+            loop {
+                let what = r.read_u8()?;
+
+                if what == 0 {
+                    // End of row
+                    break;
+                }
+
+                let mut cell = Cell::default();
+
+                if what & 0x20 != 0 {
+                    cell.note = match r.read_u8()? {
+                        255 => Note::None,
+                        254 => Note::Off,
+                        n => Note::On(f32::from((n >> 4) * 12 + (n & 0xf)) - 48.),
+                    };
+                    cell.instrument = r.read_u8()?;
+                }
+
+                if what & 0x40 != 0 {
+                    cell.volume = Some(r.read_u8()?.min(64));
+                }
+
+                if what & 0x80 != 0 {
+                    let command = r.read_u8()?;
+                    let info = r.read_u8()?;
+
+                    (cell.effect, cell.param) = s3m_effect(command, info);
+                }
+
+                if let Some(c) = channel_map[usize::from(what & 0x1f)] {
+                    row[c] = cell;
+                }
+            }
+        }
+
+        patterns.push(rows);
+    }
+
+    Ok(Module {
+        channels,
+        pans,
+        orders,
+        patterns,
+        instruments,
+        speed,
+        bpm,
+    })
+}
+
+/// Translate an S3M command to the equivalent MOD effect. Unsupported commands are dropped.
+fn s3m_effect(command: u8, info: u8) -> (u8, u8) {
+    let (x, y) = (info >> 4, info & 0xf);
+
+    match command {
+        // Axx: set speed
+        1 => (0xf, info.min(31)),
+        // Bxx: position jump
+        2 => (0xb, info),
+        // Cxx: pattern break
+        3 => (0xd, info),
+        // DxF/DFy: fine volume slides
+        4 if y == 0xf && x != 0 => (0xe, 0xa0 | x),
+        4 if x == 0xf && y != 0 => (0xe, 0xb0 | y),
+        // Dx0/D0y: volume slides
+        4 if x == 0 || y == 0 => (0xa, info),
+        // SCx: note cut
+        19 if x == 0xc => (0xe, info),
+        // Txx: set tempo
+        20 if info >= 32 => (0xf, info),
+        _ => (0, 0),
+    }
+}
+
+fn parse_xm(data: &[u8]) -> Result<Module> {
+    let mut r = Cursor::new(data);
+
+    r.set_position(60);
+    let header_size = u64::from(r.read_u32::<LittleEndian>()?);
+    let song_len = usize::from(r.read_u16::<LittleEndian>()?).min(256);
+    let _restart = r.read_u16::<LittleEndian>()?;
+    let channels = usize::from(r.read_u16::<LittleEndian>()?);
+    let pattern_count = usize::from(r.read_u16::<LittleEndian>()?);
+    let instrument_count = usize::from(r.read_u16::<LittleEndian>()?);
+    let _flags = r.read_u16::<LittleEndian>()?;
+    let speed = r.read_u16::<LittleEndian>()?.min(31) as u8;
+    let bpm = r.read_u16::<LittleEndian>()?.min(255) as u8;
+
+    let mut orders = vec![0u8; song_len];
+    r.read_exact(&mut orders)?;
+    let orders = orders.into_iter().map(usize::from).collect();
+
+    r.set_position(60 + header_size);
+
+    let mut patterns = Vec::with_capacity(pattern_count);
+
+    for p in 0..pattern_count {
+        let start = r.position();
+        let header_len = u64::from(r.read_u32::<LittleEndian>()?);
+        let _packing = r.read_u8()?;
+        let row_count = usize::from(r.read_u16::<LittleEndian>()?);
+        let packed_len = u64::from(r.read_u16::<LittleEndian>()?);
+
+        if row_count == 0 {
+            bail!("Pattern {p} has no rows");
+        }
+
+        r.set_position(start + header_len);
+
+        let mut rows = vec![vec![Cell::default(); channels]; row_count];
+
+        if packed_len > 0 {
+            for row in rows.iter_mut() {
+                for cell in row.iter_mut() {
+                    let b = r.read_u8()?;
+
+                    // If the MSB is set the byte tells which fields follow, otherwise it's the
+                    // note and all the fields follow
+                    let (flags, note) = if b & 0x80 != 0 {
+                        let note = if b & 1 != 0 { r.read_u8()? } else { 0 };
+
+                        (b, note)
+                    } else {
+                        (0x1e, b)
+                    };
+
+                    cell.note = match note {
+                        0 => Note::None,
+                        97 => Note::Off,
+                        n => Note::On(f32::from(n) - 49.),
+                    };
+
+                    let mut field = |bit: u8| -> Result<u8> {
+                        if flags & bit != 0 {
+                            Ok(r.read_u8()?)
+                        } else {
+                            Ok(0)
+                        }
+                    };
+
+                    cell.instrument = field(0x02)?;
+                    let volume = field(0x04)?;
+                    let effect = field(0x08)?;
+                    let param = field(0x10)?;
+
+                    cell.volume = match volume {
+                        0x10..=0x50 => Some(volume - 0x10),
+                        _ => None,
+                    };
+
+                    // Effects past 0xf are XM-specific and unsupported
+                    (cell.effect, cell.param) = if effect <= 0xf {
+                        (effect, param)
+                    } else {
+                        (0, 0)
+                    };
+                }
+            }
+        }
+
+        r.set_position(start + header_len + packed_len);
+
+        patterns.push(rows);
+    }
+
+    let mut instruments = Vec::with_capacity(instrument_count);
+
+    for _ in 0..instrument_count {
+        let start = r.position();
+        let size = u64::from(r.read_u32::<LittleEndian>()?);
+
+        r.set_position(start + 27);
+        let sample_count = usize::from(r.read_u16::<LittleEndian>()?);
+
+        if sample_count == 0 {
+            r.set_position(start + size);
+            instruments.push(Instrument {
+                samples: Vec::new(),
+                keymap: [0; 96],
+            });
+            continue;
+        }
+
+        let sample_header_size = u64::from(r.read_u32::<LittleEndian>()?);
+        let mut keymap = [0u8; 96];
+        r.read_exact(&mut keymap)?;
+
+        r.set_position(start + size);
+
+        struct Header {
+            len: usize,
+            loop_start: usize,
+            loop_len: usize,
+            volume: u8,
+            finetune: i8,
+            kind: u8,
+            pan: u8,
+            relative_note: i8,
+        }
+
+        let mut headers = Vec::with_capacity(sample_count);
+
+        for _ in 0..sample_count {
+            let hstart = r.position();
+
+            headers.push(Header {
+                len: r.read_u32::<LittleEndian>()? as usize,
+                loop_start: r.read_u32::<LittleEndian>()? as usize,
+                loop_len: r.read_u32::<LittleEndian>()? as usize,
+                volume: r.read_u8()?.min(64),
+                finetune: r.read_i8()?,
+                kind: r.read_u8()?,
+                pan: r.read_u8()?,
+                relative_note: r.read_i8()?,
+            });
+
+            r.set_position(hstart + sample_header_size);
+        }
+
+        let mut samples = Vec::with_capacity(sample_count);
+
+        for h in headers {
+            let is_16bit = h.kind & 0x10 != 0;
+            // Lengths and loop points are in bytes
+            let width = if is_16bit { 2 } else { 1 };
+
+            let len = h.len / width;
+            let mut pcm = Vec::with_capacity(len);
+
+            // Samples are delta-encoded
+            let mut acc = 0i16;
+
+            for _ in 0..len {
+                if is_16bit {
+                    acc = acc.wrapping_add(r.read_i16::<LittleEndian>()?);
+                    pcm.push(acc);
+                } else {
+                    acc = (acc as i8).wrapping_add(r.read_i8()?) as i16;
+                    pcm.push(acc << 8);
+                }
+            }
+
+            let (loop_start, loop_end) =
+                (h.loop_start / width, (h.loop_start + h.loop_len) / width);
+
+            let loop_range = (h.kind & 3 != 0 && loop_start < loop_end && loop_end <= len)
+                .then_some((loop_start, loop_end));
+
+            let note = f32::from(h.relative_note) + f32::from(h.finetune) / 128.;
+
+            samples.push(Sample {
+                pcm,
+                loop_range,
+                pingpong: h.kind & 3 == 2,
+                volume: h.volume,
+                c4_freq: 8363. * (note / 12.).exp2(),
+                pan: Some(f32::from(h.pan) / 255.),
+            });
+        }
+
+        instruments.push(Instrument { samples, keymap });
+    }
+
+    Ok(Module {
+        channels,
+        pans: vec![0.5; channels],
+        orders,
+        patterns,
+        instruments,
+        speed,
+        bpm,
+    })
+}
+
+#[derive(Clone, Copy)]
+struct EncodedSample {
+    /// Index in the score
+    index: usize,
+    /// Loop offset in 8-byte units
+    loop_offset: Option<u16>,
+    /// Playback frequency for C-4, adjusted for any resampling done while encoding
+    c4_freq: f32,
+}
+
+#[derive(Clone, Copy)]
+struct Channel {
+    /// Current instrument, starting at 1
+    instrument: u8,
+    /// Instrument and sample currently playing, if any
+    sample: Option<(usize, usize)>,
+    note: f32,
+    volume: u8,
+    pan: f32,
+    /// Last volume slide parameter
+    volume_slide: u8,
+}
+
+/// Builds the score one tracker tick at a time
+struct Converter<'a> {
+    module: &'a Module,
+    builder: ScoreBuilder,
+    channels: Vec<Channel>,
+    /// Samples added to the score so far
+    encoded: HashMap<(usize, usize), EncodedSample>,
+    /// Score sample currently set on each voice
+    voice_samples: Vec<Option<usize>>,
+    /// Current time in 4410Hz ticks
+    now: f64,
+}
+
+impl<'a> Converter<'a> {
+    fn new(module: &'a Module) -> Converter<'a> {
+        let channels = module
+            .pans
+            .iter()
+            .map(|&pan| Channel {
+                instrument: 0,
+                sample: None,
+                note: 0.,
+                volume: 64,
+                pan,
+                volume_slide: 0,
+            })
+            .collect();
+
+        let mut score = Score::default();
+
+        for voice in 0..module.channels {
+            score.push(Op::Adsr {
+                voice: voice as u8,
+                envelope: TRACKER_ADSR,
+            });
+        }
+
+        Converter {
+            module,
+            builder: ScoreBuilder::new(score),
+            channels,
+            encoded: HashMap::new(),
+            voice_samples: vec![None; module.channels],
+            now: 0.,
+        }
+    }
+
+    fn sample(&self, (instrument, sample): (usize, usize)) -> &'a Sample {
+        &self.module.instruments[instrument].samples[sample]
+    }
+
+    fn flush(&mut self) {
+        self.builder.flush(self.now.round() as u64);
+    }
+
+    /// Encode the sample if it's the first time it's used
+    fn encode_sample(&mut self, id: (usize, usize)) -> Result<EncodedSample> {
+        if let Some(&e) = self.encoded.get(&id) {
+            return Ok(e);
+        }
+
+        let sample = self.sample(id);
+        let buf = sample.to_audio_buffer();
+
+        let mut adpcm = Vec::new();
+        buf.dump_adpcm(&mut adpcm, &EncodeOptions::new())?;
+
+        let e = EncodedSample {
+            index: self.builder.add_sample(adpcm),
+            loop_offset: buf
+                .loop_sample()
+                .map(|ls| (ls as usize / ADPCM_BLOCK_SAMPLES * 2) as u16),
+            c4_freq: sample.c4_freq * buf.sample_rate() as f32 / sample.c4_freq.round().max(1.),
+        };
+
+        self.encoded.insert(id, e);
+
+        Ok(e)
+    }
+
+    fn trigger(&mut self, c: usize, id: (usize, usize), note: f32) -> Result<()> {
+        let e = self.encode_sample(id)?;
+        let voice = c as u8;
+
+        if self.voice_samples[c] != Some(e.index) {
+            self.voice_samples[c] = Some(e.index);
+            self.builder.setup(Op::Sample {
+                voice,
+                index: e.index,
+            });
+
+            if let Some(offset) = e.loop_offset {
+                self.builder.setup(Op::Loop { voice, offset });
+            }
+        }
+
+        let ch = &mut self.channels[c];
+
+        ch.sample = Some(id);
+        ch.note = note;
+
+        self.update_step(c);
+
+        self.builder.trigger(voice);
+
+        Ok(())
+    }
+
+    fn release(&mut self, c: usize) {
+        if self.channels[c].sample.take().is_some() {
+            self.builder.release(c as u8);
+        }
+    }
+
+    fn update_step(&mut self, c: usize) {
+        let ch = self.channels[c];
+
+        let Some(id) = ch.sample else {
+            return;
+        };
+
+        let freq = self.encoded[&id].c4_freq * (ch.note / 12.).exp2();
+
+        self.builder.step(c as u8, freq * 4096. / 44_100.);
+    }
+
+    fn update_volume(&mut self, c: usize) {
+        let ch = &self.channels[c];
+
+        self.builder
+            .volume(c as u8, f32::from(ch.volume) / 64., ch.pan);
+    }
+
+    fn slide_volume(&mut self, c: usize, up: u8, down: u8) {
+        let ch = &mut self.channels[c];
+
+        ch.volume = ch.volume.saturating_add(up).saturating_sub(down).min(64);
+
+        self.update_volume(c);
+    }
+
+    fn run(&mut self) -> Result<()> {
+        let module = self.module;
+
+        let mut speed = module.speed.max(1);
+        let mut bpm = module.bpm.max(32);
+
+        let mut order = 0;
+        let mut row = 0;
+        let mut visited = HashSet::new();
+
+        // Stop as soon as the song loops
+        while order < module.orders.len() && visited.insert((order, row)) {
+            let Some(pattern) = module.patterns.get(module.orders[order]) else {
+                order += 1;
+                row = 0;
+                continue;
+            };
+
+            let mut jump = None;
+            let mut brk = None;
+            // Per-channel volume slide and note cut tick for this row
+            let mut slides = vec![None; module.channels];
+            let mut cuts = vec![None; module.channels];
+
+            for (c, cell) in pattern[row].iter().enumerate() {
+                let mut volume_changed = false;
+
+                if cell.instrument != 0 {
+                    self.channels[c].instrument = cell.instrument;
+                }
+
+                let instrument = usize::from(self.channels[c].instrument)
+                    .checked_sub(1)
+                    .and_then(|i| module.instruments.get(i).map(|ins| (i, ins)));
+
+                match cell.note {
+                    Note::On(note) => {
+                        // Tone portamento: we can't slide, so just change the pitch
+                        let porta = matches!(cell.effect, 3 | 5);
+
+                        if porta && self.channels[c].sample.is_some() {
+                            self.channels[c].note = note;
+                            self.update_step(c);
+                        } else if let Some((i, ins)) = instrument {
+                            match ins.sample_index(note) {
+                                Some(s) => {
+                                    self.trigger(c, (i, s), note)?;
+
+                                    if cell.instrument != 0 {
+                                        self.channels[c].volume = ins.samples[s].volume;
+
+                                        if let Some(pan) = ins.samples[s].pan {
+                                            self.channels[c].pan = pan;
+                                        }
+                                    }
+
+                                    volume_changed = true;
+                                }
+                                None => self.release(c),
+                            }
+                        }
+                    }
+                    Note::Off => self.release(c),
+                    Note::None if cell.instrument != 0 => {
+                        // Instrument without a note resets the volume
+                        let sample = instrument
+                            .zip(self.channels[c].sample)
+                            .and_then(|((_, ins), (_, s))| ins.samples.get(s));
+
+                        if let Some(sample) = sample {
+                            self.channels[c].volume = sample.volume;
+                            volume_changed = true;
+                        }
+                    }
+                    Note::None => (),
+                }
+
+                if let Some(v) = cell.volume {
+                    self.channels[c].volume = v;
+                    volume_changed = true;
+                }
+
+                let (x, y) = (cell.param >> 4, cell.param & 0xf);
+
+                match (cell.effect, x) {
+                    // Volume slide
+                    (0xa, _) => {
+                        if cell.param != 0 {
+                            self.channels[c].volume_slide = cell.param;
+                        }
+
+                        slides[c] = Some(self.channels[c].volume_slide);
+                    }
+                    // Position jump
+                    (0xb, _) => jump = Some(usize::from(cell.param)),
+                    // Set volume
+                    (0xc, _) => {
+                        self.channels[c].volume = cell.param.min(64);
+                        volume_changed = true;
+                    }
+                    // Pattern break, the row is in BCD
+                    (0xd, _) => brk = Some(usize::from(x * 10 + y)),
+                    // Fine volume slide up
+                    (0xe, 0xa) => {
+                        self.slide_volume(c, y, 0);
+                        volume_changed = false;
+                    }
+                    // Fine volume slide down
+                    (0xe, 0xb) => {
+                        self.slide_volume(c, 0, y);
+                        volume_changed = false;
+                    }
+                    // Note cut
+                    (0xe, 0xc) => cuts[c] = Some(y),
+                    // Set speed/tempo
+                    (0xf, _) if cell.param != 0 => {
+                        if cell.param < 32 {
+                            speed = cell.param;
+                        } else {
+                            bpm = cell.param;
+                        }
+                    }
+                    _ => (),
+                }
+
+                if volume_changed && self.channels[c].sample.is_some() {
+                    self.update_volume(c);
+                }
+            }
+
+            for tick in 0..speed {
+                if tick > 0 {
+                    for (c, &slide) in slides.iter().enumerate() {
+                        if let Some(slide) = slide {
+                            self.slide_volume(c, slide >> 4, slide & 0xf);
+                        }
+                    }
+                }
+
+                for (c, &cut) in cuts.iter().enumerate() {
+                    if cut == Some(tick) {
+                        self.channels[c].volume = 0;
+                        self.update_volume(c);
+                    }
+                }
+
+                self.flush();
+
+                // One tick lasts 2.5 / bpm seconds
+                self.now += 4410. * 2.5 / f64::from(bpm);
+            }
+
+            match (jump, brk) {
+                (Some(j), b) => {
+                    order = j;
+                    row = b.unwrap_or(0);
+                }
+                (None, Some(b)) => {
+                    order += 1;
+                    row = b;
+                }
+                (None, None) => {
+                    row += 1;
+
+                    if row >= pattern.len() {
+                        order += 1;
+                        row = 0;
+                    }
+                }
+            }
+
+            let next_len = module
+                .orders
+                .get(order)
+                .and_then(|&p| module.patterns.get(p))
+                .map_or(0, |p| p.len());
+
+            if row >= next_len {
+                row = 0;
+            }
+        }
+
+        for c in 0..module.channels {
+            self.release(c);
+        }
+
+        Ok(())
+    }
+}
+
+/// Convert the tracker module at `path` into a score
+pub fn convert<P: AsRef<Path>>(path: P) -> Result<Score> {
+    let data = fs::read(path)?;
+
+    convert_module(&data)
+}
+
+/// Convert the tracker module in `data` into a score
+fn convert_module(data: &[u8]) -> Result<Score> {
+    let module = load_module(data)?;
+
+    if module.channels > VOICE_COUNT {
+        bail!(
+            "Module has {} channels, only {VOICE_COUNT} are supported",
+            module.channels
+        );
+    }
+
+    info!(
+        "Loaded module with {} channels, {} patterns and {} instruments",
+        module.channels,
+        module.patterns.len(),
+        module.instruments.len()
+    );
+
+    let mut conv = Converter::new(&module);
+
+    conv.run()?;
+
+    let score = conv.builder.finish(conv.now.round() as u64);
+
+    info!(
+        "Converted {:.02}s of music using {} samples",
+        score.duration_4410hz() as f32 / 4410.,
+        conv.encoded.len()
+    );
+
+    Ok(score)
+}
+
+/// Trackers don't have envelopes (or we ignore them) so we just use a short release to avoid
+/// clicks when a note is cut
+const TRACKER_ADSR: u32 = Adsr::new().release(10, false).raw();
+
+/// Amiga Paula clock frequency (PAL), divided by the period to get the sample rate
+const AMIGA_PAL_CLOCK: f32 = 3_546_894.6;
+
+/// MOD period of the middle C
+const MOD_C4_PERIOD: f32 = 428.;
+
+/// Sample used by the test modules: 112 samples with a 56 sample loop starting at 10, so that
+/// the loop has to be moved to the next ADPCM block
+#[cfg(test)]
+fn test_pcm() -> Vec<i16> {
+    (0..112).map(|i| ((i % 16) - 8) << 11).collect()
+}
+
+/// Check the score converted from a test module. All the test modules contain the same song:
+///
+/// ```text
+///          Channel 0          Channel 1
+/// Order 0 (pattern 0):
+///   Row 0  C-5 01 ...         C-4 01 EC2
+///   Row 1  --- -- A01
+///   Row 2  --- -- D12
+/// Order 1 (pattern 1):
+///   Row 11 C-3 01 ...
+///   Row 12 C-4 01 B00
+/// ```
+///
+/// At speed 6 and 125 BPM. `c4_step` and `c5_step` are the expected steps for C-4 and C-5 with
+/// the test sample.
+#[cfg(test)]
+fn check_song(data: &[u8], c4_step: u16, c5_step: u16) {
+    let score = convert_module(data).unwrap();
+
+    // Time of each op, in 4410Hz ticks
+    let mut now = 0;
+    let mut ops = Vec::new();
+
+    for op in score.ops() {
+        match *op {
+            Op::Delay { delay_4410hz } => now += u64::from(delay_4410hz),
+            ref op => ops.push((now, op.clone())),
+        }
+    }
+
+    // The pattern break skips row 11, the position jump ends the song
+    let steps: Vec<_> = ops
+        .iter()
+        .filter_map(|(_, op)| match *op {
+            Op::Step { voice, step } => Some((voice, step)),
+            _ => None,
+        })
+        .collect();
+
+    assert_eq!(steps, [(0, c5_step), (1, c4_step), (0, c4_step)]);
+
+    // The loop start is moved to the next ADPCM block, in 8-byte units
+    let loops: Vec<_> = ops
+        .iter()
+        .filter(|(_, op)| matches!(op, Op::Loop { .. }))
+        .collect();
+
+    assert_eq!(
+        loops,
+        [
+            &(
+                0,
+                Op::Loop {
+                    voice: 0,
+                    offset: 2
+                }
+            ),
+            &(
+                0,
+                Op::Loop {
+                    voice: 1,
+                    offset: 2
+                }
+            )
+        ]
+    );
+
+    let volumes = |v: u8| -> Vec<(u64, u8, u8)> {
+        ops.iter()
+            .filter_map(|&(t, ref op)| match *op {
+                Op::Volume { voice, l, r } if voice == v => Some((t, l, r)),
+                _ => None,
+            })
+            .collect()
+    };
+
+    // One tick lasts 88.2 ticks at 4410Hz. The volume slide runs on all the ticks of row 1 but
+    // the first one.
+    let left: Vec<_> = volumes(0).iter().map(|&(t, l, _)| (t, l)).collect();
+
+    assert_eq!(
+        left,
+        [
+            (0, 255),
+            (617, 251),
+            (706, 247),
+            (794, 243),
+            (882, 239),
+            (970, 235),
+            (1588, 255)
+        ]
+    );
+
+    // Note cut on the 3rd tick
+    let cut = volumes(1);
+
+    assert_eq!(cut.len(), 2);
+    assert_eq!(cut[1], (176, 0, 0));
+
+    // 4 rows of 6 ticks
+    assert_eq!(score.duration_4410hz(), 2117);
+}
+
+#[test]
+fn test_mod() {
+    let mut data = vec![0u8; 1084];
+
+    // Sample 1 header, lengths in words: 112 samples, full volume, loop 10..66
+    data[20 + 22..20 + 30].copy_from_slice(&[0, 56, 0, 64, 0, 5, 0, 28]);
+
+    data[950] = 2;
+    data[952] = 0;
+    data[953] = 1;
+    data[1080..1084].copy_from_slice(b"M.K.");
+
+    let cell = |period: u16, instrument: u8, effect: u8, param: u8| {
+        [
+            (instrument & 0xf0) | (period >> 8) as u8,
+            period as u8,
+            (instrument << 4) | effect,
+            param,
+        ]
+    };
+
+    // 2 patterns of 64 rows of 4 channels
+    let mut rows = vec![[[0u8; 4]; 4]; 128];
+
+    rows[0][0] = cell(214, 1, 0, 0);
+    rows[0][1] = cell(428, 1, 0xe, 0xc2);
+    rows[1][0] = cell(0, 0, 0xa, 0x01);
+    rows[2][0] = cell(0, 0, 0xd, 0x12);
+    rows[64 + 11][0] = cell(856, 1, 0, 0);
+    rows[64 + 12][0] = cell(428, 1, 0xb, 0);
+
+    data.extend(rows.iter().flatten().flatten());
+    data.extend(test_pcm().iter().map(|&s| (s >> 8) as u8));
+
+    check_song(&data, 770, 1539);
+}
+
+#[test]
+fn test_s3m() {
+    let mut data = vec![0u8; 0x70];
+
+    // 2 orders, 1 instrument, 2 patterns, signed samples
+    data[0x20..0x26].copy_from_slice(&[2, 0, 1, 0, 2, 0]);
+    data[0x2a] = 1;
+    data[0x2c..0x30].copy_from_slice(b"SCRM");
+    data[0x31] = 6;
+    data[0x32] = 125;
+
+    // Channel 0 on the left, channel 1 on the right
+    data[0x40..0x60].fill(0xff);
+    data[0x40] = 0;
+    data[0x41] = 8;
+
+    data[0x60..0x62].copy_from_slice(&[0, 1]);
+
+    // Instrument at 0x70, sample data at 0xc0
+    data[0x62] = 0x07;
+
+    let mut ins = [0u8; 0x50];
+    ins[0] = 1;
+    ins[0x0e] = 0x0c;
+    ins[0x10] = 112;
+    ins[0x14] = 10;
+    ins[0x18] = 66;
+    ins[0x1c] = 64;
+    ins[0x1f] = 1;
+    ins[0x20..0x24].copy_from_slice(&11_025u32.to_le_bytes());
+
+    data.extend(ins);
+    data.extend(test_pcm().iter().map(|&s| (s >> 8) as u8));
+
+    // (row, channel, note, command, info), notes use instrument 1
+    let pattern = |cells: &[(usize, u8, Option<u8>, u8, u8)]| -> Vec<u8> {
+        let mut p = vec![0, 0];
+
+        for row in 0..64 {
+            for &(r, c, note, command, info) in cells {
+                if r != row {
+                    continue;
+                }
+
+                match note {
+                    Some(n) => p.extend([c | 0xa0, n, 1, command, info]),
+                    None => p.extend([c | 0x80, command, info]),
+                }
+            }
+
+            p.push(0);
+        }
+
+        p
+    };
+
+    let patterns = [
+        pattern(&[
+            (0, 0, Some(0x50), 0, 0),
+            (0, 1, Some(0x40), 19, 0xc2),
+            (1, 0, None, 4, 0x01),
+            (2, 0, None, 3, 0x12),
+        ]),
+        pattern(&[(11, 0, Some(0x30), 0, 0), (12, 0, Some(0x40), 2, 0)]),
+    ];
+
+    for (i, p) in patterns.iter().enumerate() {
+        data.resize(data.len().next_multiple_of(16), 0);
+
+        let para = (data.len() / 16) as u16;
+        data[0x64 + i * 2..][..2].copy_from_slice(&para.to_le_bytes());
+
+        data.extend(p);
+    }
+
+    check_song(&data, 1024, 2048);
+}
+
+#[test]
+fn test_xm() {
+    let mut data = vec![0u8; 80];
+
+    data[..17].copy_from_slice(b"Extended Module: ");
+    data[58] = 0x04;
+    data[59] = 0x01;
+
+    // Song length, restart, channels, patterns, instruments, flags, speed, BPM
+    for (i, v) in [2u16, 0, 2, 2, 1, 1, 6, 125].iter().enumerate() {
+        data[64 + i * 2..][..2].copy_from_slice(&v.to_le_bytes());
+    }
+    data[60..64].copy_from_slice(&276u32.to_le_bytes());
+
+    let mut orders = [0u8; 256];
+    orders[1] = 1;
+    data.extend(orders);
+
+    // (row, channel, note, effect, param)
+    let pattern = |cells: &[(usize, usize, u8, u8, u8)]| -> Vec<u8> {
+        let mut rows = [[[0u8; 5]; 2]; 64];
+
+        for &(r, c, note, effect, param) in cells {
+            let instrument = u8::from(note != 0);
+
+            rows[r][c] = [note, instrument, 0, effect, param];
+        }
+
+        let mut p = vec![9, 0, 0, 0, 0, 64, 0];
+        p.extend(&((64 * 2 * 5) as u16).to_le_bytes());
+        p.extend(rows.iter().flatten().flatten());
+
+        p
+    };
+
+    data.extend(pattern(&[
+        (0, 0, 61, 0, 0),
+        (0, 1, 49, 0xe, 0xc2),
+        (1, 0, 0, 0xa, 0x01),
+        (2, 0, 0, 0xd, 0x12),
+    ]));
+    data.extend(pattern(&[(11, 0, 37, 0, 0), (12, 0, 49, 0xb, 0)]));
+
+    // Instrument with one sample: 112 samples, loop 10..66, full volume, panned left
+    let mut ins = vec![0u8; 263];
+    ins[..4].copy_from_slice(&263u32.to_le_bytes());
+    ins[27] = 1;
+    ins[29] = 40;
+    data.extend(ins);
+
+    let mut sample = vec![0u8; 40];
+    sample[0] = 112;
+    sample[4] = 10;
+    sample[8] = 56;
+    sample[12] = 64;
+    sample[14] = 1;
+    data.extend(sample);
+
+    // Delta encoded
+    let mut prev = 0i8;
+
+    for s in test_pcm() {
+        let s = (s >> 8) as i8;
+
+        data.push(s.wrapping_sub(prev) as u8);
+        prev = s;
+    }
+
+    check_song(&data, 777, 1554);
+
+    // Patterns without rows are rejected
+    data[336 + 5] = 0;
+    assert!(convert_module(&data).is_err());
+}
@@ -14,6 +14,8 @@ pub enum SysError {
     NoSys = 6,
     /// Timeout
     Timeout = 7,
+    /// Bad address
+    Fault = 9,
 }
 
 pub type SysResult<T> = Result<T, SysError>;
@@ -3,4 +3,5 @@
 pub mod bootscript;
 pub mod error;
 pub mod memmap;
+pub mod regs;
 pub mod syscall;
@@ -14,6 +14,11 @@ impl Range {
             None
         }
     }
+
+    /// Returns the absolute address of the register at offset `off` in this range
+    pub const fn reg(self, off: u32) -> u32 {
+        self.base + off
+    }
 }
 
 pub const RAM: Range = Range {
@@ -38,7 +43,7 @@ pub const GPU: Range = Range {
 
 pub const SPU: Range = Range {
     base: 0x4002_0000,
-    len: 1024,
+    len: 2048,
 };
 
 pub const INPUT_DEV: Range = Range {
@@ -0,0 +1,11 @@
+//! Device register definitions, shared between the emulator and the code running on the console.
+//!
+//! Register addresses are byte offsets relative to the base of the device's range in `memmap`.
+
+pub mod debug;
+pub mod dma;
+pub mod gpu;
+pub mod input_dev;
+pub mod irq;
+pub mod spu;
+pub mod sys_timer;
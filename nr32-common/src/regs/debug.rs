@@ -0,0 +1,10 @@
+//! Debug interface registers
+
+/// Debug console output, one byte at a time. A '\n' flushes the line.
+pub const PUTCHAR: u32 = 0x10;
+
+/// Shutdown the emulator. The high halfword must be `SHUTDOWN_MAGIC`, the low halfword contains the
+/// exit code.
+pub const SHUTDOWN: u32 = 0x20;
+
+pub const SHUTDOWN_MAGIC: u32 = 0xd1e;
@@ -0,0 +1,94 @@
+//! DMA registers. See `syscall::DmaAddr` for the encoding of the source and destination addresses.
+//!
+//! The DMA has `CHANNEL_COUNT` independent channels. When several channels are running at the
+//! same time the lowest channel number has the highest priority: lower priority channels only get
+//! to use the bus while the higher priority ones are stalled or idle.
+
+/// Number of channels
+pub const CHANNEL_COUNT: u32 = 3;
+/// Channel used for GPU transfers by convention (highest priority)
+pub const CHANNEL_GPU: u32 = 0;
+/// Channel used for SPU transfers by convention
+pub const CHANNEL_SPU: u32 = 1;
+/// Channel used for memory to memory transfers by convention (lowest priority)
+pub const CHANNEL_MEMORY: u32 = 2;
+
+/// Channels that have completed a transfer, one bit per channel. Write 1s to acknowledge. The
+/// `DmaDone` interrupt is triggered when this register goes from 0 to non-0.
+pub const IRQ_STATUS: u32 = 0x00;
+/// Channels that have aborted a transfer because of an error, one bit per channel. Write 1s to
+/// acknowledge. The `DmaError` interrupt is triggered when this register goes from 0 to non-0. See
+/// `CHANNEL_STATUS` for the cause.
+pub const ERROR_STATUS: u32 = 0x04;
+
+/// Start of the per-channel registers
+pub const CHANNEL_BASE: u32 = 0x40;
+/// Size of the register block of each channel
+pub const CHANNEL_STRIDE: u32 = 0x40;
+
+/// Channel: source address, or the value to write in `MODE_FILL`
+pub const CHANNEL_SRC: u32 = 0x0;
+/// Channel: destination address
+pub const CHANNEL_DST: u32 = 0x4;
+/// Channel: length in words. Writing a non-0 value starts the transfer. Reads back the number of
+/// words left to transfer in the current block.
+pub const CHANNEL_LEN: u32 = 0x8;
+/// Channel: address of the first descriptor of a chain (see `syscall::DmaDescriptor`). Writing a
+/// non-0 value starts a linked-list transfer. Reads back the address of the next descriptor to be
+/// loaded, or 0 once the last one has been reached.
+pub const CHANNEL_LIST: u32 = 0xc;
+/// Channel: transfer mode, see `MODE_*`. Must be set before writing `CHANNEL_LEN`, overwritten by
+/// every descriptor in linked-list mode.
+pub const CHANNEL_MODE: u32 = 0x10;
+/// Channel: number of words per row in `MODE_2D`
+pub const CHANNEL_ROW_LEN: u32 = 0x14;
+/// Channel: distance in bytes between the start of two source rows in `MODE_2D`
+pub const CHANNEL_SRC_STRIDE: u32 = 0x18;
+/// Channel: distance in bytes between the start of two destination rows in `MODE_2D`
+pub const CHANNEL_DST_STRIDE: u32 = 0x1c;
+/// Channel: status, see `STATUS_*`. Read-only.
+pub const CHANNEL_STATUS: u32 = 0x20;
+/// Channel: address that caused the last error. Read-only.
+pub const CHANNEL_FAULT_ADDR: u32 = 0x24;
+
+/// Returns the offset of the register `reg` (one of the `CHANNEL_*` offsets) for `channel`
+pub const fn channel_reg(channel: u32, reg: u32) -> u32 {
+    CHANNEL_BASE + channel * CHANNEL_STRIDE + reg
+}
+
+/// Status: the channel is running
+pub const STATUS_BUSY: u32 = 1 << 0;
+/// Status: the last transfer was aborted because of an error. Cleared when a new transfer is
+/// started.
+pub const STATUS_ERROR: u32 = 1 << 1;
+/// Status: cause of the error, see `CAUSE_*`
+pub const STATUS_CAUSE_SHIFT: u32 = 4;
+pub const STATUS_CAUSE_MASK: u32 = 0xf << STATUS_CAUSE_SHIFT;
+
+/// Error cause: the source isn't RAM, ROM or a valid device
+pub const CAUSE_SRC: u32 = 1;
+/// Error cause: the destination isn't RAM or a valid device
+pub const CAUSE_DST: u32 = 2;
+/// Error cause: a descriptor couldn't be read (misaligned or not in RAM or ROM)
+pub const CAUSE_DESC: u32 = 3;
+
+/// Mode: nothing is read, the value of `CHANNEL_SRC` is written to every destination word
+pub const MODE_FILL: u32 = 1 << 24;
+/// Mode: the source address is not incremented. Only meaningful for memory sources, device
+/// sources never increment.
+pub const MODE_SRC_FIXED: u32 = 1 << 25;
+/// Mode: the destination address is not incremented. Only meaningful for memory destinations,
+/// device destinations never increment.
+pub const MODE_DST_FIXED: u32 = 1 << 26;
+/// Mode: the transfer is split in rows of `CHANNEL_ROW_LEN` words. At the end of each row the
+/// (non-fixed) memory addresses move to the start of the next row using the stride registers.
+pub const MODE_2D: u32 = 1 << 27;
+/// All the mode bits. They're at the same position in `CHANNEL_MODE` and in a descriptor's `ctrl`
+/// word.
+pub const MODE_MASK: u32 = 0xf << 24;
+
+/// Mask of the length (in words) in a descriptor's `ctrl` word
+pub const DESC_LEN_MASK: u32 = 0xff_ffff;
+/// If set in a descriptor's `ctrl` word, the channel's bit in `IRQ_STATUS` is set when the
+/// descriptor has been processed, even if it's not the last of the chain.
+pub const DESC_IRQ: u32 = 1 << 31;
@@ -0,0 +1,98 @@
+//! GPU registers and command encoding
+//!
+//! Commands are sent one word at a time through the `COMMAND` register, the opcode is in the top
+//! byte of the first word. Some commands take extra parameter words.
+
+/// Write: command FIFO. Read: status register.
+pub const COMMAND: u32 = 0x0;
+
+/// Status: the command FIFO is full
+pub const STATUS_FIFO_FULL: u32 = 1;
+/// Status: number of words in the command FIFO in [31:24]
+pub const STATUS_FIFO_LEN_SHIFT: u32 = 24;
+
+/// Do nothing. The low 24 bits are ignored.
+pub const OP_NOP: u8 = 0x00;
+/// Start drawing a new frame
+pub const OP_DRAW_START: u8 = 0x01;
+/// Finish the current frame and display it
+pub const OP_DRAW_END: u8 = 0x02;
+/// Draw configuration, the sub-command is in [23:16] (see the `CONFIG_*` constants)
+pub const OP_CONFIG: u8 = 0x03;
+/// Matrix operation, the sub-command is in [23:16] (see the `MATRIX_*` constants) and the target
+/// matrix in [14:12]
+pub const OP_MATRIX: u8 = 0x10;
+/// First triangle opcode. The triangle opcodes go up to `OP_TRIANGLE_LAST` and encode the blend
+/// mode in bits [3:1]
+pub const OP_TRIANGLE: u8 = 0x40;
+pub const OP_TRIANGLE_LAST: u8 = 0x7f;
+
+/// Config: select the draw matrix in [3:0]
+pub const CONFIG_DRAW_MATRIX: u8 = 0x01;
+/// Config: fog color. Followed by one parameter word with the color as 0xBBGGRR
+pub const CONFIG_FOG_COLOR: u8 = 0x02;
+/// Config: fog distances. Followed by two s15.16 parameter words: near and far. Fog is disabled if
+/// far <= near.
+pub const CONFIG_FOG_RANGE: u8 = 0x03;
+
+/// Matrix: reset to identity
+pub const MATRIX_RESET: u8 = 0x00;
+/// Matrix: set the component at row [5:4] and column [1:0]. Followed by one s15.16 parameter word.
+pub const MATRIX_SET: u8 = 0x01;
+/// Matrix: target = A * B, A in [6:4], B in [2:0]
+pub const MATRIX_MULTIPLY: u8 = 0x02;
+
+/// Triangle blend mode using a flat color
+pub const BLEND_FLAT: u32 = 0;
+/// Triangle blend mode using a color per vertex
+pub const BLEND_GOURAUD: u32 = 2;
+
+/// Returns the opcode of command word `cmd`
+pub const fn op(cmd: u32) -> u8 {
+    (cmd >> 24) as u8
+}
+
+/// Returns the sub-command of config and matrix command word `cmd`
+pub const fn sub_op(cmd: u32) -> u8 {
+    (cmd >> 16) as u8
+}
+
+/// Build a command word with `op` and `sub_op`, `args` goes in the low 16 bits
+pub const fn command(op: u8, sub_op: u8, args: u32) -> u32 {
+    ((op as u32) << 24) | ((sub_op as u32) << 16) | (args & 0xffff)
+}
+
+pub const fn draw_start() -> u32 {
+    command(OP_DRAW_START, 0, 0)
+}
+
+pub const fn draw_end() -> u32 {
+    command(OP_DRAW_END, 0, 0)
+}
+
+pub const fn set_draw_matrix(m: u32) -> u32 {
+    command(OP_CONFIG, CONFIG_DRAW_MATRIX, m & 0xf)
+}
+
+pub const fn matrix_reset(m: u32) -> u32 {
+    command(OP_MATRIX, MATRIX_RESET, (m & 7) << 12)
+}
+
+pub const fn matrix_set(m: u32, row: u32, col: u32) -> u32 {
+    command(OP_MATRIX, MATRIX_SET, ((m & 7) << 12) | ((row & 3) << 4) | (col & 3))
+}
+
+/// `m` = `a` * `b`
+pub const fn matrix_multiply(m: u32, a: u32, b: u32) -> u32 {
+    command(OP_MATRIX, MATRIX_MULTIPLY, ((m & 7) << 12) | ((a & 7) << 4) | (b & 7))
+}
+
+/// Triangle command with `blend_mode` and the color of the first vertex as 0xBBGGRR
+pub const fn triangle(blend_mode: u32, rgb: u32) -> u32 {
+    ((OP_TRIANGLE as u32) << 24) | ((blend_mode & 7) << 25) | (rgb & 0xff_ffff)
+}
+
+/// Returns the blend mode of triangle command word `cmd`
+pub const fn triangle_blend_mode(cmd: u32) -> u32 {
+    (cmd >> 25) & 7
+}
@@ -0,0 +1,114 @@
+//! Input device serial interface registers
+
+/// Configuration register
+pub const CONF: u32 = 0x0;
+/// Port select
+pub const PORT: u32 = 0x4;
+/// Write: byte to send. Read: byte received.
+pub const TX_RX: u32 = 0x8;
+
+/// CONF: clear the TX and RX FIFOs and deselect the port
+pub const CONF_CLEAR: u32 = 1;
+/// CONF: raise an IRQ once the TX FIFO is empty
+pub const CONF_TX_COMPLETE_IRQ: u32 = 1 << 1;
+/// CONF: baud rate divider (minus 1) in [31:16], relative to CPU_FREQ / 16
+pub const CONF_CLK_DIV_SHIFT: u32 = 16;
+
+/// Depth of the TX and RX FIFOs
+pub const FIFO_DEPTH: usize = 16;
+
+/// Port of the touchscreen
+pub const PORT_TOUCHSCREEN: u8 = 0;
+/// Port of the gamepad
+pub const PORT_GAMEPAD: u8 = 1;
+/// Port of the memory card
+pub const PORT_MEMCARD: u8 = 2;
+
+// Touchscreen protocol, one byte per line. Coordinates go from 0 to 0x400. The touchscreen is
+// slow and doesn't respond if the baud rate divider in `CONF` is lower than 3.
+//
+//   TX 'T' (address the touchscreen)
+//
+// Then either a single touch point:
+//
+//   TX 'S' (read state), RX 'a'. Any other value means no touchscreen is connected.
+//   RX X [15:8] (0xffff if there's no touch)
+//   RX X [7:0]
+//   RX Y [15:8]
+//   RX Y [7:0]
+//
+// Or all the touch points:
+//
+//   TX 'M' (read all points), RX number of touch points (at most `TOUCH_MAX_POINTS`). 0xff means
+//   no touchscreen is connected.
+//
+// Followed by `TOUCH_POINT_LEN` bytes per touch point:
+//
+//   RX ID, unique among the current touch points and stable for as long as the touch lasts
+//   RX X [15:8]
+//   RX X [7:0]
+//   RX Y [15:8]
+//   RX Y [7:0]
+//   RX pressure, 1 (lightest) to 0xff. 0 if the pressure is unknown.
+
+/// Maximum number of touch points reported by the `'M'` command. Limited so that the whole
+/// transaction fits in the FIFOs.
+pub const TOUCH_MAX_POINTS: usize = 2;
+/// Length of the data for each touch point in the `'M'` command
+pub const TOUCH_POINT_LEN: usize = 6;
+
+// Gamepad protocol, one byte per line:
+//
+//   TX 'P' (address the gamepad)
+//   TX 'S' (read state), RX pad ID (`PAD_ID_*`). Any other value means no pad is connected.
+//   RX buttons [15:8], a bit is 0 if the button is pressed (see `PAD_*`)
+//   RX buttons [7:0]
+//
+// Analog pads then return the position of the sticks, 0x80 is the center:
+//
+//   RX left stick X (0x00 is left)
+//   RX left stick Y (0x00 is up)
+//   RX right stick X
+//   RX right stick Y
+
+/// Gamepad ID: digital pad
+pub const PAD_ID_DIGITAL: u8 = b'D';
+/// Gamepad ID: pad with two analog sticks
+pub const PAD_ID_ANALOG: u8 = b'A';
+
+pub const PAD_UP: u16 = 1 << 0;
+pub const PAD_DOWN: u16 = 1 << 1;
+pub const PAD_LEFT: u16 = 1 << 2;
+pub const PAD_RIGHT: u16 = 1 << 3;
+pub const PAD_A: u16 = 1 << 4;
+pub const PAD_B: u16 = 1 << 5;
+pub const PAD_X: u16 = 1 << 6;
+pub const PAD_Y: u16 = 1 << 7;
+pub const PAD_L: u16 = 1 << 8;
+pub const PAD_R: u16 = 1 << 9;
+pub const PAD_START: u16 = 1 << 10;
+pub const PAD_SELECT: u16 = 1 << 11;
+/// Left stick click (analog pads only)
+pub const PAD_L3: u16 = 1 << 12;
+/// Right stick click (analog pads only)
+pub const PAD_R3: u16 = 1 << 13;
+
+// Memory card protocol, one byte per line. The card is made of `MEMCARD_BLOCK_COUNT` blocks of
+// `MEMCARD_BLOCK_LEN` bytes, erased blocks read as 0xff.
+//
+//   TX 'C' (address the memory card)
+//   TX 'R' (read) or 'W' (write), RX 'c'. Any other value means no card is connected.
+//   TX block number [15:8]
+//   TX block number [7:0]
+//
+// Then `MEMCARD_BLOCK_LEN` data bytes, RX for a read or TX for a write, and finally:
+//
+//   RX 'k' if the command succeeded. Any other value means that the block number was out of range
+//   (or, for a write, that the block hasn't been modified).
+
+/// Length of a memory card block in bytes
+pub const MEMCARD_BLOCK_LEN: usize = 8;
+/// Number of blocks on a memory card
+pub const MEMCARD_BLOCK_COUNT: usize = 8192;
+/// Total size of a memory card in bytes
+pub const MEMCARD_SIZE: usize = MEMCARD_BLOCK_LEN * MEMCARD_BLOCK_COUNT;
@@ -0,0 +1,29 @@
+//! Interrupt controller registers
+
+/// Pending IRQs. Write 1s to acknowledge.
+pub const PENDING: u32 = 0x0;
+/// Enabled IRQs
+pub const ENABLED: u32 = 0x4;
+
+/// All interrupts supported by the interrupt controller (minus the MTI interrupt that's directly
+/// handled by the CPU). The value is the bit position in the `PENDING` and `ENABLED` registers.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Interrupt {
+    /// Triggered by the GPU every time a frame has completed
+    VSync = 0,
+    /// Triggered when the input device interface's IRQ line has a rising edge
+    InputDev = 1,
+    /// Triggered when a DMA transfer is complete
+    DmaDone = 2,
+    /// Triggered when the SPU's IRQ line has a rising edge
+    Spu = 3,
+    /// Triggered when a DMA transfer is aborted because of an error
+    DmaError = 4,
+}
+
+impl Interrupt {
+    /// Bit mask for this interrupt in the controller's registers
+    pub const fn mask(self) -> u32 {
+        1 << (self as u32)
+    }
+}
@@ -0,0 +1,210 @@
+//! SPU registers
+//!
+//! Stereo volume registers contain the left volume in the high halfword and the right volume in
+//! the low halfword, per-voice bitmask registers have one bit per voice.
+
+/// Number of voices
+pub const VOICE_COUNT: u32 = 24;
+/// SPU RAM size in bytes
+pub const RAM_SIZE: u32 = 512 * 1024;
+
+/// Main volume
+pub const MAIN_VOLUME: u32 = 0x00;
+/// Start the voices in the bitmask
+pub const VOICE_ON: u32 = 0x04;
+/// Release the voices in the bitmask
+pub const VOICE_OFF: u32 = 0x08;
+/// Voices whose output is sent to the reverb unit
+pub const REVERB_VOICES: u32 = 0x0c;
+/// RAM transfer pointer, in bytes. Also used by the DMA.
+pub const RAM_ADDR: u32 = 0x10;
+/// Write two halfwords at the RAM transfer pointer
+pub const RAM_DATA: u32 = 0x14;
+/// Voices outputting noise instead of their samples
+pub const NOISE_VOICES: u32 = 0x18;
+/// Voices whose step is modulated by the output of the previous voice
+pub const PITCH_MOD_VOICES: u32 = 0x1c;
+/// Noise clock: [5:2] shift, [1:0] step
+pub const NOISE_CLOCK: u32 = 0x20;
+/// Voices that reached an end block since they were last started (read only)
+pub const ENDX: u32 = 0x24;
+/// Voices currently running (read only)
+pub const ACTIVE_VOICES: u32 = 0x28;
+/// Address in SPU RAM triggering an IRQ when accessed, in bytes
+pub const IRQ_ADDR: u32 = 0x2c;
+/// Voices triggering an IRQ when they reach an end block
+pub const IRQ_END_VOICES: u32 = 0x30;
+/// IRQ control, see `IRQ_CONTROL_*`
+pub const IRQ_CONTROL: u32 = 0x34;
+/// Pending IRQ sources, see `IRQ_*`. Write 1s to acknowledge.
+pub const IRQ_STATUS: u32 = 0x38;
+/// Reverb control, see `REVERB_CONTROL_*`
+pub const REVERB_CONTROL: u32 = 0x40;
+/// Start of the reverb work area in bytes, the work area stretches to the end of SPU RAM
+pub const REVERB_BASE: u32 = 0x44;
+/// Reverb output volume
+pub const REVERB_VOLUME: u32 = 0x48;
+/// The 32 reverb configuration registers, two per word (the even register in the low halfword),
+/// in the same order and format as on the PSX
+pub const REVERB_CONFIG: u32 = 0x80;
+pub const REVERB_CONFIG_LAST: u32 = 0xbc;
+/// PCM stream control, see `STREAM_CONTROL_*`
+pub const STREAM_CONTROL: u32 = 0xc0;
+/// PCM stream FIFO, one stereo frame per word (left sample in the high halfword)
+pub const STREAM_DATA: u32 = 0xc4;
+/// PCM stream volume
+pub const STREAM_VOLUME: u32 = 0xc8;
+/// PCM stream FIFO level in frames (read only)
+pub const STREAM_LEVEL: u32 = 0xcc;
+/// Capture control, see `CAPTURE_CONTROL_*`
+pub const CAPTURE_CONTROL: u32 = 0xd0;
+/// Start of the capture area in bytes
+pub const CAPTURE_BASE: u32 = 0xd4;
+/// Current position in the capture buffers, in samples (read only)
+pub const CAPTURE_POS: u32 = 0xd8;
+/// Main volume sweep config, see `SWEEP_*`
+pub const MAIN_VOLUME_SWEEP: u32 = 0xdc;
+
+/// Start of the per-voice registers
+pub const VOICE_BASE: u32 = 0x100;
+/// Size of the register block of each voice
+pub const VOICE_STRIDE: u32 = 0x40;
+
+/// Voice: step length, 14 bit fixed point with 12 fractional bits
+pub const VOICE_STEP: u32 = 0x00;
+/// Voice: start address, in 16-byte blocks
+pub const VOICE_START_BLOCK: u32 = 0x04;
+/// Voice: volume
+pub const VOICE_VOLUME: u32 = 0x08;
+/// Voice: ADSR configuration
+pub const VOICE_ADSR: u32 = 0x0c;
+/// Voice: current ADSR level
+pub const VOICE_ADSR_LEVEL: u32 = 0x10;
+/// Voice: ADSR state, see `ADSR_STATE_*` (read only)
+pub const VOICE_ADSR_STATE: u32 = 0x14;
+/// Voice: current position in SPU RAM in bytes (read only)
+pub const VOICE_CUR_ADDR: u32 = 0x18;
+/// Voice: volume sweep config, see `SWEEP_*`
+pub const VOICE_VOLUME_SWEEP: u32 = 0x1c;
+/// Voice: loop address, in 16-byte blocks. Jumped to when the voice reaches an end block with the
+/// loop flag set, overwritten when the voice reaches a block with the loop start flag set.
+pub const VOICE_LOOP_BLOCK: u32 = 0x20;
+
+/// Returns the offset of voice register `reg` for `voice`
+pub const fn voice_reg(voice: u32, reg: u32) -> u32 {
+    VOICE_BASE + voice * VOICE_STRIDE + reg
+}
+
+/// IRQ status: a voice accessed the IRQ address
+pub const IRQ_ADDRESS: u32 = 1;
+/// IRQ status: a voice with the end IRQ enabled reached an end block
+pub const IRQ_VOICE_END: u32 = 1 << 1;
+/// IRQ status: the stream FIFO dropped below half full
+pub const IRQ_STREAM: u32 = 1 << 2;
+
+/// IRQ control: enable the address IRQ
+pub const IRQ_CONTROL_ADDR_ENABLE: u32 = 1;
+
+/// Reverb control: enable the reverb unit
+pub const REVERB_CONTROL_ENABLE: u32 = 1;
+
+/// Stream control: enable the stream
+pub const STREAM_CONTROL_ENABLE: u32 = 1;
+/// Stream control: raise an IRQ when the FIFO drops below half full
+pub const STREAM_CONTROL_IRQ_ENABLE: u32 = 1 << 1;
+/// Stream control: clear the FIFO
+pub const STREAM_CONTROL_CLEAR: u32 = 1 << 2;
+
+/// Capture control: enable the capture
+pub const CAPTURE_CONTROL_ENABLE: u32 = 1;
+
+/// Volume sweep (16 bits per channel): enable the sweep
+pub const SWEEP_ENABLE: u16 = 1 << 15;
+/// Volume sweep: exponential mode
+pub const SWEEP_EXPONENTIAL: u16 = 1 << 14;
+/// Volume sweep: sweep down instead of up
+pub const SWEEP_DECREASE: u16 = 1 << 13;
+/// Volume sweep: invert the phase of the output
+pub const SWEEP_INVERT: u16 = 1 << 12;
+/// Volume sweep: rate shift in [6:2]
+pub const SWEEP_SHIFT_SHIFT: u16 = 2;
+
+pub const ADSR_STATE_ATTACK: u32 = 0;
+pub const ADSR_STATE_DECAY: u32 = 1;
+pub const ADSR_STATE_SUSTAIN: u32 = 2;
+pub const ADSR_STATE_RELEASE: u32 = 3;
+pub const ADSR_STATE_STOPPED: u32 = 4;
+
+/// ADSR envelope configuration, built with the layout of the `VOICE_ADSR` register:
+///
+///   [30:26] - Release shift
+///   [25]    - Release exponential
+///   [24:21] - Sustain level
+///   [20:19] - Sustain step
+///   [18:14] - Sustain shift
+///   [13]    - Sustain decrease
+///   [12]    - Sustain exponential
+///   [11:8]  - Decay   shift
+///   [7:6]   - Attack  step
+///   [5:1]   - Attack  shift
+///   [0]     - Attack  exponential
+///
+/// Higher shift and step values yield slower envelopes.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Adsr(u32);
+
+impl Adsr {
+    /// Instant attack and release, full level sustain
+    pub const fn new() -> Adsr {
+        Adsr(0).sustain_level(15).sustain(31, 3, false, false)
+    }
+
+    pub const fn from_raw(raw: u32) -> Adsr {
+        Adsr(raw)
+    }
+
+    pub const fn raw(self) -> u32 {
+        self.0
+    }
+
+    /// `shift` is in 0..=31, `step` in 0..=3
+    pub const fn attack(self, shift: u8, step: u8, exponential: bool) -> Adsr {
+        let v = ((step as u32 & 3) << 6) | ((shift as u32 & 0x1f) << 1) | exponential as u32;
+
+        Adsr((self.0 & !0xff) | v)
+    }
+
+    /// `shift` is in 0..=15. The decay is always exponential and stops at the sustain level.
+    pub const fn decay(self, shift: u8) -> Adsr {
+        Adsr((self.0 & !(0xf << 8)) | ((shift as u32 & 0xf) << 8))
+    }
+
+    /// Sustain level in 0..=15, 15 is full volume
+    pub const fn sustain_level(self, level: u8) -> Adsr {
+        Adsr((self.0 & !(0xf << 21)) | ((level as u32 & 0xf) << 21))
+    }
+
+    /// `shift` is in 0..=31, `step` in 0..=3. The level increases during sustain unless
+    /// `decrease` is set.
+    pub const fn sustain(self, shift: u8, step: u8, exponential: bool, decrease: bool) -> Adsr {
+        let v = ((step as u32 & 3) << 19)
+            | ((shift as u32 & 0x1f) << 14)
+            | ((decrease as u32) << 13)
+            | ((exponential as u32) << 12);
+
+        Adsr((self.0 & !(0x1ff << 12)) | v)
+    }
+
+    /// `shift` is in 0..=31
+    pub const fn release(self, shift: u8, exponential: bool) -> Adsr {
+        let v = ((shift as u32 & 0x1f) << 26) | ((exponential as u32) << 25);
+
+        Adsr((self.0 & !(0x3f << 25)) | v)
+    }
+}
+
+impl Default for Adsr {
+    fn default() -> Adsr {
+        Adsr::new()
+    }
+}
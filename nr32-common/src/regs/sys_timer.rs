@@ -0,0 +1,10 @@
+//! System timer (MTIME) registers
+
+/// MTIME[31:0]
+pub const MTIME_L: u32 = 0x0;
+/// MTIME[63:32]
+pub const MTIME_H: u32 = 0x4;
+/// MTIMECMP[31:0]
+pub const MTIMECMP_L: u32 = 0x8;
+/// MTIMECMP[63:32]
+pub const MTIMECMP_H: u32 = 0xc;
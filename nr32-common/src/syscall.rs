@@ -1,4 +1,5 @@
 use crate::error::{SysError, SysResult};
+use crate::regs;
 
 /// Suspend task for [a1:a0] MTIME ticks
 ///
@@ -79,6 +80,17 @@ pub const SYS_FUTEX_WAKE: u32 = 0x0b;
 /// - a2: length in words
 pub const SYS_DO_DMA: u32 = 0x0c;
 
+/// Put task to sleep until the SPU IRQ fires. Returns immediately if an SPU IRQ is already pending.
+///
+/// The IRQ sources must be acknowledged in the SPU by the caller, otherwise this call will keep
+/// returning immediately.
+pub const SYS_WAIT_FOR_SPU_IRQ: u32 = 0x0d;
+
+/// Linked-list DMA transfer. Suspends task until the whole chain has been processed.
+///
+/// - a0: address of the first `DmaDescriptor` of the chain
+pub const SYS_DO_DMA_LIST: u32 = 0x0e;
+
 /// Representation of a DMA source/dest address
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct DmaAddr(pub u32);
@@ -86,6 +98,10 @@ pub struct DmaAddr(pub u32);
 impl DmaAddr {
     /// The DMA reads from or writes to the GPU
     pub const GPU: DmaAddr = DmaAddr(2);
+    /// The DMA reads from or writes to SPU RAM, starting at the SPU's RAM transfer pointer
+    pub const SPU: DmaAddr = DmaAddr(2 | (1 << 4));
+    /// The DMA writes to the SPU's PCM stream FIFO
+    pub const SPU_STREAM: DmaAddr = DmaAddr(2 | (2 << 4));
 
     pub fn target(self) -> SysResult<DmaTarget> {
         match self.0 & 3 {
@@ -94,6 +110,8 @@ impl DmaAddr {
                 // Device
                 match (self.0 >> 4) & 0xff {
                     0 => Ok(DmaTarget::Gpu),
+                    1 => Ok(DmaTarget::Spu),
+                    2 => Ok(DmaTarget::SpuStream),
                     _ => Err(SysError::Invalid),
                 }
             }
@@ -118,6 +136,7 @@ impl DmaAddr {
 
         match a.target()? {
             DmaTarget::Memory => (),
+            DmaTarget::Spu => (),
             _ => return Err(SysError::Invalid),
         }
 
@@ -130,6 +149,8 @@ impl DmaAddr {
         match a.target()? {
             DmaTarget::Memory => (),
             DmaTarget::Gpu => (),
+            DmaTarget::Spu => (),
+            DmaTarget::SpuStream => (),
         }
 
         Ok(a)
@@ -140,10 +161,96 @@ impl DmaAddr {
     }
 }
 
+/// Descriptor for linked-list DMA transfers. Must be word-aligned in RAM or ROM.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(C)]
+pub struct DmaDescriptor {
+    /// Raw `DmaAddr` of the source, or value to write in fill mode
+    pub src: u32,
+    /// Raw `DmaAddr` of the destination
+    pub dst: u32,
+    /// Length in words in the low 24 bits (see `regs::dma::DESC_LEN_MASK`), plus the
+    /// `regs::dma::MODE_*` and `regs::dma::DESC_*` flags
+    pub ctrl: u32,
+    /// Address of the next descriptor, 0 for the last one
+    pub next: u32,
+    /// Words per row in 2D mode, ignored otherwise
+    pub row_len: u32,
+    /// Distance in bytes between the start of two source rows in 2D mode, ignored otherwise
+    pub src_stride: u32,
+    /// Distance in bytes between the start of two destination rows in 2D mode, ignored otherwise
+    pub dst_stride: u32,
+    /// Must be 0
+    pub reserved: u32,
+}
+
+impl DmaDescriptor {
+    /// Copy `len_words` from `src` to `dst`
+    pub fn new(src: DmaAddr, dst: DmaAddr, len_words: usize) -> SysResult<DmaDescriptor> {
+        Self::with_src(src.raw(), dst, len_words, 0)
+    }
+
+    /// Write `len_words` copies of `val` to `dst`
+    pub fn fill(dst: DmaAddr, val: u32, len_words: usize) -> SysResult<DmaDescriptor> {
+        Self::with_src(val, dst, len_words, regs::dma::MODE_FILL)
+    }
+
+    fn with_src(src: u32, dst: DmaAddr, len_words: usize, mode: u32) -> SysResult<DmaDescriptor> {
+        if len_words as u32 & !regs::dma::DESC_LEN_MASK != 0 {
+            return Err(SysError::Invalid);
+        }
+
+        Ok(DmaDescriptor {
+            src,
+            dst: dst.raw(),
+            ctrl: len_words as u32 | mode,
+            next: 0,
+            row_len: 0,
+            src_stride: 0,
+            dst_stride: 0,
+            reserved: 0,
+        })
+    }
+
+    /// Don't increment the source address
+    pub fn src_fixed(mut self) -> DmaDescriptor {
+        self.ctrl |= regs::dma::MODE_SRC_FIXED;
+        self
+    }
+
+    /// Don't increment the destination address
+    pub fn dst_fixed(mut self) -> DmaDescriptor {
+        self.ctrl |= regs::dma::MODE_DST_FIXED;
+        self
+    }
+
+    /// Copy rows of `row_len` words, the start of each row being `src_stride` and `dst_stride`
+    /// bytes after the start of the previous one
+    pub fn rect(mut self, row_len: usize, src_stride: usize, dst_stride: usize) -> DmaDescriptor {
+        self.ctrl |= regs::dma::MODE_2D;
+        self.row_len = row_len as u32;
+        self.src_stride = src_stride as u32;
+        self.dst_stride = dst_stride as u32;
+        self
+    }
+
+    pub fn len_words(&self) -> usize {
+        (self.ctrl & regs::dma::DESC_LEN_MASK) as usize
+    }
+
+    pub fn mode(&self) -> u32 {
+        self.ctrl & regs::dma::MODE_MASK
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum DmaTarget {
     /// System bus (incrementing addresses, RAM/ROM)
     Memory,
     /// GPU port
-    Gpu
+    Gpu,
+    /// SPU RAM
+    Spu,
+    /// SPU PCM stream FIFO
+    SpuStream,
 }
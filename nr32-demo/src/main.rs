@@ -7,18 +7,20 @@ extern crate alloc;
 
 use core::time::Duration;
 
-use alloc::sync::Arc;
 use nr32_sys::allocator;
-use nr32_sys::dma::{DmaAddr, do_dma};
+use nr32_sys::dma::{DmaAddr, DmaDescriptor, do_dma_list};
 use nr32_sys::fs::Fs;
-use nr32_sys::gpu::send_to_gpu;
+use nr32_sys::gamepad;
+use nr32_sys::gpu::{draw_end, draw_start, set_fog};
 use nr32_sys::math::{
     Angle, Fp32, matrix,
     matrix::{MAT0, MAT1, MAT2, MAT3, MAT4, MAT5, MAT7},
 };
-use nr32_sys::sync::{Fifo, Semaphore};
-use nr32_sys::syscall::{input_device, sleep, wait_for_vsync};
+use nr32_sys::memcard;
+use nr32_sys::spu::{self, SampleBank};
+use nr32_sys::syscall::{SysError, sleep, wait_for_vsync};
 use nr32_sys::thread::ThreadBuilder;
+use nr32_sys::touchscreen::{TouchPoint, Touches, read_touches};
 
 #[global_allocator]
 static ALLOCATOR: allocator::Allocator = allocator::Allocator::new();
@@ -35,13 +37,6 @@ mod panic_handler {
     }
 }
 
-struct DmaOp {
-    from: DmaAddr,
-    to: DmaAddr,
-    len_words: usize,
-    dma_done: Option<Arc<Semaphore>>,
-}
-
 #[unsafe(no_mangle)]
 pub extern "C" fn nr32_main() {
     log::set_logger(&nr32_sys::logger::LOGGER).unwrap();
@@ -53,38 +48,12 @@ pub extern "C" fn nr32_main() {
 
     info!("Loaded FS: {}", fs.fsck().unwrap());
 
-    let dma_fifo: Arc<Fifo<DmaOp, 8>> = Arc::new(Fifo::new());
-
-    let fifo_c = dma_fifo.clone();
-
-    ThreadBuilder::new(*b"DMA ")
-        .stack_size(1024)
-        .priority(-1)
-        .spawn(move || {
-            info!("DMA task start");
-            loop {
-                let c = fifo_c.as_ref().pop();
-
-                let r = do_dma(c.from, c.to, c.len_words);
-
-                if let Err(e) = r {
-                    error!(
-                        "DMA {:?} -> {:?} [{}W] failed: {:?}",
-                        c.from, c.to, c.len_words, e
-                    );
-                }
-
-                if let Some(s) = c.dma_done {
-                    s.post();
-                }
-            }
-        })
-        .unwrap();
-
     start_audio(fs);
 
     info!("Audio started");
 
+    count_boots();
+
     // MAT0: Draw matrix
     // MAT1: MVP matrix
     // MAT2: Projection matrix
@@ -114,6 +83,9 @@ pub extern "C" fn nr32_main() {
         [0, 1, 0].into(),
     );
 
+    // Fade distant geometry into the (black) background
+    set_fog([0, 0, 0], 80.into(), 400.into());
+
     let mut angle_y = Angle::from_degrees(0.into());
     let mut angle_x = Angle::from_degrees(0.into());
     let a_increment = Angle::from_degrees((0.5).into());
@@ -121,44 +93,66 @@ pub extern "C" fn nr32_main() {
     let ship = fs.contents(&[b"assets", b"models", b"ship.nr3d"]).unwrap();
     let beach = fs.contents(&[b"assets", b"models", b"beach.nr3d"]).unwrap();
 
-    let mut prev_touch: Option<(u16, u16)> = None;
-
-    let render_done = Arc::new(Semaphore::new(0));
+    let mut prev_touches = Touches::default();
+    let mut zoom = Fp32::ONE;
 
     loop {
-        let touch = read_touch_screen();
-
-        if let (Some(p), Some(t)) = (prev_touch, touch) {
-            let (px, py) = p;
-            let (tx, ty) = t;
-            let px = px as i16;
-            let py = py as i16;
-            let tx = tx as i16;
-            let ty = ty as i16;
-
-            let dx = tx - px;
-            let dy = ty - py;
-
-            let angle_dy = a_increment * dx.unsigned_abs();
-            let angle_dx = a_increment * dy.unsigned_abs();
-
-            if dx >= 0 {
-                angle_y += angle_dy;
-            } else {
-                angle_y -= angle_dy;
+        let touches = read_touches().ok().flatten().unwrap_or_default();
+
+        match (prev_touches.as_slice(), touches.as_slice()) {
+            // Drag to rotate
+            ([p], [t]) if p.id == t.id => {
+                let dx = t.x as i16 - p.x as i16;
+                let dy = t.y as i16 - p.y as i16;
+
+                let angle_dy = a_increment * dx.unsigned_abs();
+                let angle_dx = a_increment * dy.unsigned_abs();
+
+                if dx >= 0 {
+                    angle_y += angle_dy;
+                } else {
+                    angle_y -= angle_dy;
+                }
+
+                if dy >= 0 {
+                    angle_x += angle_dx;
+                } else {
+                    angle_x -= angle_dx;
+                }
             }
+            // Pinch to zoom
+            ([p0, p1], [t0, t1]) if p0.id == t0.id && p1.id == t1.id => {
+                let prev_dist = touch_distance(p0, p1);
+                let dist = touch_distance(t0, t1);
 
-            if dy >= 0 {
-                angle_x += angle_dx;
-            } else {
-                angle_x -= angle_dx;
+                if prev_dist > 0 && dist > 0 {
+                    zoom = (zoom * Fp32::ratio(dist, prev_dist)).clamp(MIN_ZOOM, MAX_ZOOM);
+                }
             }
+            _ => (),
         }
 
-        prev_touch = touch;
+        prev_touches = touches;
+
+        if let Ok(Some(pad)) = gamepad::read_gamepad() {
+            // Same direction as a touch drag
+            let pad_increment = a_increment * 4;
+
+            if pad.pressed(gamepad::PAD_LEFT) {
+                angle_y -= pad_increment;
+            }
+            if pad.pressed(gamepad::PAD_RIGHT) {
+                angle_y += pad_increment;
+            }
+            if pad.pressed(gamepad::PAD_UP) {
+                angle_x -= pad_increment;
+            }
+            if pad.pressed(gamepad::PAD_DOWN) {
+                angle_x += pad_increment;
+            }
+        }
 
-        // Start draw
-        send_to_gpu(0x01 << 24);
+        draw_start();
 
         matrix::translate(m_mat, 0.into(), (0).into(), (-50).into());
         matrix::rotate_x(MAT7, angle_x);
@@ -166,66 +160,66 @@ pub extern "C" fn nr32_main() {
         matrix::rotate_y(MAT7, angle_y);
         matrix::multiply(m_mat, m_mat, MAT7);
 
-        matrix::scale(MAT7, 1.1.into(), 1.1.into(), 1.1.into());
+        let scale = Fp32::from_f32(1.1) * zoom;
+        matrix::scale(MAT7, scale, scale, scale);
         matrix::multiply(m_mat, m_mat, MAT7);
 
         matrix::multiply(mvp_mat, p_mat, v_mat);
         matrix::multiply(mvp_mat, mvp_mat, m_mat);
 
-        dma_fifo.as_ref().push(DmaOp {
-            from: DmaAddr::from_memory(ship.as_ptr() as usize).unwrap(),
-            to: DmaAddr::GPU,
-            len_words: ship.len() / 4,
-            dma_done: None,
-        });
-        dma_fifo.as_ref().push(DmaOp {
-            from: DmaAddr::from_memory(beach.as_ptr() as usize).unwrap(),
-            to: DmaAddr::GPU,
-            len_words: beach.len() / 4,
-            dma_done: Some(render_done.clone()),
-        });
-
-        render_done.wait();
+        let mut draw_list = [
+            DmaDescriptor::new(
+                DmaAddr::from_memory(ship.as_ptr() as usize).unwrap(),
+                DmaAddr::GPU,
+                ship.len() / 4,
+            )
+            .unwrap(),
+            DmaDescriptor::new(
+                DmaAddr::from_memory(beach.as_ptr() as usize).unwrap(),
+                DmaAddr::GPU,
+                beach.len() / 4,
+            )
+            .unwrap(),
+        ];
+
+        if let Err(e) = do_dma_list(&mut draw_list) {
+            error!("Draw DMA failed: {:?}", e);
+        }
 
-        // End draw
-        send_to_gpu(0x02 << 24);
+        draw_end();
 
         wait_for_vsync();
     }
 }
 
-/// Returns the current coordinates of the screen touch (if any).
-/// The values are in the range [0; 0x400] where [0, 0] is top-left and [0x400, 0x400] is
-/// bottom-right
-fn read_touch_screen() -> Option<(u16, u16)> {
-    let cmd: &mut [u8] = &mut [
-        b'T', // Address touchscreen
-        b'S', // Read state
-        0,    // x high
-        0,    // x low
-        0,    // y high
-        0,    // y low
-    ];
-    input_device(0, cmd).unwrap();
-
-    if cmd[1] != b'a' {
-        // Device didn't respond
-        return None;
-    }
+/// Keep track of the number of times the demo has been started in the first memory card slot
+fn count_boots() {
+    let mut buf = [0u8; 4];
+
+    let boots = match memcard::read_slot(0, &mut buf) {
+        Ok(4) => u32::from_le_bytes(buf),
+        Ok(_) | Err(SysError::NoEnt) => 0,
+        Err(e) => {
+            warn!("Can't read the boot counter: {:?}", e);
+            0
+        }
+    } + 1;
 
-    let x = u16::from(cmd[2]) << 8;
-    let x = u16::from(cmd[3]) | x;
-    let y = u16::from(cmd[4]) << 8;
-    let y = u16::from(cmd[5]) | y;
+    info!("Boot #{boots}");
 
-    if x > 0x400 || y > 0x400 {
-        // Out of range -> no touch
-        None
-    } else {
-        Some((x, y))
+    if let Err(e) = memcard::write_slot(0, &boots.to_le_bytes()) {
+        warn!("Can't save the boot counter: {:?}", e);
     }
 }
 
+/// Manhattan distance between two touch points, good enough for zooming
+fn touch_distance(a: &TouchPoint, b: &TouchPoint) -> i32 {
+    i32::from(a.x.abs_diff(b.x)) + i32::from(a.y.abs_diff(b.y))
+}
+
+const MIN_ZOOM: Fp32 = Fp32::from_f32(0.5);
+const MAX_ZOOM: Fp32 = Fp32::from_f32(3.);
+
 /// 12th root of 2
 const SEMITONE_RATIO: Fp32 = Fp32::from_f32(1.0594631);
 
@@ -238,131 +232,37 @@ fn start_audio(fs: Fs) {
         note.iter().fold(0u32, |acc, &b| acc + b as u32)
     );
 
-    let a_step = nrad_step(note) as i32;
-
-    nrad_upload(0, note);
+    let mut bank = SampleBank::whole_ram();
 
-    spu_main_volume(i16::MAX / 2, i16::MAX / 2);
-    spu_voice_volume(0, i16::MAX, i16::MAX);
-    spu_voice_volume(1, i16::MAX, i16::MAX);
+    let note = bank.load_nrad(note).unwrap();
 
-    spu_voice_start_block(0, 0);
-    spu_voice_start_block(1, 0);
+    spu::set_main_volume(i16::MAX / 2, i16::MAX / 2);
 
-    ThreadBuilder::new(*b"TON0")
-        .stack_size(1024)
-        .priority(1)
-        .spawn(move || {
-            let mut pitch = Fp32::ONE;
+    for (name, period_ms) in [(*b"TON0", 500), (*b"TON1", 490)] {
+        let voice = spu::alloc_voice().unwrap();
 
-            loop {
-                pitch *= SEMITONE_RATIO;
+        voice.set_volume(i16::MAX, i16::MAX);
+        voice.set_sample(&note);
 
-                if pitch > 4.into() {
-                    pitch = Fp32::ONE;
-                }
-
-                spu_voice_step(0, (pitch * a_step).round() as u16);
-
-                unsafe {
-                    *SPU_VOICE_ON = 0x1;
-                }
-                sleep(Duration::from_millis(500));
-            }
-        })
-        .unwrap();
+        ThreadBuilder::new(name)
+            .stack_size(1024)
+            .priority(1)
+            .spawn(move || {
+                let mut pitch = Fp32::ONE;
 
-    ThreadBuilder::new(*b"TON1")
-        .stack_size(1024)
-        .priority(1)
-        .spawn(move || {
-            let mut pitch = Fp32::ONE;
+                loop {
+                    pitch *= SEMITONE_RATIO;
 
-            loop {
-                pitch *= SEMITONE_RATIO;
+                    if pitch > 4.into() {
+                        pitch = Fp32::ONE;
+                    }
 
-                if pitch > 4.into() {
-                    pitch = Fp32::ONE;
-                }
-
-                spu_voice_step(1, (pitch * a_step).round() as u16);
+                    voice.set_pitch(&note, pitch);
+                    voice.key_on();
 
-                unsafe {
-                    *SPU_VOICE_ON = 1 << 1;
+                    sleep(Duration::from_millis(period_ms));
                 }
-                sleep(Duration::from_millis(490));
-            }
-        })
-        .unwrap();
-}
-
-fn spu_upload(addr: u16, d: &[u8]) {
-    assert_eq!(addr & 3, 0, "SPU addr misaligned");
-    unsafe {
-        SPU_RAM_ADDR.write_volatile(addr as u32);
-    }
-
-    for b in d.chunks_exact(4) {
-        let w = u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
-        unsafe {
-            SPU_RAM_W.write_volatile(w);
-        }
+            })
+            .unwrap();
     }
 }
-
-fn spu_main_volume(vleft: i16, vright: i16) {
-    let v = ((vleft as u32) << 16) | (vright as u32);
-
-    unsafe {
-        SPU_VOLUME_MAIN.write_volatile(v);
-    }
-}
-
-fn spu_voice_volume(voice: u32, vleft: i16, vright: i16) {
-    let v = ((vleft as u32) << 16) | (vright as u32);
-    let p = (SPU_VOICE_VOLUME + voice * SPU_VOICE_OFF) as *mut u32;
-
-    unsafe {
-        p.write_volatile(v);
-    }
-}
-
-fn spu_voice_step(voice: u32, step: u16) {
-    let p = (SPU_VOICE_STEP + voice * SPU_VOICE_OFF) as *mut u32;
-
-    unsafe {
-        p.write_volatile(step as u32);
-    }
-}
-
-fn spu_voice_start_block(voice: u32, addr: u32) {
-    let p = (SPU_VOICE_START_BLOCK + voice * SPU_VOICE_OFF) as *mut u32;
-
-    unsafe {
-        p.write_volatile(addr);
-    }
-}
-
-fn nrad_step(nrad_buf: &[u8]) -> u16 {
-    let step_lo = u16::from(nrad_buf[6]);
-    let step_hi = u16::from(nrad_buf[7]) << 8;
-
-    step_lo | step_hi
-}
-
-fn nrad_upload(addr: u16, nrad_buf: &[u8]) {
-    spu_upload(addr, &nrad_buf[8..]);
-}
-
-const SPU_BASE: u32 = 0x4002_0000;
-const SPU_VOLUME_MAIN: *mut u32 = SPU_BASE as *mut u32;
-const SPU_VOICE_ON: *mut u32 = (SPU_BASE + 4) as *mut u32;
-const SPU_RAM_ADDR: *mut u32 = (SPU_BASE + 4 * 4) as *mut u32;
-const SPU_RAM_W: *mut u32 = (SPU_BASE + 5 * 4) as *mut u32;
-
-const SPU_VOICE_BASE: u32 = SPU_BASE + 0x100;
-const SPU_VOICE_OFF: u32 = 0x20;
-
-const SPU_VOICE_STEP: u32 = SPU_VOICE_BASE;
-const SPU_VOICE_START_BLOCK: u32 = SPU_VOICE_BASE + 4;
-const SPU_VOICE_VOLUME: u32 = SPU_VOICE_BASE + 2 * 4;
@@ -1,5 +1,7 @@
 use core::fmt::{self, Write};
 use log::{Log, Metadata, Record};
+use nr32_common::memmap;
+use nr32_common::regs::debug;
 
 pub struct DebugConsole;
 
@@ -41,4 +43,4 @@ impl Log for ConsoleLogger {
 
 pub static LOGGER: ConsoleLogger = ConsoleLogger;
 
-const DEBUG_OUT: *mut u8 = 0x4000_0010 as *mut u8;
+const DEBUG_OUT: *mut u8 = memmap::DEBUG.reg(debug::PUTCHAR) as *mut u8;
@@ -1,14 +1,20 @@
 use crate::lock::{Mutex, MutexGuard};
+use crate::scheduler::TaskId;
+use alloc::collections::VecDeque;
 use nr32_common::error::{SysError, SysResult};
 use nr32_common::memmap;
-use nr32_common::syscall::DmaAddr;
+use nr32_common::regs::dma;
+use nr32_common::syscall::{DmaAddr, DmaDescriptor, DmaTarget};
 
 pub struct Dma {
-    /// True if a DMA transaction is running. We only allow one DMA request at a time.
-    in_progress: bool,
+    /// Requests for each DMA channel. The request at the front of the queue is the one currently
+    /// running.
+    queues: [VecDeque<Request>; dma::CHANNEL_COUNT as usize],
 }
 
-static DMA: Mutex<Dma> = Mutex::new(Dma { in_progress: false });
+static DMA: Mutex<Dma> = Mutex::new(Dma {
+    queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+});
 
 pub fn get() -> MutexGuard<'static, Dma> {
     match DMA.try_lock() {
@@ -20,11 +26,51 @@ pub fn get() -> MutexGuard<'static, Dma> {
 }
 
 impl Dma {
-    pub fn done(&mut self) {
-        self.in_progress = false;
+    /// Called on IRQ. `on_done` is called for every task whose transfer has completed or failed.
+    pub fn done(&mut self, mut on_done: impl FnMut(TaskId, SysResult<()>)) {
+        let status = unsafe { DMA_IRQ_STATUS.read_volatile() };
+        let errors = unsafe { DMA_ERROR_STATUS.read_volatile() };
+
+        unsafe {
+            DMA_IRQ_STATUS.write_volatile(status);
+            DMA_ERROR_STATUS.write_volatile(errors);
+        }
+
+        for (ch, queue) in self.queues.iter_mut().enumerate() {
+            let res = if errors & (1 << ch) != 0 {
+                let fault_addr = unsafe { reg(ch as u32, dma::CHANNEL_FAULT_ADDR).read_volatile() };
+
+                warn!("DMA {ch}: transfer aborted, bad address 0x{fault_addr:x}");
+
+                Err(SysError::Fault)
+            } else if status & (1 << ch) != 0 && !is_busy(ch) {
+                Ok(())
+            } else {
+                // The IRQ can also fire in the middle of a linked-list transfer if a descriptor
+                // requests it, we only care about the end of the chain
+                continue;
+            };
+
+            match queue.pop_front() {
+                Some(r) => on_done(r.task, res),
+                None => warn!("DMA IRQ on idle channel {ch}"),
+            }
+
+            if let Some(r) = queue.front() {
+                r.transfer.start(ch);
+            }
+        }
     }
 
-    pub fn start(&mut self, src: usize, dst: usize, len_words: usize) -> SysResult<()> {
+    /// Queue a transfer on the appropriate channel for `task`. The task should wait until `done`
+    /// reports it.
+    pub fn submit(
+        &mut self,
+        task: TaskId,
+        src: usize,
+        dst: usize,
+        len_words: usize,
+    ) -> SysResult<()> {
         if len_words == 0 {
             return Err(SysError::Invalid);
         }
@@ -32,22 +78,162 @@ impl Dma {
         let src = DmaAddr::src_from_raw(src as u32)?;
         let dst = DmaAddr::dst_from_raw(dst as u32)?;
 
-        if self.in_progress {
-            return Err(SysError::Busy);
+        // Catch bad pointers early, the DMA would only abort the transfer once it reaches them
+        let len_bytes = len_words.checked_mul(4).ok_or(SysError::Fault)? as u32;
+
+        if src.target()? == DmaTarget::Memory
+            && !in_range(memmap::RAM, src.raw(), len_bytes)
+            && !in_range(memmap::ROM, src.raw(), len_bytes)
+        {
+            return Err(SysError::Fault);
         }
 
-        unsafe {
-            DMA_SRC.write_volatile(src.raw());
-            DMA_DST.write_volatile(dst.raw());
-            // This also starts the transfer
-            DMA_LEN.write_volatile(len_words as u32);
+        if dst.target()? == DmaTarget::Memory && !in_range(memmap::RAM, dst.raw(), len_bytes) {
+            return Err(SysError::Fault);
+        }
+
+        if src.target()? == DmaTarget::Spu && dst.target()? == DmaTarget::Spu {
+            // There's a single SPU RAM pointer
+            return Err(SysError::Invalid);
         }
 
+        let ch = channel_for(src, dst)?;
+
+        self.queue(
+            ch,
+            Request {
+                task,
+                transfer: Transfer::Block {
+                    src,
+                    dst,
+                    len_words: len_words as u32,
+                },
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Queue a linked-list transfer for `task`. The channel is picked based on the first
+    /// descriptor.
+    pub fn submit_list(&mut self, task: TaskId, desc: usize) -> SysResult<()> {
+        // The descriptors are validated by the DMA as it loads them, we only check the first one
+        // since we have to read it ourselves.
+        let addr = DmaAddr::from_memory(desc)?;
+
+        if addr.raw() == 0 {
+            return Err(SysError::Invalid);
+        }
+
+        let desc_len = size_of::<DmaDescriptor>() as u32;
+        if !in_range(memmap::RAM, addr.raw(), desc_len)
+            && !in_range(memmap::ROM, addr.raw(), desc_len)
+        {
+            return Err(SysError::Fault);
+        }
+
+        let first = unsafe { (desc as *const DmaDescriptor).read_volatile() };
+
+        let src = if first.mode() & dma::MODE_FILL != 0 {
+            // Fill value, the channel only depends on the destination
+            DmaAddr(0)
+        } else {
+            DmaAddr::src_from_raw(first.src)?
+        };
+
+        let ch = channel_for(src, DmaAddr::dst_from_raw(first.dst)?)?;
+
+        self.queue(
+            ch,
+            Request {
+                task,
+                transfer: Transfer::List(addr),
+            },
+        );
+
         Ok(())
     }
+
+    fn queue(&mut self, ch: usize, r: Request) {
+        let queue = &mut self.queues[ch];
+
+        if queue.is_empty() {
+            r.transfer.start(ch);
+        }
+
+        queue.push_back(r);
+    }
+}
+
+struct Request {
+    /// Task waiting for this request
+    task: TaskId,
+    transfer: Transfer,
+}
+
+enum Transfer {
+    Block {
+        src: DmaAddr,
+        dst: DmaAddr,
+        len_words: u32,
+    },
+    /// Address of the first descriptor
+    List(DmaAddr),
+}
+
+impl Transfer {
+    fn start(&self, ch: usize) {
+        let ch = ch as u32;
+
+        unsafe {
+            match *self {
+                Transfer::Block {
+                    src,
+                    dst,
+                    len_words,
+                } => {
+                    // The mode may have been changed by a previous linked-list transfer
+                    reg(ch, dma::CHANNEL_MODE).write_volatile(0);
+                    reg(ch, dma::CHANNEL_SRC).write_volatile(src.raw());
+                    reg(ch, dma::CHANNEL_DST).write_volatile(dst.raw());
+                    // This also starts the transfer
+                    reg(ch, dma::CHANNEL_LEN).write_volatile(len_words);
+                }
+                Transfer::List(desc) => {
+                    // This starts the transfer
+                    reg(ch, dma::CHANNEL_LIST).write_volatile(desc.raw());
+                }
+            }
+        }
+    }
+}
+
+/// Pick the channel for a transfer from `src` to `dst`
+fn channel_for(src: DmaAddr, dst: DmaAddr) -> SysResult<usize> {
+    let ch = match (src.target()?, dst.target()?) {
+        (_, DmaTarget::Gpu) => dma::CHANNEL_GPU,
+        (DmaTarget::Spu, _) | (_, DmaTarget::Spu | DmaTarget::SpuStream) => dma::CHANNEL_SPU,
+        _ => dma::CHANNEL_MEMORY,
+    };
+
+    Ok(ch as usize)
+}
+
+/// Returns true if channel `ch` is still processing a transfer
+fn is_busy(ch: usize) -> bool {
+    let status = unsafe { reg(ch as u32, dma::CHANNEL_STATUS).read_volatile() };
+
+    status & dma::STATUS_BUSY != 0
+}
+
+/// Returns true if `[addr, addr + len)` is entirely contained in `range`
+fn in_range(range: memmap::Range, addr: u32, len: u32) -> bool {
+    addr >= range.base && len <= range.len && addr - range.base <= range.len - len
+}
+
+const fn reg(ch: u32, reg: u32) -> *mut u32 {
+    memmap::DMA.reg(dma::channel_reg(ch, reg)) as *mut u32
 }
 
-const DMA_BASE: usize = memmap::DMA.base as usize;
-const DMA_SRC: *mut u32 = DMA_BASE as *mut u32;
-const DMA_DST: *mut u32 = (DMA_BASE + 4) as *mut u32;
-const DMA_LEN: *mut u32 = (DMA_BASE + 8) as *mut u32;
+const DMA_IRQ_STATUS: *mut u32 = memmap::DMA.reg(dma::IRQ_STATUS) as *mut u32;
+const DMA_ERROR_STATUS: *mut u32 = memmap::DMA.reg(dma::ERROR_STATUS) as *mut u32;
@@ -2,6 +2,7 @@
 use crate::lock::{Mutex, MutexGuard};
 use crate::{SysError, SysResult};
 use nr32_common::memmap;
+use nr32_common::regs::input_dev as regs;
 
 pub struct InputDev {
     /// If a transfer is ongoing, this is the target buffer for the RX data
@@ -26,17 +27,15 @@ impl InputDev {
 
         let mut conf = 0;
 
-        // TX/RX FIFO + port clear
-        conf |= 1;
-        // TX complete IRQ
-        conf |= 1 << 1;
+        conf |= regs::CONF_CLEAR;
+        conf |= regs::CONF_TX_COMPLETE_IRQ;
         // Baud rate divider: (CPU_FREQ / 16) / 5 -> ~280kHz
-        conf |= (5 - 1) << 16;
+        conf |= (5 - 1) << regs::CONF_CLK_DIV_SHIFT;
 
         unsafe {
-            INPUT_DEV_CONF.write_volatile(conf);
+            INPUT_DEV_CONF.write_volatile(conf as usize);
 
-            // Port selection: touchscreen
+            // Port selection
             INPUT_DEV_PORT.write_volatile(port);
 
             for b in data_in_out.iter() {
@@ -79,9 +78,8 @@ pub fn get() -> MutexGuard<'static, InputDev> {
     }
 }
 
-const INPUT_DEV_BASE: usize = memmap::INPUT_DEV.base as usize;
-const INPUT_DEV_CONF: *mut usize = INPUT_DEV_BASE as *mut usize;
-const INPUT_DEV_PORT: *mut u8 = (INPUT_DEV_BASE + 4) as *mut u8;
-const INPUT_DEV_TX_RX: *mut u8 = (INPUT_DEV_BASE + 4 * 2) as *mut u8;
+const INPUT_DEV_CONF: *mut usize = memmap::INPUT_DEV.reg(regs::CONF) as *mut usize;
+const INPUT_DEV_PORT: *mut u8 = memmap::INPUT_DEV.reg(regs::PORT) as *mut u8;
+const INPUT_DEV_TX_RX: *mut u8 = memmap::INPUT_DEV.reg(regs::TX_RX) as *mut u8;
 
-const TX_RX_FIFO_DEPTH: usize = 16;
+const TX_RX_FIFO_DEPTH: usize = regs::FIFO_DEPTH;
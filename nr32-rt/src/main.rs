@@ -21,6 +21,9 @@ mod utils;
 use core::fmt::Write;
 use core::sync::atomic::{AtomicUsize, Ordering::Acquire};
 use nr32_common::error::{SysError, SysResult};
+use nr32_common::memmap;
+use nr32_common::regs::irq::{self, Interrupt};
+use nr32_common::regs::spu;
 use nr32_common::syscall;
 
 // Linker symbols
@@ -70,14 +73,12 @@ pub extern "C" fn rust_trap() {
 fn handle_irqs() {
     let pending = unsafe { IRQ_PENDING.read() };
 
-    // VSYNC
-    if pending & 1 != 0 {
+    if pending & Interrupt::VSync.mask() as usize != 0 {
         let mut sched = scheduler::get();
         sched.wake_up_state(scheduler::TaskState::WaitingForVSync);
     }
 
-    // Input dev
-    if pending & (1 << 1) != 0 {
+    if pending & Interrupt::InputDev.mask() as usize != 0 {
         let mut input_dev = input_dev::get();
         input_dev.xmit_done();
 
@@ -85,13 +86,16 @@ fn handle_irqs() {
         sched.wake_up_state(scheduler::TaskState::WaitingForInputDev);
     }
 
-    // DMA
-    if pending & (1 << 2) != 0 {
+    if pending & (Interrupt::DmaDone.mask() | Interrupt::DmaError.mask()) as usize != 0 {
         let mut dma = dma::get();
-        dma.done();
+        let mut sched = scheduler::get();
+
+        dma.done(|task, res| sched.wake_up_task(task, scheduler::TaskState::WaitingForDma, res));
+    }
 
+    if pending & Interrupt::Spu.mask() as usize != 0 {
         let mut sched = scheduler::get();
-        sched.wake_up_state(scheduler::TaskState::WaitingForDma);
+        sched.wake_up_state(scheduler::TaskState::WaitingForSpuIrq);
     }
 
     // ACK everything
@@ -276,11 +280,32 @@ pub extern "C" fn handle_ecall(
 
             let mut dma = dma::get();
 
-            dma.start(src, dst, len_words).map(|_| {
+            dma.submit(sched.cur_task_id(), src, dst, len_words)
+                .map(|_| {
+                    sched.current_task_set_state(scheduler::TaskState::WaitingForDma);
+                    0
+                })
+        }
+        syscall::SYS_DO_DMA_LIST => {
+            let desc = arg0;
+
+            let mut dma = dma::get();
+
+            dma.submit_list(sched.cur_task_id(), desc).map(|_| {
                 sched.current_task_set_state(scheduler::TaskState::WaitingForDma);
                 0
             })
         }
+        syscall::SYS_WAIT_FOR_SPU_IRQ => {
+            // If an IRQ is already pending we won't get a new edge until it's acknowledged, so we
+            // must not wait
+            let status = unsafe { SPU_IRQ_STATUS.read_volatile() };
+
+            if status == 0 {
+                sched.current_task_set_state(scheduler::TaskState::WaitingForSpuIrq);
+            }
+            Ok(0)
+        }
         _ => Err(SysError::NoSys),
     };
 
@@ -329,12 +354,12 @@ fn system_init() {
         let mut irq_en = 0;
         // Activate VSYNC IRQ (for tasks that block on VSync, we could only enable it when needed but
         // it's a minor load)
-        irq_en |= 1;
-        // Input dev IRQ
-        irq_en |= 1 << 1;
-        // DMA IRQ
-        irq_en |= 1 << 2;
-        IRQ_ENABLED.write_volatile(irq_en);
+        irq_en |= Interrupt::VSync.mask();
+        irq_en |= Interrupt::InputDev.mask();
+        irq_en |= Interrupt::DmaDone.mask();
+        irq_en |= Interrupt::DmaError.mask();
+        irq_en |= Interrupt::Spu.mask();
+        IRQ_ENABLED.write_volatile(irq_en as usize);
         riscv::register::mie::set_mext();
     }
 }
@@ -359,6 +384,8 @@ mod panic_handler {
 const MTIME_HZ: u32 = 44_100 * 16;
 
 /// External Interrupt Controller: IRQ pending register
-const IRQ_PENDING: *mut usize = 0xffff_fff0 as *mut usize;
+const IRQ_PENDING: *mut usize = memmap::IRQ_CONTROLLER.reg(irq::PENDING) as *mut usize;
 /// External Interrupt Controller: IRQ enabled register
-const IRQ_ENABLED: *mut usize = 0xffff_fff4 as *mut usize;
+const IRQ_ENABLED: *mut usize = memmap::IRQ_CONTROLLER.reg(irq::ENABLED) as *mut usize;
+/// SPU: IRQ status register
+const SPU_IRQ_STATUS: *mut usize = memmap::SPU.reg(spu::IRQ_STATUS) as *mut usize;
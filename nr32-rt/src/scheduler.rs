@@ -5,8 +5,10 @@ use crate::{
 };
 use alloc::vec::Vec;
 use core::ptr::NonNull;
+use nr32_common::memmap;
+use nr32_common::regs::sys_timer;
 
-type TaskId = usize;
+pub type TaskId = usize;
 
 pub struct Scheduler {
     tasks: Vec<Task>,
@@ -303,6 +305,31 @@ impl Scheduler {
         }
     }
 
+    /// Wake up task `id` if it's in `state`. `result` is returned by the syscall the task was
+    /// blocked in.
+    #[unsafe(link_section = ".text.fast")]
+    pub fn wake_up_task(&mut self, id: TaskId, state: TaskState, result: SysResult<()>) {
+        let cur_prio = self.tasks[self.cur_task].prio;
+
+        let Some(t) = self.tasks.get_mut(id) else {
+            return;
+        };
+
+        if t.state != state {
+            return;
+        }
+
+        t.state = TaskState::Running;
+
+        if let Err(e) = result {
+            t.set_banked_reg(Reg::A0, e as usize);
+        }
+
+        if t.prio > cur_prio {
+            self.schedule();
+        }
+    }
+
     pub fn dump_task_stats(&mut self) {
         // For more accuracy (and simplicity) we use data from the last task change since that's
         // when the counters were last updated. That means that this code won't work if a single
@@ -437,6 +464,7 @@ pub enum TaskState {
     WaitingForVSync,
     WaitingForInputDev,
     WaitingForDma,
+    WaitingForSpuIrq,
 }
 
 /// Use MTIMECMP to schedule an interrupt
@@ -498,13 +526,13 @@ pub enum TaskType {
 const TASK_SLOT_ROUND_ROBBIN: u32 = MTIME_HZ / 120;
 
 /// MTIME[31:0]
-const MTIME_L: *mut usize = 0xffff_ffe0 as *mut usize;
+const MTIME_L: *mut usize = memmap::SYS_TIMER.reg(sys_timer::MTIME_L) as *mut usize;
 /// MTIME[63:32]
-const MTIME_H: *mut usize = 0xffff_ffe4 as *mut usize;
+const MTIME_H: *mut usize = memmap::SYS_TIMER.reg(sys_timer::MTIME_H) as *mut usize;
 /// MTIMECMP[31:0]
-const MTIMECMP_L: *mut usize = 0xffff_ffe8 as *mut usize;
+const MTIMECMP_L: *mut usize = memmap::SYS_TIMER.reg(sys_timer::MTIMECMP_L) as *mut usize;
 /// MTIMECMP[63:32]
-const MTIMECMP_H: *mut usize = 0xffff_ffec as *mut usize;
+const MTIMECMP_H: *mut usize = memmap::SYS_TIMER.reg(sys_timer::MTIMECMP_H) as *mut usize;
 
 #[unsafe(link_section = ".text.fast")]
 fn mtime_get() -> u64 {
@@ -1,8 +1,10 @@
+use nr32_common::memmap;
+use nr32_common::regs::debug;
 use riscv::asm::wfi;
 
 /// Tell the simulator to stop with the given exit code
 pub fn shutdown(code: u16) -> ! {
-    let v = 0x0d1e0000 | u32::from(code);
+    let v = (debug::SHUTDOWN_MAGIC << 16) | u32::from(code);
 
     loop {
         unsafe {
@@ -13,4 +15,4 @@ pub fn shutdown(code: u16) -> ! {
     }
 }
 
-const SIM_EXIT: *mut u32 = 0x1000_0020 as *mut u32;
+const SIM_EXIT: *mut u32 = memmap::DEBUG.reg(debug::SHUTDOWN) as *mut u32;
@@ -1,7 +1,9 @@
-use crate::syscall::{SysResult, syscall_3};
-pub use nr32_common::syscall::DmaAddr;
-use nr32_common::syscall::SYS_DO_DMA;
+use crate::syscall::{SysError, SysResult, syscall_1, syscall_3};
+pub use nr32_common::syscall::{DmaAddr, DmaDescriptor};
+use nr32_common::syscall::{SYS_DO_DMA, SYS_DO_DMA_LIST};
 
+/// Copy `len_words` from `source` to `target` and wait for the transfer to complete. Returns
+/// `SysError::Fault` if one of the addresses is out of bounds.
 pub fn do_dma(source: DmaAddr, target: DmaAddr, len_words: usize) -> SysResult<()> {
     unsafe {
         syscall_3(
@@ -13,3 +15,30 @@ pub fn do_dma(source: DmaAddr, target: DmaAddr, len_words: usize) -> SysResult<(
     }
     .map(|_| ())
 }
+
+/// Run all the transfers in `descs` in order with a single linked-list DMA transfer. The `next`
+/// fields are overwritten to chain the descriptors. Returns `SysError::Fault` if the DMA aborts
+/// the chain because of a bad address, in which case the transfers may have been partially
+/// carried out.
+pub fn do_dma_list(descs: &mut [DmaDescriptor]) -> SysResult<()> {
+    if descs.is_empty() {
+        return Err(SysError::Invalid);
+    }
+
+    for i in 1..descs.len() {
+        descs[i - 1].next = &descs[i] as *const DmaDescriptor as u32;
+    }
+
+    descs[descs.len() - 1].next = 0;
+
+    // The syscall only returns once the whole chain has been processed, so the descriptors
+    // remain valid for the whole transfer
+    unsafe { syscall_1(SYS_DO_DMA_LIST, descs.as_ptr() as usize) }.map(|_| ())
+}
+
+/// Write `len_words` copies of `val` to `dst`
+pub fn dma_fill(dst: DmaAddr, val: u32, len_words: usize) -> SysResult<()> {
+    let mut desc = [DmaDescriptor::fill(dst, val, len_words).map_err(|_| SysError::Invalid)?];
+
+    do_dma_list(&mut desc)
+}
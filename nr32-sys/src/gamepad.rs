@@ -0,0 +1,56 @@
+//! Gamepad on input port 1. See `regs::input_dev` for the protocol.
+
+use crate::regs::input_dev as regs;
+use crate::syscall::{SysResult, input_device};
+
+pub use regs::{
+    PAD_A, PAD_B, PAD_DOWN, PAD_L, PAD_L3, PAD_LEFT, PAD_R, PAD_R3, PAD_RIGHT, PAD_SELECT,
+    PAD_START, PAD_UP, PAD_X, PAD_Y,
+};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct GamepadState {
+    /// Pressed buttons (`PAD_*`)
+    pub buttons: u16,
+    /// Position of the left and right sticks as `[x, y]`, 0 is the center. None for a digital
+    /// pad.
+    pub sticks: Option<[[i8; 2]; 2]>,
+}
+
+impl GamepadState {
+    /// Returns true if all the buttons in `buttons` are pressed
+    pub fn pressed(&self, buttons: u16) -> bool {
+        self.buttons & buttons == buttons
+    }
+}
+
+/// Read the state of the gamepad. Returns None if no pad is connected.
+pub fn read_gamepad() -> SysResult<Option<GamepadState>> {
+    let cmd: &mut [u8] = &mut [
+        b'P', // Address gamepad
+        b'S', // Read state
+        0,    // Buttons high
+        0,    // Buttons low
+        0,    // Left stick X
+        0,    // Left stick Y
+        0,    // Right stick X
+        0,    // Right stick Y
+    ];
+
+    input_device(regs::PORT_GAMEPAD, cmd)?;
+
+    let buttons = !(u16::from(cmd[2]) << 8 | u16::from(cmd[3]));
+
+    let sticks = match cmd[1] {
+        regs::PAD_ID_DIGITAL => None,
+        regs::PAD_ID_ANALOG => {
+            let axis = |b: u8| (b ^ 0x80) as i8;
+
+            Some([[axis(cmd[4]), axis(cmd[5])], [axis(cmd[6]), axis(cmd[7])]])
+        }
+        // Device didn't respond
+        _ => return Ok(None),
+    };
+
+    Ok(Some(GamepadState { buttons, sticks }))
+}
@@ -1,5 +1,8 @@
+use crate::math::Fp32;
 use crate::syscall::sleep;
 use core::time::Duration;
+use nr32_common::memmap;
+use nr32_common::regs::gpu as regs;
 
 pub fn send_to_gpu(cmd: u32) {
     while !gpu_can_write() {
@@ -11,13 +14,42 @@ pub fn send_to_gpu(cmd: u32) {
     }
 }
 
+/// Start drawing a new frame
+pub fn draw_start() {
+    send_to_gpu(regs::draw_start());
+}
+
+/// Finish drawing the current frame and display it
+pub fn draw_end() {
+    send_to_gpu(regs::draw_end());
+}
+
+/// Enable distance fog: vertices further than `near` from the eye progressively fade to `color`,
+/// which is reached at `far`.
+pub fn set_fog(color: [u8; 3], near: Fp32, far: Fp32) {
+    let [r, g, b] = color;
+
+    send_to_gpu(regs::command(regs::OP_CONFIG, regs::CONFIG_FOG_COLOR, 0));
+    send_to_gpu(u32::from(r) | (u32::from(g) << 8) | (u32::from(b) << 16));
+
+    send_to_gpu(regs::command(regs::OP_CONFIG, regs::CONFIG_FOG_RANGE, 0));
+    send_to_gpu(near.to_s16_16() as u32);
+    send_to_gpu(far.to_s16_16() as u32);
+}
+
+/// Disable distance fog
+pub fn disable_fog() {
+    send_to_gpu(regs::command(regs::OP_CONFIG, regs::CONFIG_FOG_RANGE, 0));
+    send_to_gpu(0);
+    send_to_gpu(0);
+}
+
 pub fn gpu_can_write() -> bool {
-    // Command FIFO full
-    gpu_status() & 1 == 0
+    gpu_status() & regs::STATUS_FIFO_FULL == 0
 }
 
 pub fn gpu_status() -> u32 {
     unsafe { GPU_CMD.read_volatile() }
 }
 
-const GPU_CMD: *mut u32 = 0x4001_0000 as *mut u32;
+const GPU_CMD: *mut u32 = memmap::GPU.reg(regs::COMMAND) as *mut u32;
@@ -5,13 +5,19 @@ extern crate log;
 
 extern crate alloc;
 
+pub use nr32_common::{memmap, regs};
+
 pub mod adler32;
 pub mod allocator;
 pub mod dma;
 pub mod fs;
+pub mod gamepad;
 pub mod gpu;
 pub mod logger;
 pub mod math;
+pub mod memcard;
+pub mod spu;
 pub mod sync;
 pub mod syscall;
 pub mod thread;
+pub mod touchscreen;
@@ -1,6 +1,7 @@
 use super::{Angle, Fp32, Vec3};
 
 use crate::gpu::send_to_gpu;
+use nr32_common::regs::gpu;
 
 /// Hardware matrix index
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
@@ -17,24 +18,21 @@ pub const MAT7: Matrix = Matrix(7);
 
 /// Tell the GPU to use `m` to transform the vertices while drawing
 pub fn set_draw_matrix(m: Matrix) {
-    let m_select = u32::from(m.0 & 7);
-    send_to_gpu((0x03 << 24) | (1 << 16) | m_select);
+    send_to_gpu(gpu::set_draw_matrix(u32::from(m.0)));
 }
 
 /// Reset matrix to identity
 pub fn identity(m: Matrix) {
-    let m_select = u32::from(m.0 & 7) << 12;
-
-    send_to_gpu((0x10 << 24) | m_select);
+    send_to_gpu(gpu::matrix_reset(u32::from(m.0)));
 }
 
 /// Calculate `ma` x `mx` and put the result in `mout`
 pub fn multiply(mout: Matrix, ma: Matrix, mb: Matrix) {
-    let m_select = u32::from(mout.0 & 7) << 12;
-    let ma = u32::from(ma.0 & 7) << 4;
-    let mb = u32::from(mb.0 & 7);
-
-    send_to_gpu((0x10 << 24) | (0x02 << 16) | m_select | ma | mb);
+    send_to_gpu(gpu::matrix_multiply(
+        u32::from(mout.0),
+        u32::from(ma.0),
+        u32::from(mb.0),
+    ));
 }
 
 /// Configure `m` to hold the given camera perspective matrix
@@ -135,10 +133,6 @@ pub fn rotate_z(m: Matrix, angle: Angle) {
 }
 
 pub fn set_matrix_component(m: Matrix, i: u8, j: u8, v: Fp32) {
-    let m_select = u32::from(m.0 & 7) << 12;
-    let i = u32::from(i & 3);
-    let j = u32::from(j & 3);
-
-    send_to_gpu((0x10 << 24) | (0x01 << 16) | m_select | (i << 4) | j);
+    send_to_gpu(gpu::matrix_set(u32::from(m.0), u32::from(i), u32::from(j)));
     send_to_gpu(v.to_s16_16() as u32);
 }
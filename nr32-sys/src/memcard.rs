@@ -0,0 +1,168 @@
+//! Memory card on input port 2. See `regs::input_dev` for the protocol.
+//!
+//! The card is split in `SLOT_COUNT` save slots of `SLOT_LEN` bytes. Each slot starts with a
+//! header:
+//!
+//! ```text
+//!   "NRSV"
+//!   u32 length of the data in bytes
+//!   u32 Adler32 checksum of the data
+//!   u32 reserved (0)
+//! ```
+//!
+//! Followed by the data. The header is written last so that an interrupted save doesn't pass the
+//! checksum.
+
+use crate::adler32::adler32;
+use crate::regs::input_dev as regs;
+use crate::syscall::{SysError, SysResult, input_device};
+
+pub use regs::{MEMCARD_BLOCK_COUNT as BLOCK_COUNT, MEMCARD_BLOCK_LEN as BLOCK_LEN};
+
+/// Number of save slots
+pub const SLOT_COUNT: usize = 8;
+/// Size of a save slot, header included
+pub const SLOT_LEN: usize = regs::MEMCARD_SIZE / SLOT_COUNT;
+/// Maximum length of the data in a save slot
+pub const SLOT_DATA_MAX: usize = SLOT_LEN - HEADER_LEN;
+
+/// Read block `block` of the memory card. Returns `SysError::NoEnt` if no card is connected.
+pub fn read_block(block: usize, data: &mut [u8; BLOCK_LEN]) -> SysResult<()> {
+    let mut cmd = block_command(b'R', block)?;
+
+    input_device(regs::PORT_MEMCARD, &mut cmd)?;
+    check_block_command(&cmd)?;
+
+    data.copy_from_slice(&cmd[4..][..BLOCK_LEN]);
+
+    Ok(())
+}
+
+/// Write block `block` of the memory card. Returns `SysError::NoEnt` if no card is connected.
+pub fn write_block(block: usize, data: &[u8; BLOCK_LEN]) -> SysResult<()> {
+    let mut cmd = block_command(b'W', block)?;
+
+    cmd[4..][..BLOCK_LEN].copy_from_slice(data);
+
+    input_device(regs::PORT_MEMCARD, &mut cmd)?;
+    check_block_command(&cmd)
+}
+
+/// Read save slot `slot` into `data` and return its length. Returns `SysError::NoEnt` if the slot
+/// is empty or no card is connected, `SysError::Invalid` if the save is corrupted and
+/// `SysError::TooLong` if it doesn't fit in `data`.
+pub fn read_slot(slot: usize, data: &mut [u8]) -> SysResult<usize> {
+    let first_block = slot_first_block(slot)?;
+
+    let mut header = [0u8; HEADER_LEN];
+
+    for (i, chunk) in header.as_chunks_mut::<BLOCK_LEN>().0.iter_mut().enumerate() {
+        read_block(first_block + i, chunk)?;
+    }
+
+    if header[0..4] != SLOT_MAGIC {
+        return Err(SysError::NoEnt);
+    }
+
+    let len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+    let csum = u32::from_le_bytes(header[8..12].try_into().unwrap());
+
+    if len > SLOT_DATA_MAX {
+        return Err(SysError::Invalid);
+    }
+
+    let data = data.get_mut(..len).ok_or(SysError::TooLong)?;
+
+    let data_block = first_block + HEADER_LEN / BLOCK_LEN;
+    let mut block = [0u8; BLOCK_LEN];
+
+    for (i, chunk) in data.chunks_mut(BLOCK_LEN).enumerate() {
+        read_block(data_block + i, &mut block)?;
+        chunk.copy_from_slice(&block[..chunk.len()]);
+    }
+
+    if adler32(data) != csum {
+        warn!("Memory card slot {slot}: bad checksum");
+        return Err(SysError::Invalid);
+    }
+
+    Ok(len)
+}
+
+/// Save `data` in slot `slot`, replacing its previous contents
+pub fn write_slot(slot: usize, data: &[u8]) -> SysResult<()> {
+    let first_block = slot_first_block(slot)?;
+
+    if data.len() > SLOT_DATA_MAX {
+        return Err(SysError::TooLong);
+    }
+
+    let data_block = first_block + HEADER_LEN / BLOCK_LEN;
+
+    for (i, chunk) in data.chunks(BLOCK_LEN).enumerate() {
+        let mut block = [0xff; BLOCK_LEN];
+
+        block[..chunk.len()].copy_from_slice(chunk);
+        write_block(data_block + i, &block)?;
+    }
+
+    let mut header = [0u8; HEADER_LEN];
+
+    header[0..4].copy_from_slice(&SLOT_MAGIC);
+    header[4..8].copy_from_slice(&(data.len() as u32).to_le_bytes());
+    header[8..12].copy_from_slice(&adler32(data).to_le_bytes());
+
+    // The first block (with the magic) goes last, until then the slot reads as empty or corrupted
+    for (i, chunk) in header.as_chunks::<BLOCK_LEN>().0.iter().enumerate().rev() {
+        write_block(first_block + i, chunk)?;
+    }
+
+    Ok(())
+}
+
+/// Mark slot `slot` as empty. The data itself isn't erased.
+pub fn erase_slot(slot: usize) -> SysResult<()> {
+    write_block(slot_first_block(slot)?, &[0xff; BLOCK_LEN])
+}
+
+fn slot_first_block(slot: usize) -> SysResult<usize> {
+    if slot >= SLOT_COUNT {
+        return Err(SysError::Invalid);
+    }
+
+    Ok(slot * (SLOT_LEN / BLOCK_LEN))
+}
+
+fn block_command(command: u8, block: usize) -> SysResult<[u8; BLOCK_COMMAND_LEN]> {
+    if block >= BLOCK_COUNT {
+        return Err(SysError::Invalid);
+    }
+
+    let mut cmd = [0u8; BLOCK_COMMAND_LEN];
+
+    cmd[0] = b'C'; // Address memory card
+    cmd[1] = command;
+    cmd[2] = (block >> 8) as u8;
+    cmd[3] = block as u8;
+
+    Ok(cmd)
+}
+
+fn check_block_command(cmd: &[u8; BLOCK_COMMAND_LEN]) -> SysResult<()> {
+    if cmd[1] != b'c' {
+        // Device didn't respond
+        return Err(SysError::NoEnt);
+    }
+
+    if cmd[BLOCK_COMMAND_LEN - 1] != b'k' {
+        return Err(SysError::Invalid);
+    }
+
+    Ok(())
+}
+
+/// Address, command, block number, data and status
+const BLOCK_COMMAND_LEN: usize = 4 + BLOCK_LEN + 1;
+
+const SLOT_MAGIC: [u8; 4] = *b"NRSV";
+const HEADER_LEN: usize = 16;
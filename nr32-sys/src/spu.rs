@@ -0,0 +1,432 @@
+//! High-level SPU driver
+//!
+//! Voices are reserved with [`alloc_voice`] and released when the returned [`Voice`] is dropped,
+//! so that independent threads (music, sound effects...) never step on each other's voices. Each
+//! voice only touches its own registers and the key on/off registers only act on the bits that
+//! are set, so all the functions here can be called concurrently.
+
+use crate::dma::{DmaAddr, do_dma};
+use crate::math::Fp32;
+use crate::sync::Semaphore;
+use crate::syscall::{SysError, SysResult};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use nr32_common::memmap;
+use nr32_common::regs::spu as regs;
+
+pub use regs::Adsr;
+
+pub mod sequencer;
+
+/// Size of an ADPCM block in bytes. Samples are always stored on block boundaries in SPU RAM.
+pub const BLOCK_SIZE: u32 = 16;
+
+/// Step value playing a sample at 44.1kHz
+pub const STEP_44100: u16 = 1 << 12;
+/// Maximum step value (a bit less than 4 times the base rate)
+pub const STEP_MAX: u16 = 0x3fff;
+
+/// Reserve a free voice. Returns None if all the voices are in use.
+pub fn alloc_voice() -> Option<Voice> {
+    let mut allocated = ALLOCATED_VOICES.load(Ordering::Relaxed);
+
+    loop {
+        let free = !allocated & ALL_VOICES;
+
+        if free == 0 {
+            return None;
+        }
+
+        let index = free.trailing_zeros();
+
+        match ALLOCATED_VOICES.compare_exchange_weak(
+            allocated,
+            allocated | (1 << index),
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => return Some(Voice { index }),
+            // We got raced, try again
+            Err(a) => allocated = a,
+        }
+    }
+}
+
+/// Start all the voices in `mask` simultaneously
+pub fn key_on_mask(mask: u32) {
+    write_reg(regs::VOICE_ON, mask & ALL_VOICES);
+}
+
+/// Release all the voices in `mask` simultaneously
+pub fn key_off_mask(mask: u32) {
+    write_reg(regs::VOICE_OFF, mask & ALL_VOICES);
+}
+
+/// Returns the bitmask of the voices currently playing
+pub fn active_voices() -> u32 {
+    read_reg(regs::ACTIVE_VOICES)
+}
+
+pub fn set_main_volume(left: i16, right: i16) {
+    write_reg(regs::MAIN_VOLUME, pack_volume(left, right));
+}
+
+/// Copy `data` to SPU RAM at byte offset `addr`. `addr` must be word-aligned.
+///
+/// The transfer uses the DMA if `data` is word-aligned, otherwise it's copied one word at a time
+/// by the CPU.
+pub fn upload(addr: u32, data: &[u8]) -> SysResult<()> {
+    if addr & 3 != 0 || addr as usize + data.len() > regs::RAM_SIZE as usize {
+        return Err(SysError::Invalid);
+    }
+
+    if data.is_empty() {
+        return Ok(());
+    }
+
+    // The RAM transfer pointer is shared, only one upload can run at a time
+    UPLOAD_LOCK.wait();
+
+    write_reg(regs::RAM_ADDR, addr);
+
+    let res = if data.as_ptr() as usize & 3 == 0 {
+        let words = data.len() / 4;
+        let tail = &data[words * 4..];
+
+        let res = if words > 0 {
+            dma_upload(data, words)
+        } else {
+            Ok(())
+        };
+
+        res.map(|_| cpu_upload(tail))
+    } else {
+        cpu_upload(data);
+        Ok(())
+    };
+
+    UPLOAD_LOCK.post();
+
+    res
+}
+
+fn dma_upload(data: &[u8], words: usize) -> SysResult<()> {
+    let src = DmaAddr::from_memory(data.as_ptr() as usize).map_err(|_| SysError::Invalid)?;
+
+    do_dma(src, DmaAddr::SPU, words)
+}
+
+fn cpu_upload(data: &[u8]) {
+    for chunk in data.chunks(4) {
+        let mut w = [0; 4];
+
+        w[..chunk.len()].copy_from_slice(chunk);
+
+        write_reg(regs::RAM_DATA, u32::from_le_bytes(w));
+    }
+}
+
+/// A voice reserved with [`alloc_voice`]. The voice is released and freed when dropped.
+pub struct Voice {
+    index: u32,
+}
+
+impl Voice {
+    /// Index of the voice in the SPU, between 0 and 23
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// Bit of this voice in the per-voice bitmask registers
+    pub fn mask(&self) -> u32 {
+        1 << self.index
+    }
+
+    pub fn set_volume(&self, left: i16, right: i16) {
+        self.write(regs::VOICE_VOLUME, pack_volume(left, right));
+    }
+
+    /// Set the playback step: `STEP_44100` plays the sample at 44.1kHz
+    pub fn set_step(&self, step: u16) {
+        self.write(regs::VOICE_STEP, u32::from(step.min(STEP_MAX)));
+    }
+
+    /// Set the ADSR envelope, takes effect on the next key on
+    pub fn set_adsr(&self, adsr: Adsr) {
+        self.write(regs::VOICE_ADSR, adsr.raw());
+    }
+
+    /// Select `sample` and set the step to play it at its native rate. Takes effect on the next
+    /// key on.
+    pub fn set_sample(&self, sample: &Sample) {
+        self.set_start_block(sample.start_block());
+        self.set_step(sample.step());
+    }
+
+    /// Set the start address of the sample in SPU RAM, in 16-byte blocks. Takes effect on the next
+    /// key on.
+    pub fn set_start_block(&self, block: u32) {
+        self.write(regs::VOICE_START_BLOCK, block);
+    }
+
+    /// Set the address the voice jumps to when it reaches an end block with the loop flag set, in
+    /// 16-byte blocks. Overwritten by the SPU when the voice reaches a block with the loop start
+    /// flag set.
+    pub fn set_loop_block(&self, block: u32) {
+        self.write(regs::VOICE_LOOP_BLOCK, block);
+    }
+
+    /// Play `sample` with the pitch multiplied by `ratio` (e.g. 2 to play one octave higher)
+    pub fn set_pitch(&self, sample: &Sample, ratio: Fp32) {
+        self.set_step(sample.step_at(ratio));
+    }
+
+    /// Start playing the voice from the start of its sample
+    pub fn key_on(&self) {
+        key_on_mask(self.mask());
+    }
+
+    /// Put the voice in release, it will stop once the envelope reaches 0
+    pub fn key_off(&self) {
+        key_off_mask(self.mask());
+    }
+
+    /// True if the voice is still playing (including release)
+    pub fn is_active(&self) -> bool {
+        active_voices() & self.mask() != 0
+    }
+
+    fn write(&self, reg: u32, val: u32) {
+        write_reg(regs::voice_reg(self.index, reg), val);
+    }
+}
+
+impl Drop for Voice {
+    fn drop(&mut self) {
+        self.key_off();
+
+        ALLOCATED_VOICES.fetch_and(!self.mask(), Ordering::Release);
+    }
+}
+
+/// Parsed NRAD file, as generated by `multitool audio`
+pub struct Nrad<'a> {
+    step: u16,
+    data: &'a [u8],
+}
+
+impl<'a> Nrad<'a> {
+    pub fn parse(nrad: &'a [u8]) -> SysResult<Nrad<'a>> {
+        if nrad.len() < NRAD_HEADER_LEN || &nrad[0..4] != b"NRAD" {
+            return Err(SysError::Invalid);
+        }
+
+        let step = u16::from_le_bytes([nrad[6], nrad[7]]);
+        let data = &nrad[NRAD_HEADER_LEN..];
+
+        if !data.len().is_multiple_of(BLOCK_SIZE as usize) {
+            return Err(SysError::Invalid);
+        }
+
+        Ok(Nrad { step, data })
+    }
+
+    /// Step value to play the sample at its native rate
+    pub fn step(&self) -> u16 {
+        self.step
+    }
+
+    /// Raw ADPCM blocks
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+}
+
+/// A sample loaded in SPU RAM
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Sample {
+    /// Location in SPU RAM, in blocks
+    start_block: u32,
+    /// Length in blocks
+    len_blocks: u32,
+    /// Step value to play the sample at its native rate
+    step: u16,
+}
+
+impl Sample {
+    pub fn start_block(&self) -> u32 {
+        self.start_block
+    }
+
+    /// Byte offset of the sample in SPU RAM
+    pub fn addr(&self) -> u32 {
+        self.start_block * BLOCK_SIZE
+    }
+
+    /// Length of the sample in bytes
+    pub fn len(&self) -> u32 {
+        self.len_blocks * BLOCK_SIZE
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len_blocks == 0
+    }
+
+    pub fn step(&self) -> u16 {
+        self.step
+    }
+
+    /// Step value to play the sample with its pitch multiplied by `ratio`
+    pub fn step_at(&self, ratio: Fp32) -> u16 {
+        (ratio * i32::from(self.step))
+            .round()
+            .clamp(0, i32::from(STEP_MAX)) as u16
+    }
+}
+
+/// Manages the allocation of samples in a region of SPU RAM
+pub struct SampleBank {
+    /// First block of the region
+    start_block: u32,
+    /// Block following the end of the region
+    end_block: u32,
+    /// Allocated (start_block, len_blocks), sorted by address
+    allocs: Vec<(u32, u32)>,
+}
+
+impl SampleBank {
+    /// Create a bank managing `len` bytes of SPU RAM starting at `base`. Both are rounded to
+    /// blocks, the bank is empty if that leaves no complete block inside the SPU RAM.
+    pub fn new(base: u32, len: u32) -> SampleBank {
+        let end_block = base.saturating_add(len).min(regs::RAM_SIZE) / BLOCK_SIZE;
+
+        SampleBank {
+            start_block: base.div_ceil(BLOCK_SIZE).min(end_block),
+            end_block,
+            allocs: Vec::new(),
+        }
+    }
+
+    /// Bank managing the entire SPU RAM
+    pub fn whole_ram() -> SampleBank {
+        SampleBank::new(0, regs::RAM_SIZE)
+    }
+
+    /// Upload an NRAD file and return the resulting sample
+    pub fn load_nrad(&mut self, nrad: &[u8]) -> SysResult<Sample> {
+        let nrad = Nrad::parse(nrad)?;
+
+        self.load_adpcm(nrad.data(), nrad.step())
+    }
+
+    /// Upload raw ADPCM blocks to be played with `step`
+    pub fn load_adpcm(&mut self, adpcm: &[u8], step: u16) -> SysResult<Sample> {
+        let len_blocks = (adpcm.len() as u32).div_ceil(BLOCK_SIZE);
+
+        let start_block = self.alloc(len_blocks)?;
+
+        let sample = Sample {
+            start_block,
+            len_blocks,
+            step,
+        };
+
+        if let Err(e) = upload(sample.addr(), adpcm) {
+            self.free(&sample);
+            return Err(e);
+        }
+
+        Ok(sample)
+    }
+
+    /// Reserve `len` bytes without uploading anything, for data managed by the caller (e.g. a
+    /// score loading its own samples)
+    pub fn reserve(&mut self, len: u32) -> SysResult<Sample> {
+        let len_blocks = len.div_ceil(BLOCK_SIZE);
+
+        let start_block = self.alloc(len_blocks)?;
+
+        Ok(Sample {
+            start_block,
+            len_blocks,
+            step: STEP_44100,
+        })
+    }
+
+    /// Free the RAM used by `sample`. The caller must make sure that no voice is still playing it.
+    pub fn free(&mut self, sample: &Sample) {
+        self.allocs
+            .retain(|&(start, len)| (start, len) != (sample.start_block, sample.len_blocks));
+    }
+
+    /// Free all the samples in the bank
+    pub fn clear(&mut self) {
+        self.allocs.clear();
+    }
+
+    /// Total free space in bytes (which may be fragmented)
+    pub fn free_space(&self) -> u32 {
+        let used: u32 = self.allocs.iter().map(|&(_, len)| len).sum();
+
+        (self.end_block - self.start_block)
+            .checked_sub(used)
+            .map_or(0, |free| free * BLOCK_SIZE)
+    }
+
+    /// First-fit allocation of `len_blocks`
+    fn alloc(&mut self, len_blocks: u32) -> SysResult<u32> {
+        if len_blocks == 0 {
+            return Err(SysError::Invalid);
+        }
+
+        let mut pos = self.start_block;
+
+        for (i, &(start, len)) in self.allocs.iter().enumerate() {
+            if start - pos >= len_blocks {
+                self.allocs.insert(i, (pos, len_blocks));
+                return Ok(pos);
+            }
+
+            pos = start + len;
+        }
+
+        if self
+            .end_block
+            .checked_sub(pos)
+            .is_some_and(|free| free >= len_blocks)
+        {
+            self.allocs.push((pos, len_blocks));
+            Ok(pos)
+        } else {
+            Err(SysError::NoMem)
+        }
+    }
+}
+
+fn pack_volume(left: i16, right: i16) -> u32 {
+    ((left as u16 as u32) << 16) | (right as u16 as u32)
+}
+
+fn write_reg(reg: u32, val: u32) {
+    let p = memmap::SPU.reg(reg) as *mut u32;
+
+    unsafe {
+        p.write_volatile(val);
+    }
+}
+
+fn read_reg(reg: u32) -> u32 {
+    let p = memmap::SPU.reg(reg) as *mut u32;
+
+    unsafe { p.read_volatile() }
+}
+
+const ALL_VOICES: u32 = (1 << regs::VOICE_COUNT) - 1;
+
+/// Bitmask of the voices currently reserved
+static ALLOCATED_VOICES: AtomicU32 = AtomicU32::new(0);
+
+/// Held while uploading data to SPU RAM
+static UPLOAD_LOCK: Semaphore = Semaphore::new(1);
+
+const NRAD_HEADER_LEN: usize = 8;
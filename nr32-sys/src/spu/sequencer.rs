@@ -0,0 +1,607 @@
+//! NRAS score sequencer
+//!
+//! NRAS files (generated by `multitool score`) are a stream of SPU commands separated by delays in
+//! 1/4410th of a second. The score is read directly from the cartridge and played by a dedicated
+//! thread which can be controlled from any other thread through the [`Sequencer`] handle.
+//!
+//! Like on the PSX, SPU RAM addresses in NRAS files are in 8-byte units.
+//!
+//! The voice numbers and SPU RAM addresses in the score are relocated: the voices are allocated
+//! with `alloc_voice` and the samples are loaded in a region reserved in a `SampleBank`, so that a
+//! score can play alongside sound effects.
+
+use super::{Adsr, BLOCK_SIZE, Sample, SampleBank, Voice, alloc_voice, key_off_mask, key_on_mask};
+use crate::fs::Fs;
+use crate::math::Fp32;
+use crate::syscall::{MTIME_HZ, SysError, SysResult, futex_wait, futex_wake};
+use crate::thread::ThreadBuilder;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicUsize, Ordering};
+use core::time::Duration;
+use nr32_common::memmap;
+use nr32_common::regs::{spu as regs, sys_timer};
+
+/// A score ready to be played
+pub struct Song {
+    nras: &'static [u8],
+    /// Voices used by the score
+    voice_mask: u32,
+    /// SPU RAM reserved for the samples of the score
+    region: Option<Sample>,
+    /// Added to the byte addresses in the score to relocate them to `region`
+    addr_offset: u32,
+    /// Position of the first op
+    start: usize,
+    /// Position to restart from when looping. The samples loaded at the start of the score don't
+    /// need to be uploaded again if nothing else is loaded later.
+    loop_start: usize,
+}
+
+impl Song {
+    /// Load the score at `path` in `fs`, see [`Song::new`]
+    pub fn from_fs(fs: &Fs, path: &[&[u8]], bank: &mut SampleBank) -> SysResult<Song> {
+        let nras = fs.contents(path)?;
+
+        Song::new(nras, bank)
+    }
+
+    /// Validate `nras` and reserve the SPU RAM needed by its samples in `bank`. The samples are
+    /// only uploaded when the score starts playing.
+    pub fn new(nras: &'static [u8], bank: &mut SampleBank) -> SysResult<Song> {
+        if nras.len() < NRAS_HEADER_LEN || &nras[0..4] != b"NRAS" {
+            return Err(SysError::Invalid);
+        }
+
+        let start = NRAS_HEADER_LEN;
+
+        let mut voice_mask = 0;
+        // Range of SPU RAM loaded by the score, in bytes
+        let mut loaded: Option<(u32, u32)> = None;
+        // Position of the first op that isn't a RAM load
+        let mut preload_end = None;
+        let mut late_loads = false;
+
+        let mut pos = start;
+        loop {
+            let op_pos = pos;
+
+            let Some(op) = Op::parse(nras, &mut pos)? else {
+                break;
+            };
+
+            match op {
+                Op::RamLoad { addr, data } => {
+                    let end = addr + data.len() as u32;
+
+                    loaded = match loaded {
+                        Some((s, e)) => Some((s.min(addr), e.max(end))),
+                        None => Some((addr, end)),
+                    };
+
+                    if preload_end.is_some() {
+                        late_loads = true;
+                    }
+                }
+                op => {
+                    if preload_end.is_none() {
+                        preload_end = Some(op_pos);
+                    }
+
+                    voice_mask |= op.voice_mask();
+                }
+            }
+        }
+
+        let (region, addr_offset) = match loaded {
+            Some((s, e)) => {
+                // The voices can only start on block boundaries so the relocation must preserve
+                // the alignment
+                let s = s & !(BLOCK_SIZE - 1);
+
+                let region = bank.reserve(e - s)?;
+                let offset = region.addr().wrapping_sub(s);
+
+                (Some(region), offset)
+            }
+            None => (None, 0),
+        };
+
+        let loop_start = match preload_end {
+            Some(p) if !late_loads => p,
+            _ => start,
+        };
+
+        Ok(Song {
+            nras,
+            voice_mask,
+            region,
+            addr_offset,
+            start,
+            loop_start,
+        })
+    }
+
+    /// SPU RAM reserved for the samples. It can be freed from the bank once the song is no longer
+    /// playing.
+    pub fn region(&self) -> Option<Sample> {
+        self.region
+    }
+
+    /// Number of voices used by the score
+    pub fn voice_count(&self) -> u32 {
+        self.voice_mask.count_ones()
+    }
+
+    /// Relocate the byte address `addr`
+    fn relocate(&self, addr: u32) -> u32 {
+        addr.wrapping_add(self.addr_offset) % regs::RAM_SIZE
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PlayState {
+    Stopped = 0,
+    Playing = 1,
+    Paused = 2,
+}
+
+/// Handle to a sequencer thread. Dropping the handle stops the playback, frees the voices and
+/// terminates the thread.
+pub struct Sequencer {
+    shared: Arc<Shared>,
+}
+
+impl Sequencer {
+    /// Allocate the voices needed by `song` and spawn the sequencer thread. The song starts
+    /// stopped. Returns `SysError::Busy` if there aren't enough free voices.
+    ///
+    /// The thread should run at a high priority to keep the timing accurate.
+    pub fn spawn(song: Song, priority: i32) -> SysResult<Sequencer> {
+        let mut voices: [Option<Voice>; 24] = Default::default();
+
+        for (i, v) in voices.iter_mut().enumerate() {
+            if song.voice_mask & (1 << i) != 0 {
+                *v = Some(alloc_voice().ok_or(SysError::Busy)?);
+            }
+        }
+
+        let shared = Arc::new(Shared {
+            commands: AtomicUsize::new(0),
+            state: AtomicU32::new(PlayState::Stopped as u32),
+            rewind: AtomicBool::new(false),
+            quit: AtomicBool::new(false),
+            looping: AtomicBool::new(false),
+            tempo: AtomicI32::new(Fp32::ONE.to_s16_16()),
+            volume: AtomicU32::new(i16::MAX as u32),
+        });
+
+        let mut player = Player {
+            pos: song.start,
+            song,
+            voices,
+            shared: shared.clone(),
+            volumes: [(0, 0); 24],
+            volume: i16::MAX,
+            deadline: 0,
+            remaining: 0,
+        };
+
+        ThreadBuilder::new(*b"NRAS")
+            .stack_size(2048)
+            .priority(priority)
+            .spawn(move || player.run())?;
+
+        Ok(Sequencer { shared })
+    }
+
+    /// Start playing the song, or resume it if it was paused
+    pub fn play(&self) {
+        self.shared.set_state(PlayState::Playing);
+    }
+
+    /// Pause the song. The voices are released, `play` resumes from the current position.
+    pub fn pause(&self) {
+        let _ = self.shared.state.compare_exchange(
+            PlayState::Playing as u32,
+            PlayState::Paused as u32,
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        );
+        self.shared.notify();
+    }
+
+    /// Stop the song, `play` restarts from the beginning
+    pub fn stop(&self) {
+        self.shared.rewind.store(true, Ordering::Release);
+        self.shared.set_state(PlayState::Stopped);
+    }
+
+    pub fn state(&self) -> PlayState {
+        self.shared.state()
+    }
+
+    /// If `looping` is true the song restarts when it reaches the end, otherwise it stops
+    pub fn set_looping(&self, looping: bool) {
+        self.shared.looping.store(looping, Ordering::Release);
+    }
+
+    /// Playback speed factor: 2 plays the song twice as fast. Takes effect from the next delay.
+    /// The pitch isn't affected.
+    pub fn set_tempo(&self, tempo: Fp32) {
+        let tempo = tempo.to_s16_16().max(MIN_TEMPO);
+
+        self.shared.tempo.store(tempo, Ordering::Release);
+    }
+
+    /// Song volume, applied on top of the voice volumes set by the score. `i16::MAX` is full
+    /// volume.
+    pub fn set_volume(&self, volume: i16) {
+        self.shared
+            .volume
+            .store(volume.max(0) as u32, Ordering::Release);
+        self.shared.notify();
+    }
+}
+
+impl Drop for Sequencer {
+    fn drop(&mut self) {
+        self.shared.quit.store(true, Ordering::Release);
+        self.shared.notify();
+    }
+}
+
+/// State shared between the `Sequencer` handle and the thread
+struct Shared {
+    /// Incremented every time the handle changes something, the thread waits on it
+    commands: AtomicUsize,
+    /// Requested `PlayState`
+    state: AtomicU32,
+    /// Set to have the thread go back to the start of the song
+    rewind: AtomicBool,
+    /// Set to terminate the thread
+    quit: AtomicBool,
+    looping: AtomicBool,
+    /// Tempo as s16.16
+    tempo: AtomicI32,
+    volume: AtomicU32,
+}
+
+impl Shared {
+    fn state(&self) -> PlayState {
+        match self.state.load(Ordering::Acquire) {
+            1 => PlayState::Playing,
+            2 => PlayState::Paused,
+            _ => PlayState::Stopped,
+        }
+    }
+
+    fn set_state(&self, state: PlayState) {
+        self.state.store(state as u32, Ordering::Release);
+        self.notify();
+    }
+
+    fn notify(&self) {
+        self.commands.fetch_add(1, Ordering::AcqRel);
+        let _ = futex_wake(&self.commands, 1);
+    }
+}
+
+/// Sequencer thread state
+struct Player {
+    song: Song,
+    /// Current position in the score
+    pos: usize,
+    /// Voices allocated for each voice number of the score
+    voices: [Option<Voice>; 24],
+    shared: Arc<Shared>,
+    /// Last volume set by the score for each voice
+    volumes: [(u8, u8); 24],
+    /// Song volume currently applied
+    volume: i16,
+    /// MTIME at which the current delay ends
+    deadline: u64,
+    /// MTIME ticks left in the current delay when the song was paused
+    remaining: u64,
+}
+
+impl Player {
+    fn run(&mut self) {
+        let mut silenced = true;
+
+        loop {
+            let commands = self.shared.commands.load(Ordering::Acquire);
+
+            if self.shared.quit.load(Ordering::Acquire) {
+                self.silence();
+                return;
+            }
+
+            if self.shared.rewind.swap(false, Ordering::AcqRel) {
+                self.silence();
+                self.pos = self.song.start;
+                self.deadline = 0;
+                self.remaining = 0;
+            }
+
+            let volume = self.shared.volume.load(Ordering::Acquire) as i16;
+            if volume != self.volume {
+                self.volume = volume;
+                for v in 0..24 {
+                    self.apply_volume(v);
+                }
+            }
+
+            if self.shared.state() != PlayState::Playing {
+                if !silenced {
+                    self.silence();
+                    silenced = true;
+                    self.remaining = self.deadline.saturating_sub(mtime());
+                }
+
+                let _ = futex_wait(&self.shared.commands, commands, None);
+                continue;
+            }
+
+            if silenced {
+                silenced = false;
+                self.deadline = mtime() + self.remaining;
+                self.remaining = 0;
+            }
+
+            let now = mtime();
+            if now < self.deadline {
+                let timeout = ticks_to_duration(self.deadline - now);
+
+                let _ = futex_wait(&self.shared.commands, commands, Some(timeout));
+                continue;
+            }
+
+            if let Err(e) = self.step() {
+                error!("NRAS playback failed: {:?}", e);
+                self.shared.rewind.store(true, Ordering::Release);
+                self.shared.state.store(PlayState::Stopped as u32, Ordering::Release);
+            }
+        }
+    }
+
+    /// Run the ops until the next delay or the end of the score
+    fn step(&mut self) -> SysResult<()> {
+        loop {
+            let Some(op) = Op::parse(self.song.nras, &mut self.pos)? else {
+                // End of score
+                if self.shared.looping.load(Ordering::Acquire) {
+                    self.pos = self.song.loop_start;
+                } else {
+                    self.shared.rewind.store(true, Ordering::Release);
+                    let _ = self.shared.state.compare_exchange(
+                        PlayState::Playing as u32,
+                        PlayState::Stopped as u32,
+                        Ordering::AcqRel,
+                        Ordering::Relaxed,
+                    );
+                }
+
+                return Ok(());
+            };
+
+            match op {
+                Op::Delay(delay) => {
+                    let tempo = self.shared.tempo.load(Ordering::Acquire) as u64;
+                    let ticks = ((u64::from(delay) * TICKS_PER_DELAY) << 16) / tempo;
+
+                    // Computing the deadline from the previous one instead of the current time
+                    // prevents the timing errors from accumulating, but we don't try to catch up
+                    // if we're more than a second late
+                    let late_limit = mtime().saturating_sub(u64::from(MTIME_HZ));
+                    self.deadline = self.deadline.max(late_limit) + ticks;
+
+                    return Ok(());
+                }
+                Op::Step { voice, step } => self.voice(voice).set_step(step),
+                Op::Volume { voice, l, r } => {
+                    self.volumes[usize::from(voice)] = (l, r);
+                    self.apply_volume(usize::from(voice));
+                }
+                Op::Adsr { voice, envelope } => {
+                    self.voice(voice).set_adsr(Adsr::from_raw(envelope));
+                }
+                Op::Sample { voice, addr } => {
+                    let block = self.song.relocate(addr) / BLOCK_SIZE;
+                    self.voice(voice).set_start_block(block);
+                }
+                Op::Loop { voice, addr } => {
+                    let block = self.song.relocate(addr) / BLOCK_SIZE;
+                    self.voice(voice).set_loop_block(block);
+                }
+                Op::Release { mask } => key_off_mask(self.map_mask(mask)),
+                Op::Trigger { mask } => key_on_mask(self.map_mask(mask)),
+                Op::RamLoad { addr, data } => {
+                    super::upload(self.song.relocate(addr), data)?;
+                }
+            }
+        }
+    }
+
+    fn voice(&self, voice: u8) -> &Voice {
+        // `Sequencer::spawn` allocated all the voices used by the score
+        self.voices[usize::from(voice)].as_ref().unwrap()
+    }
+
+    /// Convert a bitmask of score voices to SPU voices
+    fn map_mask(&self, mask: u32) -> u32 {
+        self.voices
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| mask & (1 << i) != 0)
+            .filter_map(|(_, v)| v.as_ref())
+            .fold(0, |m, v| m | v.mask())
+    }
+
+    fn apply_volume(&self, voice: usize) {
+        let Some(v) = &self.voices[voice] else {
+            return;
+        };
+
+        let (l, r) = self.volumes[voice];
+        let scale = |c: u8| ((i32::from(c) * i32::from(self.volume)) / 0xff) as i16;
+
+        v.set_volume(scale(l), scale(r));
+    }
+
+    /// Release all the voices of the song
+    fn silence(&self) {
+        key_off_mask(self.map_mask(!0));
+    }
+}
+
+/// A decoded NRAS op
+enum Op {
+    /// Wait in 1/4410th of a second
+    Delay(u16),
+    Step { voice: u8, step: u16 },
+    Volume { voice: u8, l: u8, r: u8 },
+    Adsr { voice: u8, envelope: u32 },
+    /// Set the voice's start address, in bytes
+    Sample { voice: u8, addr: u32 },
+    /// Set the voice's loop address, in bytes
+    Loop { voice: u8, addr: u32 },
+    Release { mask: u32 },
+    Trigger { mask: u32 },
+    /// Upload `data` to SPU RAM at byte address `addr`
+    RamLoad { addr: u32, data: &'static [u8] },
+}
+
+impl Op {
+    /// Decode the op at `*pos` and move `pos` past it. Returns None at the end of the score.
+    fn parse(nras: &'static [u8], pos: &mut usize) -> SysResult<Option<Op>> {
+        let Some(&b) = nras.get(*pos) else {
+            return Ok(None);
+        };
+
+        let mut cur = *pos + 1;
+
+        let mut args = |n: usize| -> SysResult<&'static [u8]> {
+            let a = nras.get(cur..cur + n).ok_or(SysError::Invalid)?;
+
+            cur += n;
+
+            Ok(a)
+        };
+
+        let voice = b & 0x1f;
+        if b >> 5 != 0 && b >> 5 != 7 && voice >= 24 {
+            return Err(SysError::Invalid);
+        }
+
+        let u16_at = |a: &[u8], i: usize| u16::from_le_bytes([a[i], a[i + 1]]);
+        let u24_at = |a: &[u8]| u32::from_le_bytes([a[0], a[1], a[2], 0]);
+
+        let op = match b >> 5 {
+            0 => {
+                let a = args(1)?;
+
+                Op::Delay(u16::from_le_bytes([a[0], b]))
+            }
+            1 => Op::Step {
+                voice,
+                step: u16_at(args(2)?, 0),
+            },
+            2 => {
+                let a = args(2)?;
+
+                Op::Volume {
+                    voice,
+                    l: a[0],
+                    r: a[1],
+                }
+            }
+            3 => {
+                let a = args(4)?;
+
+                Op::Adsr {
+                    voice,
+                    envelope: u32::from_le_bytes([a[0], a[1], a[2], a[3]]),
+                }
+            }
+            4 => Op::Sample {
+                voice,
+                addr: u32::from(u16_at(args(2)?, 0)) * NRAS_ADDR_UNIT,
+            },
+            5 => Op::Loop {
+                voice,
+                addr: u32::from(u16_at(args(2)?, 0)) * NRAS_ADDR_UNIT,
+            },
+            7 => match voice {
+                0 => Op::Release {
+                    mask: u24_at(args(3)?),
+                },
+                1 => Op::Trigger {
+                    mask: u24_at(args(3)?),
+                },
+                2 => {
+                    // Start and length - 1
+                    let a = args(4)?;
+                    let addr = u32::from(u16_at(a, 0)) * NRAS_ADDR_UNIT;
+                    let len = (usize::from(u16_at(a, 2)) + 1) * NRAS_ADDR_UNIT as usize;
+
+                    Op::RamLoad {
+                        addr,
+                        data: args(len)?,
+                    }
+                }
+                _ => return Err(SysError::Invalid),
+            },
+            _ => return Err(SysError::Invalid),
+        };
+
+        *pos = cur;
+
+        Ok(Some(op))
+    }
+
+    /// Score voices used by this op
+    fn voice_mask(&self) -> u32 {
+        match *self {
+            Op::Delay(_) | Op::RamLoad { .. } => 0,
+            Op::Step { voice, .. }
+            | Op::Volume { voice, .. }
+            | Op::Adsr { voice, .. }
+            | Op::Sample { voice, .. }
+            | Op::Loop { voice, .. } => 1 << voice,
+            Op::Release { mask } | Op::Trigger { mask } => mask,
+        }
+    }
+}
+
+fn mtime() -> u64 {
+    let mtime_l = memmap::SYS_TIMER.reg(sys_timer::MTIME_L) as *const u32;
+    let mtime_h = memmap::SYS_TIMER.reg(sys_timer::MTIME_H) as *const u32;
+
+    loop {
+        unsafe {
+            let h = mtime_h.read_volatile();
+            let l = mtime_l.read_volatile();
+            let c = mtime_h.read_volatile();
+
+            // Make sure that the counter didn't wrap as we were reading it
+            if h == c {
+                return (u64::from(h) << 32) | u64::from(l);
+            }
+        }
+    }
+}
+
+fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::from_micros((ticks * 1_000_000) / u64::from(MTIME_HZ))
+}
+
+const NRAS_HEADER_LEN: usize = 8;
+
+/// Unit of the SPU RAM addresses and lengths in NRAS files, in bytes
+const NRAS_ADDR_UNIT: u32 = 8;
+
+/// MTIME ticks per delay unit (1/4410th of a second)
+const TICKS_PER_DELAY: u64 = MTIME_HZ as u64 / 4410;
+
+/// Tempo lower bound (s16.16), to avoid absurdly long delays
+const MIN_TEMPO: i32 = 1 << 12;
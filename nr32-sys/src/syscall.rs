@@ -6,7 +6,7 @@ use core::time::Duration;
 use nr32_common::syscall::*;
 
 /// Frequency of the MTIME timer tick
-const MTIME_HZ: u32 = 44_100 * 16;
+pub(crate) const MTIME_HZ: u32 = 44_100 * 16;
 
 fn duration_to_ticks(duration: Duration) -> u64 {
     if duration.as_secs() < 0xffff_ffff {
@@ -32,6 +32,12 @@ pub fn wait_for_vsync() {
     unsafe { syscall_0(SYS_WAIT_FOR_VSYNC).unwrap() };
 }
 
+/// Wait until the SPU raises an IRQ. The IRQ sources must be acknowledged in the SPU before
+/// calling this function again.
+pub fn wait_for_spu_irq() {
+    unsafe { syscall_0(SYS_WAIT_FOR_SPU_IRQ).unwrap() };
+}
+
 pub fn exit() -> ! {
     unsafe { syscall_0(SYS_EXIT).unwrap() };
 
@@ -107,7 +113,7 @@ unsafe fn syscall_0(code: u32) -> SysResult<usize> {
     check_syscall_return(arg0, arg1)
 }
 
-unsafe fn syscall_1(code: u32, mut arg0: usize) -> SysResult<usize> {
+pub(crate) unsafe fn syscall_1(code: u32, mut arg0: usize) -> SysResult<usize> {
     let mut arg1;
 
     unsafe {
@@ -218,6 +224,8 @@ pub enum SysError {
     Timeout = 7,
     /// No such file or directory
     NoEnt = 8,
+    /// Bad address
+    Fault = 9,
 }
 
 pub type SysResult<T> = Result<T, SysError>;
@@ -234,6 +242,7 @@ fn check_syscall_return(result: usize, val: usize) -> SysResult<usize> {
         5 => TooLong,
         6 => NoSys,
         7 => Timeout,
+        9 => Fault,
         e => {
             warn!("Unexpected syscall error: {e}");
             Invalid
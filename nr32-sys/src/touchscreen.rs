@@ -0,0 +1,71 @@
+//! Touchscreen on input port 0. See `regs::input_dev` for the protocol.
+
+use crate::regs::input_dev as regs;
+use crate::syscall::{SysResult, input_device};
+
+pub use regs::TOUCH_MAX_POINTS;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct TouchPoint {
+    /// Identifies the touch for as long as it lasts
+    pub id: u8,
+    /// From 0 to 0x400
+    pub x: u16,
+    /// From 0 to 0x400
+    pub y: u16,
+    /// 1 (lightest) to 0xff, None if the touchscreen can't measure it
+    pub pressure: Option<u8>,
+}
+
+/// All the points currently touching the screen
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Touches {
+    points: [TouchPoint; TOUCH_MAX_POINTS],
+    count: usize,
+}
+
+impl Touches {
+    pub fn as_slice(&self) -> &[TouchPoint] {
+        &self.points[..self.count]
+    }
+
+    /// Returns the touch point with `id` if it's still there
+    pub fn find(&self, id: u8) -> Option<&TouchPoint> {
+        self.as_slice().iter().find(|p| p.id == id)
+    }
+}
+
+/// Read all the touch points. Returns None if no touchscreen is connected.
+pub fn read_touches() -> SysResult<Option<Touches>> {
+    let mut cmd = [0u8; 2 + TOUCH_MAX_POINTS * regs::TOUCH_POINT_LEN];
+
+    cmd[0] = b'T'; // Address touchscreen
+    cmd[1] = b'M'; // Read all points
+
+    input_device(regs::PORT_TOUCHSCREEN, &mut cmd)?;
+
+    let count = usize::from(cmd[1]);
+
+    if count > TOUCH_MAX_POINTS {
+        // Device didn't respond
+        return Ok(None);
+    }
+
+    let mut touches = Touches::default();
+
+    for (p, b) in touches.points[..count]
+        .iter_mut()
+        .zip(cmd[2..].chunks_exact(regs::TOUCH_POINT_LEN))
+    {
+        *p = TouchPoint {
+            id: b[0],
+            x: u16::from_be_bytes([b[1], b[2]]),
+            y: u16::from_be_bytes([b[3], b[4]]),
+            pressure: (b[5] != 0).then_some(b[5]),
+        };
+    }
+
+    touches.count = count;
+
+    Ok(Some(touches))
+}
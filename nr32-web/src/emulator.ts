@@ -6,6 +6,8 @@ import noRaFragmentShader from './shaders/nora.frag.glsl?raw';
 import screenVertexShader from './shaders/screen.vert.glsl?raw';
 import screenFragmentShader from './shaders/screen.frag.glsl?raw';
 
+type TouchPoint = [number, number, number, number | undefined];
+
 export class Emulator {
   canvas: HTMLCanvasElement;
   m: NoRa32;
@@ -14,10 +16,12 @@ export class Emulator {
   // Context for displaying the off-screen buffer to the canvas
   screenContext: GlContext;
   wasm: Awaited<ReturnType<typeof init>>;
-  // Last recorded touch position (if any). 16bit integers that go from [0, 0]
-  // on the top-left and [0xffff, 0xffff] in the bottom right. `undefined` if
-  // there's no touch.
-  touchPos: [number, number] | undefined = undefined;
+  // Current touch points as [id, x, y, pressure]. The coordinates go from
+  // [0, 0] on the top-left to [0x400, 0x400] in the bottom right, the pressure
+  // from 0 to 1 (`undefined` if unknown).
+  touches: TouchPoint[] = [];
+  // Gamepad buttons currently held on the keyboard (PAD_* bits)
+  keyboardButtons = 0;
 
   private constructor(canvas: HTMLCanvasElement, wasm: Awaited<ReturnType<typeof init>>) {
     this.wasm = wasm;
@@ -47,6 +51,8 @@ export class Emulator {
     });
 
     const projectionsLoc = this.noRaContext.getUniformLocation('u_projections');
+    const fogColorLoc = this.noRaContext.getUniformLocation('u_fog_color');
+    const fogRangeLoc = this.noRaContext.getUniformLocation('u_fog_range');
 
     // Framebuffer used for off-screen rendering
     const noRaFbo = gl.createFramebuffer();
@@ -116,7 +122,16 @@ export class Emulator {
     noRaBind();
 
     this.m.on_draw_triangles(
-      (mat_f32_ptr: number, mat_count: number, i16_ptr: number, u8_ptr: number, count: number) => {
+      (
+        mat_f32_ptr: number,
+        mat_count: number,
+        i16_ptr: number,
+        u8_ptr: number,
+        count: number,
+        fog_color: number,
+        fog_near: number,
+        fog_far: number,
+      ) => {
         const i16Data = new Int16Array(wasm.memory.buffer, i16_ptr, count * 3);
         const u8Data = new Uint8Array(wasm.memory.buffer, u8_ptr, count * 5);
         const matdata = new Float32Array(wasm.memory.buffer, mat_f32_ptr, mat_count * 16);
@@ -129,6 +144,14 @@ export class Emulator {
 
         gl.uniformMatrix4fv(projectionsLoc, false, matdata);
 
+        gl.uniform3f(
+          fogColorLoc,
+          (fog_color & 0xff) / 255,
+          ((fog_color >> 8) & 0xff) / 255,
+          ((fog_color >> 16) & 0xff) / 255,
+        );
+        gl.uniform2f(fogRangeLoc, fog_near, fog_far);
+
         gl.drawArrays(gl.TRIANGLES, 0, count);
       },
     );
@@ -163,11 +186,14 @@ export class Emulator {
 
     for (const event of ['mouseup', 'touchend']) {
       document.addEventListener(event, (e) => {
-        if (this.touchPos) {
+        if (this.touches.length > 0) {
           this.updateTouch(e);
         }
       });
     }
+
+    document.addEventListener('keydown', (e) => this.updateKey(e, true));
+    document.addEventListener('keyup', (e) => this.updateKey(e, false));
   }
 
   static async build(canvas: HTMLCanvasElement): Promise<Emulator> {
@@ -181,41 +207,126 @@ export class Emulator {
   }
 
   runFrame() {
-    this.m.set_inputs(this.touchPos);
+    const [padButtons, padSticks] = this.readGamepad();
+
+    this.m.set_inputs(this.touches, padButtons, padSticks);
     this.m.run_frame();
   }
 
   updateTouch(ev: Event) {
     const rect = this.canvas.getBoundingClientRect();
-    let x: number, y: number;
+    const toScreen = (x: number, y: number): [number, number] => [
+      Math.round(((x - rect.left) / rect.width) * 0x400),
+      Math.round(((y - rect.top) / rect.height) * 0x400),
+    ];
 
     ev.preventDefault();
 
     if (typeof TouchEvent !== 'undefined' && ev instanceof TouchEvent) {
-      if (ev.touches.length > 0) {
-        x = ev.touches[0].clientX - rect.left;
-        y = ev.touches[0].clientY - rect.top;
-      } else {
-        // No touch
-        this.touchPos = undefined;
-        return;
-      }
+      this.touches = Array.from(ev.touches, (t): TouchPoint => [
+        t.identifier,
+        ...toScreen(t.clientX, t.clientY),
+        // Browsers report 0 when they can't measure the force
+        t.force > 0 ? t.force : undefined,
+      ]);
     } else if (ev instanceof MouseEvent) {
       if ((ev.buttons & 1) == 0) {
         // No click
-        this.touchPos = undefined;
+        this.touches = [];
         return;
       }
-      x = ev.clientX - rect.left;
-      y = ev.clientY - rect.top;
-    } else {
-      // ?
+
+      this.touches = [[0, ...toScreen(ev.clientX, ev.clientY), undefined]];
+    }
+  }
+
+  updateKey(ev: KeyboardEvent, pressed: boolean) {
+    const button = KEYBOARD_MAP[ev.code];
+
+    if (button === undefined) {
       return;
     }
 
-    x = Math.round((x / rect.width) * 0x400);
-    y = Math.round((y / rect.height) * 0x400);
+    ev.preventDefault();
 
-    this.touchPos = [x, y];
+    if (pressed) {
+      this.keyboardButtons |= button;
+    } else {
+      this.keyboardButtons &= ~button;
+    }
+  }
+
+  // Combine the keyboard state with the first connected gamepad (if any). The
+  // sticks are only reported if a gamepad with at least 4 axes is connected.
+  readGamepad(): [number, number[] | undefined] {
+    let buttons = this.keyboardButtons;
+    let sticks: number[] | undefined = undefined;
+
+    const pad = navigator.getGamepads().find((p) => p?.connected);
+
+    if (pad && pad.mapping === 'standard') {
+      pad.buttons.forEach((b, i) => {
+        if (b.pressed && i < STANDARD_GAMEPAD_MAP.length) {
+          buttons |= STANDARD_GAMEPAD_MAP[i];
+        }
+      });
+
+      if (pad.axes.length >= 4) {
+        sticks = pad.axes.slice(0, 4);
+      }
+    }
+
+    return [buttons, sticks];
   }
 }
+
+// Must match the PAD_* constants in nr32-common/src/regs/input_dev.rs
+const PAD_UP = 1 << 0;
+const PAD_DOWN = 1 << 1;
+const PAD_LEFT = 1 << 2;
+const PAD_RIGHT = 1 << 3;
+const PAD_A = 1 << 4;
+const PAD_B = 1 << 5;
+const PAD_X = 1 << 6;
+const PAD_Y = 1 << 7;
+const PAD_L = 1 << 8;
+const PAD_R = 1 << 9;
+const PAD_START = 1 << 10;
+const PAD_SELECT = 1 << 11;
+const PAD_L3 = 1 << 12;
+const PAD_R3 = 1 << 13;
+
+const KEYBOARD_MAP: Record<string, number> = {
+  ArrowUp: PAD_UP,
+  ArrowDown: PAD_DOWN,
+  ArrowLeft: PAD_LEFT,
+  ArrowRight: PAD_RIGHT,
+  KeyX: PAD_A,
+  KeyZ: PAD_B,
+  KeyS: PAD_X,
+  KeyA: PAD_Y,
+  KeyQ: PAD_L,
+  KeyW: PAD_R,
+  Enter: PAD_START,
+  ShiftRight: PAD_SELECT,
+};
+
+// Button order of the W3C "standard" gamepad mapping
+const STANDARD_GAMEPAD_MAP = [
+  PAD_A, // Bottom face button
+  PAD_B, // Right face button
+  PAD_X, // Left face button
+  PAD_Y, // Top face button
+  PAD_L, // Left shoulder
+  PAD_R, // Right shoulder
+  0, // Left trigger
+  0, // Right trigger
+  PAD_SELECT,
+  PAD_START,
+  PAD_L3,
+  PAD_R3,
+  PAD_UP,
+  PAD_DOWN,
+  PAD_LEFT,
+  PAD_RIGHT,
+];
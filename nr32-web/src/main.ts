@@ -3,6 +3,7 @@ import './style.css';
 import { Emulator } from './emulator.ts';
 import { SampleFifo } from './shared-fifo.ts';
 import { redirectConsole } from './console.ts';
+import { loadMemoryCard, storeMemoryCard } from './save-storage.ts';
 
 import workletUrl from './audio-worklet.ts?worker&url';
 
@@ -26,10 +27,33 @@ async function main() {
 
   const emu = await Emulator.build(canvas);
 
-  const rom = await fetchROM('./cart.nr32');
+  const romUrl = './cart.nr32';
+  const rom = await fetchROM(romUrl);
 
   emu.loadRom(rom);
 
+  // Restore the memory card from the previous session and save it whenever
+  // the game writes to it
+  try {
+    const image = await loadMemoryCard(romUrl);
+
+    if (image) {
+      emu.m.load_memory_card(image);
+    }
+  } catch (e) {
+    console.error('Failed to load the memory card', e);
+  }
+
+  setInterval(() => {
+    const image = emu.m.take_memory_card_changes();
+
+    if (image) {
+      storeMemoryCard(romUrl, image).catch((e) =>
+        console.error('Failed to save the memory card', e),
+      );
+    }
+  }, 1000);
+
   // We have two ways of synchronizing the emulator: if audio is on we use the
   // audio worklet's FIFO level to decide when a new frame should be scheduled
   // (sync-on-audio). If we're muted we just schedule with a 30FPS interval
@@ -0,0 +1,40 @@
+// Persistent storage for the memory card images, backed by IndexedDB. Images
+// are stored by key (typically the URL of the cartridge).
+
+const DB_NAME = 'nora32';
+const STORE_NAME = 'memory-cards';
+
+function openDb(): Promise<IDBDatabase> {
+  return new Promise((resolve, reject) => {
+    const req = indexedDB.open(DB_NAME, 1);
+
+    req.onupgradeneeded = () => {
+      req.result.createObjectStore(STORE_NAME);
+    };
+    req.onsuccess = () => resolve(req.result);
+    req.onerror = () => reject(req.error);
+  });
+}
+
+export async function loadMemoryCard(key: string): Promise<Uint8Array | undefined> {
+  const db = await openDb();
+
+  return new Promise((resolve, reject) => {
+    const req = db.transaction(STORE_NAME, 'readonly').objectStore(STORE_NAME).get(key);
+
+    req.onsuccess = () => resolve(req.result instanceof Uint8Array ? req.result : undefined);
+    req.onerror = () => reject(req.error);
+  });
+}
+
+export async function storeMemoryCard(key: string, image: Uint8Array): Promise<void> {
+  const db = await openDb();
+
+  return new Promise((resolve, reject) => {
+    const tx = db.transaction(STORE_NAME, 'readwrite');
+
+    tx.objectStore(STORE_NAME).put(image, key);
+    tx.oncomplete = () => resolve();
+    tx.onerror = () => reject(tx.error);
+  });
+}
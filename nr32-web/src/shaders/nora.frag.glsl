@@ -2,8 +2,11 @@
 precision mediump float;
 
 in vec4 v_color;
+in float v_fog;
 out vec4 fragColor;
 
+uniform vec3 u_fog_color;
+
 const float ditherMatrix[16] = float[16](
   -0.5,
   0.0,
@@ -40,5 +43,7 @@ vec4 dither(vec4 color, vec2 fragCoord) {
 }
 
 void main() {
-  fragColor = dither(v_color, gl_FragCoord.xy);
+  vec4 color = vec4(mix(v_color.rgb, u_fog_color, v_fog), v_color.a);
+
+  fragColor = dither(color, gl_FragCoord.xy);
 }
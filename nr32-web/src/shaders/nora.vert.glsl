@@ -5,11 +5,24 @@ in uvec4 a_color;
 in uint a_projection_index;
 
 out vec4 v_color;
+out float v_fog;
 
 uniform mat4 u_projections[32];
+/* Fog [near, far] distances. Fog is disabled if far <= near */
+uniform vec2 u_fog_range;
 
 void main() {
   mat4 m = u_projections[a_projection_index];
   gl_Position = m * vec4(a_position, 1.0);
   v_color = vec4(a_color) / 255.0;
+
+  float near = u_fog_range.x;
+  float far = u_fog_range.y;
+
+  if (far > near) {
+    /* After the perspective transform W is the distance from the eye */
+    v_fog = clamp((gl_Position.w - near) / (far - near), 0.0, 1.0);
+  } else {
+    v_fog = 0.0;
+  }
 }
@@ -5,7 +5,6 @@ mod decoder;
 use crate::{CycleCounter, NoRa32, sync};
 use decoder::{Decoder, Instruction};
 use nr32_common::memmap::{RAM, ROM};
-use nr32_common::syscall::DmaTarget;
 use std::fmt;
 
 pub struct Cpu {
@@ -873,21 +872,11 @@ pub fn step(m: &mut NoRa32) {
 
 /// Invalidate the reservation if it overlaps with the DMA
 pub fn check_dma_reservation(m: &mut NoRa32) {
-    if let Some(r) = m.cpu.reservation {
-        if !m.dma.is_running() {
-            return;
-        }
-
-        let dma_dst = m.dma.dst();
-        if let Ok(DmaTarget::Memory) = dma_dst.target() {
-            let start = dma_dst.raw();
-            let len = m.dma.rem_words() * 4;
-
-            if r >= start && r < start + len {
-                // Invalidate
-                m.cpu.reservation = None;
-            }
-        }
+    if let Some(r) = m.cpu.reservation
+        && m.dma.is_writing_to(r)
+    {
+        // Invalidate
+        m.cpu.reservation = None;
     }
 }
 
@@ -1,10 +1,61 @@
 use crate::fifo::Fifo;
 use crate::irq;
-use crate::{CPU_FREQ, CycleCounter, NoRa32, cpu, gpu, sync};
+use crate::{CPU_FREQ, CycleCounter, NoRa32, cpu, gpu, spu, sync};
 use nr32_common::memmap::{RAM, ROM};
+use nr32_common::regs::dma as regs;
 use nr32_common::syscall::{DmaAddr, DmaTarget};
+use std::ops::{Index, IndexMut};
 
 pub struct Dma {
+    channels: [Channel; regs::CHANNEL_COUNT as usize],
+    /// Channels that have completed a transfer, one bit per channel
+    irq_status: u32,
+    /// Channels that have aborted a transfer because of an error, one bit per channel
+    error_status: u32,
+}
+
+impl Dma {
+    pub fn new() -> Dma {
+        Dma {
+            channels: [Channel::new(), Channel::new(), Channel::new()],
+            irq_status: 0,
+            error_status: 0,
+        }
+    }
+
+    /// Returns true if one of the running channels may be about to write to `addr`
+    pub fn is_writing_to(&self, addr: u32) -> bool {
+        self.channels.iter().any(|c| {
+            if !c.is_running() {
+                return false;
+            }
+
+            if let Ok(DmaTarget::Memory) = c.dst.target() {
+                let start = c.dst.raw();
+
+                addr >= start && addr - start < c.dst_span()
+            } else {
+                false
+            }
+        })
+    }
+}
+
+impl Index<usize> for Dma {
+    type Output = Channel;
+
+    fn index(&self, channel: usize) -> &Self::Output {
+        &self.channels[channel]
+    }
+}
+
+impl IndexMut<usize> for Dma {
+    fn index_mut(&mut self, channel: usize) -> &mut Self::Output {
+        &mut self.channels[channel]
+    }
+}
+
+pub struct Channel {
     /// Where the DMA is reading from
     src: DmaAddr,
     /// Where the DMA is writing to
@@ -14,28 +65,144 @@ pub struct Dma {
     rem_words: u32,
     /// Copy buffer
     buf: Fifo<32, u32>,
+    /// Address of the next descriptor to load in linked-list mode, 0 if none
+    next_desc: u32,
+    /// True if the current descriptor requested an IRQ on completion
+    desc_irq: bool,
+    /// Transfer mode, see `regs::MODE_*`
+    mode: u32,
+    /// Words per row in 2D mode
+    row_len: u32,
+    /// Distance in bytes between two source rows in 2D mode
+    src_stride: u32,
+    /// Distance in bytes between two destination rows in 2D mode
+    dst_stride: u32,
+    /// Position of `src` in the current row in 2D mode
+    src_col: u32,
+    /// Position of `dst` in the current row in 2D mode
+    dst_col: u32,
+    /// Cause of the last error (see `regs::CAUSE_*`), 0 if the last transfer didn't fail
+    error: u32,
+    /// Address that caused the last error
+    fault_addr: u32,
 }
 
-impl Dma {
-    pub fn new() -> Dma {
-        Dma {
+impl Channel {
+    fn new() -> Channel {
+        Channel {
             src: DmaAddr(0),
             dst: DmaAddr(0),
             rem_words: 0,
             buf: Fifo::new(),
+            next_desc: 0,
+            desc_irq: false,
+            mode: 0,
+            row_len: 0,
+            src_stride: 0,
+            dst_stride: 0,
+            src_col: 0,
+            dst_col: 0,
+            error: 0,
+            fault_addr: 0,
         }
     }
 
-    pub fn dst(&self) -> DmaAddr {
-        self.dst
+    fn is_running(&self) -> bool {
+        self.is_copying() || self.next_desc != 0
+    }
+
+    /// True if the current block (either set directly through the registers or from a
+    /// descriptor) still has words to copy
+    fn is_copying(&self) -> bool {
+        self.rem_words > 0 || !self.buf.is_empty()
     }
 
-    pub fn rem_words(&self) -> u32 {
-        self.rem_words
+    fn is_fill(&self) -> bool {
+        self.mode & regs::MODE_FILL != 0
     }
 
-    pub fn is_running(&self) -> bool {
-        self.rem_words > 0 || !self.buf.is_empty()
+    /// Start a new block of `len` words
+    fn start(&mut self, len: u32) {
+        self.rem_words = len;
+        self.buf.clear();
+        self.src_col = 0;
+        self.dst_col = 0;
+    }
+
+    /// Stop the transfer, including any pending descriptor
+    fn abort(&mut self) {
+        self.start(0);
+        self.next_desc = 0;
+        self.desc_irq = false;
+    }
+
+    /// Abort the transfer because of an error
+    fn fault(&mut self, cause: u32, addr: u32) {
+        self.abort();
+        self.error = cause;
+        self.fault_addr = addr;
+    }
+
+    fn status(&self) -> u32 {
+        let mut status = 0;
+
+        if self.is_running() {
+            status |= regs::STATUS_BUSY;
+        }
+
+        if self.error != 0 {
+            status |= regs::STATUS_ERROR | (self.error << regs::STATUS_CAUSE_SHIFT);
+        }
+
+        status
+    }
+
+    fn advance_src(&mut self) {
+        if self.mode & regs::MODE_SRC_FIXED == 0 {
+            self.src.0 = self.advance(self.src.0, self.src_stride, |c| &mut c.src_col);
+        }
+    }
+
+    fn advance_dst(&mut self) {
+        if self.mode & regs::MODE_DST_FIXED == 0 {
+            self.dst.0 = self.advance(self.dst.0, self.dst_stride, |c| &mut c.dst_col);
+        }
+    }
+
+    /// Returns the address following `addr`, moving on to the next row in 2D mode
+    fn advance(&mut self, addr: u32, stride: u32, col: fn(&mut Channel) -> &mut u32) -> u32 {
+        let row_len = self.row_len;
+        let addr = addr.wrapping_add(4);
+
+        if self.mode & regs::MODE_2D == 0 || row_len == 0 {
+            return addr;
+        }
+
+        let col = col(self);
+
+        *col += 1;
+
+        if *col < row_len {
+            addr
+        } else {
+            *col = 0;
+            addr.wrapping_sub(row_len * 4).wrapping_add(stride)
+        }
+    }
+
+    /// Upper bound of the number of bytes after `dst` that may still be written by this block
+    fn dst_span(&self) -> u32 {
+        let words = self.rem_words + self.buf.len() as u32;
+
+        if self.mode & regs::MODE_DST_FIXED != 0 {
+            4
+        } else if self.mode & regs::MODE_2D != 0 && self.row_len != 0 {
+            let rows = (words + self.dst_col).div_ceil(self.row_len);
+
+            rows.saturating_mul(self.dst_stride.max(self.row_len * 4))
+        } else {
+            words * 4
+        }
     }
 }
 
@@ -43,90 +210,148 @@ fn sync_for_dma(m: &mut NoRa32, target: DmaTarget) {
     match target {
         DmaTarget::Memory => (),
         DmaTarget::Gpu => gpu::run(m),
+        DmaTarget::Spu | DmaTarget::SpuStream => spu::run(m),
     }
 }
 
-fn dma_refill_buf(m: &mut NoRa32, cycles: &mut CycleCounter) {
-    let src_target = m.dma.src.target().unwrap();
+/// Fill the channel's buffer. Returns true if we stopped because we didn't have enough cycles
+/// left to read the next word.
+fn dma_refill_buf(m: &mut NoRa32, ch: usize, cycles: &mut CycleCounter) -> bool {
+    while m.dma[ch].rem_words > 0 && !m.dma[ch].buf.is_full() {
+        let c = &mut m.dma[ch];
+
+        if c.is_fill() {
+            if *cycles >= 1 {
+                *cycles -= 1;
+                c.buf.push(c.src.raw());
+                c.rem_words -= 1;
+                continue;
+            } else {
+                return true;
+            }
+        }
 
-    while m.dma.rem_words > 0 && !m.dma.buf.is_full() {
-        match src_target {
-            DmaTarget::Memory => {
-                if let Some(off) = RAM.contains(m.dma.src.raw()) {
+        match c.src.target() {
+            Ok(DmaTarget::Memory) => {
+                if let Some(off) = RAM.contains(c.src.raw()) {
                     if *cycles >= 1 {
                         *cycles -= 1;
                         let v = m.ram[(off >> 2) as usize];
-                        m.dma.buf.push(v);
-                        m.dma.rem_words -= 1;
-                        m.dma.src.0 = m.dma.src.0.wrapping_add(4);
+                        c.buf.push(v);
+                        c.rem_words -= 1;
+                        c.advance_src();
                     } else {
-                        return;
+                        return true;
                     }
-                } else if let Some(off) = ROM.contains(m.dma.src.raw()) {
+                } else if let Some(off) = ROM.contains(c.src.raw()) {
                     if *cycles >= 20 {
                         *cycles -= 20;
-                        let v = m.rom[(off >> 2) as usize];
-                        m.dma.buf.push(v);
-                        m.dma.rem_words -= 1;
-                        m.dma.src.0 = m.dma.src.0.wrapping_add(4);
+                        let v = m.rom.get((off >> 2) as usize).cloned().unwrap_or(!0);
+                        c.buf.push(v);
+                        c.rem_words -= 1;
+                        c.advance_src();
                     } else {
-                        return;
+                        return true;
                     }
                 } else {
-                    todo!()
+                    warn!("DMA {ch}: invalid source address 0x{:x}", c.src.raw());
+                    c.fault(regs::CAUSE_SRC, c.src.raw());
                 }
             }
-            _ => todo!(),
+            Ok(DmaTarget::Spu) => {
+                if *cycles >= spu::DMA_WORD_CYCLES {
+                    *cycles -= spu::DMA_WORD_CYCLES;
+                    let v = spu::dma_load(m);
+                    let c = &mut m.dma[ch];
+                    c.buf.push(v);
+                    c.rem_words -= 1;
+                } else {
+                    return true;
+                }
+            }
+            _ => {
+                warn!("DMA {ch}: invalid source {:x}", c.src.raw());
+                c.fault(regs::CAUSE_SRC, c.src.raw());
+            }
         }
     }
+
+    false
 }
 
-fn run_dma_cycles(m: &mut NoRa32, mut cycles: CycleCounter) -> CycleCounter {
-    let src_target = m.dma.src.target().unwrap();
-    let dst_target = m.dma.dst.target().unwrap();
+/// Run channel `ch` for up to `cycles` cycles. The cycles actually used are removed from
+/// `cycles` and `starved` is set if the channel stopped because it didn't have enough cycles left.
+/// Returns the number of cycles until the channel should be run again.
+fn run_channel(
+    m: &mut NoRa32,
+    ch: usize,
+    cycles: &mut CycleCounter,
+    starved: &mut bool,
+) -> CycleCounter {
+    let Ok(dst_target) = m.dma[ch].dst.target() else {
+        let c = &mut m.dma[ch];
+        warn!("DMA {ch}: invalid destination {:x}", c.dst.raw());
+        c.fault(regs::CAUSE_DST, c.dst.raw());
+        return CPU_FREQ;
+    };
 
-    sync_for_dma(m, src_target);
+    if !m.dma[ch].is_fill()
+        && let Ok(src_target) = m.dma[ch].src.target()
+    {
+        sync_for_dma(m, src_target);
+    }
     sync_for_dma(m, dst_target);
 
     loop {
-        dma_refill_buf(m, &mut cycles);
+        *starved |= dma_refill_buf(m, ch, cycles);
 
-        if m.dma.buf.is_empty() {
-            // We're stalling on input (or we're done).
-            return match src_target {
-                // Arbitrary value that should be short enough to avoid introducing too much
-                // latency but long enough to avoid a big performance impact.
-                DmaTarget::Memory => {
-                    sync::rewind(m, DMASYNC, cycles);
-                    128
-                }
-                _ => todo!(),
-            };
+        if m.dma[ch].buf.is_empty() {
+            // We're stalling on input (or we're done). Arbitrary value that should be short
+            // enough to avoid introducing too much latency but long enough to avoid a big
+            // performance impact.
+            return 128;
         }
 
-        while let Some(v) = m.dma.buf.front() {
+        while let Some(v) = m.dma[ch].buf.front() {
             let res = match dst_target {
                 DmaTarget::Memory => {
-                    if let Some(off) = RAM.contains(m.dma.dst.raw()) {
+                    let c = &mut m.dma[ch];
+
+                    if let Some(off) = RAM.contains(c.dst.raw()) {
                         m.ram[(off >> 2) as usize] = v;
-                        m.dma.dst.0 = m.dma.dst.0.wrapping_add(4);
+                        c.advance_dst();
                         DmaResult::Ok
                     } else {
-                        todo!()
+                        warn!("DMA {ch}: invalid destination address 0x{:x}", c.dst.raw());
+                        c.fault(regs::CAUSE_DST, c.dst.raw());
+                        return CPU_FREQ;
                     }
                 }
                 DmaTarget::Gpu => gpu::dma_store(m, v),
+                DmaTarget::SpuStream => spu::dma_stream_store(m, v),
+                DmaTarget::Spu => {
+                    if *cycles >= spu::DMA_WORD_CYCLES {
+                        *cycles -= spu::DMA_WORD_CYCLES;
+                        spu::dma_store(m, v);
+                        DmaResult::Ok
+                    } else {
+                        // Wait until we can write a few more words. Same as above, the duration is
+                        // arbitrary.
+                        *starved = true;
+                        DmaResult::Stall(128)
+                    }
+                }
             };
 
             match res {
                 DmaResult::Ok => {
-                    m.dma.buf.pop();
+                    m.dma[ch].buf.pop();
                 }
                 DmaResult::Stall(duration) => {
                     // We're stalling on output
                     assert!(duration > 0);
                     // Make sure we don't waste cycles if we could read more
-                    dma_refill_buf(m, &mut cycles);
+                    *starved |= dma_refill_buf(m, ch, cycles);
 
                     return duration;
                 }
@@ -135,61 +360,255 @@ fn run_dma_cycles(m: &mut NoRa32, mut cycles: CycleCounter) -> CycleCounter {
     }
 }
 
+/// Flag the channels in `channels` as done, triggering the DMA interrupt if none were already
+/// pending.
+fn raise_irq(m: &mut NoRa32, channels: u32) {
+    let was_high = m.dma.irq_status != 0;
+
+    m.dma.irq_status |= channels;
+
+    if !was_high {
+        irq::trigger(m, irq::Interrupt::DmaDone);
+    }
+}
+
+/// Flag the channels in `channels` as failed, triggering the DMA error interrupt if none were
+/// already pending.
+fn raise_error_irq(m: &mut NoRa32, channels: u32) {
+    let was_high = m.dma.error_status != 0;
+
+    m.dma.error_status |= channels;
+
+    if !was_high {
+        irq::trigger(m, irq::Interrupt::DmaError);
+    }
+}
+
 pub fn run(m: &mut NoRa32) {
-    let elapsed = sync::resync(m, DMASYNC);
+    let mut cycles = sync::resync(m, DMASYNC);
 
     sync::next_event(m, DMASYNC, CPU_FREQ);
 
-    let was_running = m.dma.is_running();
+    let mut next_sync = CPU_FREQ;
+    let mut starved = false;
 
-    let next_sync = if was_running {
-        run_dma_cycles(m, elapsed)
-    } else {
-        CPU_FREQ
-    };
+    // Channels are served in priority order, lower priority channels only get the cycles left
+    // over by the higher priority ones.
+    for ch in 0..(regs::CHANNEL_COUNT as usize) {
+        if !m.dma[ch].is_running() {
+            continue;
+        }
 
-    let is_running = m.dma.is_running();
+        let mut ch_sync = if m.dma[ch].is_copying() {
+            run_channel(m, ch, &mut cycles, &mut starved)
+        } else {
+            CPU_FREQ
+        };
 
-    if was_running && !is_running {
-        // End of transfer
-        irq::trigger(m, irq::Interrupt::DmaDone);
+        if !m.dma[ch].is_copying() && m.dma[ch].next_desc != 0 {
+            if m.dma[ch].desc_irq {
+                // Intermediate descriptor requesting an IRQ
+                raise_irq(m, 1 << ch);
+            }
+
+            ch_sync = load_descriptor(m, ch);
+        }
+
+        if m.dma[ch].is_running() {
+            next_sync = next_sync.min(ch_sync);
+        } else if m.dma[ch].error != 0 {
+            raise_error_irq(m, 1 << ch);
+        } else {
+            // End of transfer
+            raise_irq(m, 1 << ch);
+        }
     }
 
-    let next_sync = if is_running {
-        next_sync
-    } else {
-        // Idle
-        CPU_FREQ
-    };
+    if starved {
+        // Some channel could make use of the leftover cycles once we have a few more
+        sync::rewind(m, DMASYNC, cycles);
+    }
 
     sync::next_event(m, DMASYNC, next_sync);
 }
 
+/// Load the descriptor at `next_desc` for channel `ch` and returns the number of cycles it took
+fn load_descriptor(m: &mut NoRa32, ch: usize) -> CycleCounter {
+    let addr = m.dma[ch].next_desc;
+    let mut desc = [0u32; 8];
+    let mut cycles = 0;
+
+    m.dma[ch].next_desc = 0;
+    m.dma[ch].desc_irq = false;
+
+    for i in 0..desc.len() {
+        if i == 4 && desc[2] & regs::MODE_2D == 0 {
+            // The rest of the descriptor is only used in 2D mode
+            break;
+        }
+
+        let a = addr.wrapping_add((i as u32) << 2);
+
+        desc[i] = if a & 3 != 0 {
+            warn!("DMA {ch}: misaligned descriptor at 0x{addr:x}");
+            m.dma[ch].fault(regs::CAUSE_DESC, addr);
+            return 1;
+        } else if let Some(off) = RAM.contains(a) {
+            cycles += 1;
+            m.ram[(off >> 2) as usize]
+        } else if let Some(off) = ROM.contains(a) {
+            cycles += 20;
+            m.rom.get((off >> 2) as usize).cloned().unwrap_or(!0)
+        } else {
+            warn!("DMA {ch}: invalid descriptor address 0x{a:x}");
+            m.dma[ch].fault(regs::CAUSE_DESC, a);
+            return 1;
+        };
+    }
+
+    let [src, dst, ctrl, next, row_len, src_stride, dst_stride, _] = desc;
+
+    if ctrl & regs::MODE_FILL == 0 && DmaAddr::src_from_raw(src).is_err() {
+        warn!("DMA {ch}: invalid source {src:x} in descriptor at 0x{addr:x}");
+        m.dma[ch].fault(regs::CAUSE_SRC, src);
+        return 1;
+    }
+
+    if DmaAddr::dst_from_raw(dst).is_err() {
+        warn!("DMA {ch}: invalid destination {dst:x} in descriptor at 0x{addr:x}");
+        m.dma[ch].fault(regs::CAUSE_DST, dst);
+        return 1;
+    }
+
+    let c = &mut m.dma[ch];
+
+    c.src = DmaAddr(src);
+    c.dst = DmaAddr(dst);
+    c.mode = ctrl & regs::MODE_MASK;
+    c.row_len = row_len;
+    c.src_stride = src_stride;
+    c.dst_stride = dst_stride;
+    c.start(ctrl & regs::DESC_LEN_MASK);
+    c.desc_irq = ctrl & regs::DESC_IRQ != 0;
+    c.next_desc = next;
+
+    if c.rem_words > 0 {
+        cpu::check_dma_reservation(m);
+    }
+
+    cycles
+}
+
+pub fn load_word(m: &mut NoRa32, addr: u32) -> u32 {
+    run(m);
+
+    match addr {
+        regs::IRQ_STATUS => m.dma.irq_status,
+        regs::ERROR_STATUS => m.dma.error_status,
+        regs::CHANNEL_BASE.. => {
+            let ch = ((addr - regs::CHANNEL_BASE) / regs::CHANNEL_STRIDE) as usize;
+            if ch >= regs::CHANNEL_COUNT as usize {
+                warn!("Read from unknown DMA channel {ch}");
+                return !0;
+            }
+
+            let c = &m.dma[ch];
+
+            match (addr - regs::CHANNEL_BASE) % regs::CHANNEL_STRIDE {
+                regs::CHANNEL_SRC => c.src.raw(),
+                regs::CHANNEL_DST => c.dst.raw(),
+                regs::CHANNEL_LEN => c.rem_words + c.buf.len() as u32,
+                regs::CHANNEL_LIST => c.next_desc,
+                regs::CHANNEL_MODE => c.mode,
+                regs::CHANNEL_ROW_LEN => c.row_len,
+                regs::CHANNEL_SRC_STRIDE => c.src_stride,
+                regs::CHANNEL_DST_STRIDE => c.dst_stride,
+                regs::CHANNEL_STATUS => c.status(),
+                regs::CHANNEL_FAULT_ADDR => c.fault_addr,
+                _ => {
+                    warn!("Unknown DMA register {addr:x}");
+                    !0
+                }
+            }
+        }
+        n => {
+            warn!("Unknown DMA register {n:x}");
+            !0
+        }
+    }
+}
+
 pub fn store_word(m: &mut NoRa32, addr: u32, val: u32) {
     run(m);
 
-    match addr >> 2 {
-        // SRC
-        0 => m.dma.src = DmaAddr(val),
-        // DST
-        1 => m.dma.dst = DmaAddr(val),
-        // LEN
-        2 => {
-            m.dma.rem_words = val;
-            m.dma.buf.clear();
-
-            let n_e = if val == 0 {
-                // Idle
-                CPU_FREQ
-            } else {
-                // Start
-                cpu::check_dma_reservation(m);
-                32
+    match addr {
+        regs::IRQ_STATUS => m.dma.irq_status &= !val,
+        regs::ERROR_STATUS => m.dma.error_status &= !val,
+        regs::CHANNEL_BASE.. => {
+            let ch = ((addr - regs::CHANNEL_BASE) / regs::CHANNEL_STRIDE) as usize;
+            if ch >= regs::CHANNEL_COUNT as usize {
+                warn!("Write to unknown DMA channel {ch}");
+                return;
+            }
+
+            let c = &mut m.dma[ch];
+
+            let start = match (addr - regs::CHANNEL_BASE) % regs::CHANNEL_STRIDE {
+                regs::CHANNEL_SRC => {
+                    c.src = DmaAddr(val);
+                    false
+                }
+                regs::CHANNEL_DST => {
+                    c.dst = DmaAddr(val);
+                    false
+                }
+                regs::CHANNEL_LEN => {
+                    c.start(val);
+                    c.next_desc = 0;
+                    c.desc_irq = false;
+                    c.error = 0;
+
+                    if val != 0 {
+                        cpu::check_dma_reservation(m);
+                    }
+
+                    val != 0
+                }
+                regs::CHANNEL_LIST => {
+                    c.start(0);
+                    c.next_desc = val;
+                    c.desc_irq = false;
+                    c.error = 0;
+
+                    val != 0
+                }
+                regs::CHANNEL_MODE => {
+                    c.mode = val & regs::MODE_MASK;
+                    false
+                }
+                regs::CHANNEL_ROW_LEN => {
+                    c.row_len = val;
+                    false
+                }
+                regs::CHANNEL_SRC_STRIDE => {
+                    c.src_stride = val;
+                    false
+                }
+                regs::CHANNEL_DST_STRIDE => {
+                    c.dst_stride = val;
+                    false
+                }
+                _ => {
+                    warn!("Unknown DMA register {addr:x}");
+                    false
+                }
             };
 
-            sync::next_event(m, DMASYNC, n_e);
+            if start {
+                sync::next_event(m, DMASYNC, 32);
+            }
         }
-        n => panic!("Unknown DMA register {n:x}"),
+        n => warn!("Unknown DMA register {n:x}"),
     }
 }
 
@@ -199,3 +618,225 @@ pub enum DmaResult {
 }
 
 const DMASYNC: sync::SyncToken = sync::SyncToken::Dma;
+
+/// Run the DMA for `cycles` cycles, acknowledging the IRQs. Returns the number of channel
+/// completions seen for each channel.
+#[cfg(test)]
+fn run_test_cycles(m: &mut NoRa32, cycles: CycleCounter) -> [u32; regs::CHANNEL_COUNT as usize] {
+    use nr32_common::regs::irq as irq_regs;
+
+    let mut irqs = [0; regs::CHANNEL_COUNT as usize];
+
+    for _ in 0..(cycles / 16) {
+        m.tick(16);
+        sync::handle_events(m);
+
+        let pending = irq::load_word(m, irq_regs::PENDING);
+
+        if pending & irq::Interrupt::DmaDone.mask() != 0 {
+            let status = load_word(m, regs::IRQ_STATUS);
+
+            for (ch, n) in irqs.iter_mut().enumerate() {
+                *n += (status >> ch) & 1;
+            }
+
+            store_word(m, regs::IRQ_STATUS, status);
+        }
+
+        irq::store_word(m, irq_regs::PENDING, pending);
+    }
+
+    irqs
+}
+
+#[test]
+fn test_dma_list() {
+    let mut m = NoRa32::new();
+    let ch = regs::CHANNEL_MEMORY;
+
+    for i in 0..64 {
+        m.ram[i] = 0xabcd_0000 | i as u32;
+    }
+
+    // [src, dst, ctrl, next]. Only the first descriptor requests an IRQ, the second one must
+    // be chained silently to the third.
+    let chain = [
+        [0x000, 0x1000, 16 | regs::DESC_IRQ, 0x220],
+        [0x040, 0x2000, 24, 0x240],
+        [0x0a0, 0x3000, 4, 0],
+    ];
+
+    for (i, desc) in chain.iter().enumerate() {
+        let off = 0x200 / 4 + i * 8;
+
+        m.ram[off..off + 4].copy_from_slice(desc);
+    }
+
+    store_word(&mut m, regs::channel_reg(ch, regs::CHANNEL_LIST), 0x200);
+
+    let irqs = run_test_cycles(&mut m, 16_000);
+
+    assert!(!m.dma[ch as usize].is_running());
+    assert_eq!(irqs[ch as usize], 2);
+    assert_eq!(irqs.iter().sum::<u32>(), 2);
+    assert_eq!(
+        load_word(&mut m, regs::channel_reg(ch, regs::CHANNEL_LEN)),
+        0
+    );
+    assert_eq!(
+        load_word(&mut m, regs::channel_reg(ch, regs::CHANNEL_LIST)),
+        0
+    );
+
+    for (dst, src, len) in [(0x1000, 0, 16), (0x2000, 16, 24), (0x3000, 40, 4)] {
+        let dst = dst / 4;
+
+        assert_eq!(m.ram[dst..dst + len], m.ram[src..src + len]);
+        assert_eq!(m.ram[dst + len], 0);
+    }
+}
+
+#[test]
+fn test_dma_priority() {
+    let mut m = NoRa32::new();
+
+    for i in 0..0x400 {
+        m.ram[i] = i as u32;
+    }
+
+    // Start the low priority channel first, the high priority one must still take over the bus
+    for (ch, dst) in [
+        (regs::CHANNEL_MEMORY, 0x10_0000),
+        (regs::CHANNEL_GPU, 0x8_0000),
+    ] {
+        store_word(&mut m, regs::channel_reg(ch, regs::CHANNEL_SRC), 0);
+        store_word(&mut m, regs::channel_reg(ch, regs::CHANNEL_DST), dst);
+        store_word(&mut m, regs::channel_reg(ch, regs::CHANNEL_LEN), 0x400);
+    }
+
+    // Enough time for the first transfer but not for the second
+    let irqs = run_test_cycles(&mut m, 0x600);
+
+    assert_eq!(irqs, [1, 0, 0]);
+    assert!(m.dma[regs::CHANNEL_MEMORY as usize].is_running());
+
+    let irqs = run_test_cycles(&mut m, 0x600);
+
+    assert_eq!(irqs, [0, 0, 1]);
+
+    for dst in [0x8_0000 / 4, 0x10_0000 / 4] {
+        assert_eq!(m.ram[dst..dst + 0x400], m.ram[..0x400]);
+    }
+}
+
+#[test]
+fn test_dma_modes() {
+    let mut m = NoRa32::new();
+    let ch = regs::CHANNEL_MEMORY;
+
+    let set = |m: &mut NoRa32, reg, val| store_word(m, regs::channel_reg(ch, reg), val);
+
+    let transfer = |m: &mut NoRa32, src, dst, len, mode| {
+        set(m, regs::CHANNEL_MODE, mode);
+        set(m, regs::CHANNEL_SRC, src);
+        set(m, regs::CHANNEL_DST, dst);
+        set(m, regs::CHANNEL_LEN, len);
+
+        run_test_cycles(m, 1000);
+        assert!(!m.dma[ch as usize].is_running());
+    };
+
+    // 8x4 words source buffer
+    for i in 0..32 {
+        m.ram[0x2000 / 4 + i] = i as u32;
+    }
+
+    transfer(&mut m, 0xdead_beef, 0x1000, 4, regs::MODE_FILL);
+    assert_eq!(
+        m.ram[0x1000 / 4..][..5],
+        [0xdead_beef, 0xdead_beef, 0xdead_beef, 0xdead_beef, 0]
+    );
+
+    transfer(&mut m, 0x2000 + 3 * 4, 0x1100, 5, regs::MODE_SRC_FIXED);
+    assert_eq!(m.ram[0x1100 / 4..][..6], [3, 3, 3, 3, 3, 0]);
+
+    transfer(&mut m, 0x2000, 0x1200, 4, regs::MODE_DST_FIXED);
+    assert_eq!(m.ram[0x1200 / 4..][..2], [3, 0]);
+
+    // Copy the 3x2 rectangle at (2, 1) out of the source buffer
+    set(&mut m, regs::CHANNEL_ROW_LEN, 3);
+    set(&mut m, regs::CHANNEL_SRC_STRIDE, 8 * 4);
+    set(&mut m, regs::CHANNEL_DST_STRIDE, 3 * 4);
+    transfer(&mut m, 0x2000 + 8 * 4 + 2 * 4, 0x1300, 6, regs::MODE_2D);
+    assert_eq!(m.ram[0x1300 / 4..][..7], [10, 11, 12, 18, 19, 20, 0]);
+
+    // And put it back at (0, 2) in a buffer with a different stride
+    set(&mut m, regs::CHANNEL_SRC_STRIDE, 3 * 4);
+    set(&mut m, regs::CHANNEL_DST_STRIDE, 4 * 4);
+    transfer(&mut m, 0x1300, 0x1400 + 2 * 4 * 4, 6, regs::MODE_2D);
+    assert_eq!(
+        m.ram[0x1400 / 4..][..16],
+        [0, 0, 0, 0, 0, 0, 0, 0, 10, 11, 12, 0, 18, 19, 20, 0]
+    );
+}
+
+#[test]
+fn test_dma_error() {
+    let mut m = NoRa32::new();
+    let ch = regs::CHANNEL_MEMORY;
+
+    let get = |m: &mut NoRa32, reg| load_word(m, regs::channel_reg(ch, reg));
+    let set = |m: &mut NoRa32, reg, val| store_word(m, regs::channel_reg(ch, reg), val);
+
+    // Source outside of RAM and ROM
+    set(&mut m, regs::CHANNEL_SRC, 0x1000_0000);
+    set(&mut m, regs::CHANNEL_DST, 0x1000);
+    set(&mut m, regs::CHANNEL_LEN, 16);
+
+    let irqs = run_test_cycles(&mut m, 1000);
+
+    assert_eq!(irqs, [0; regs::CHANNEL_COUNT as usize]);
+    assert!(!m.dma[ch as usize].is_running());
+    assert_eq!(load_word(&mut m, regs::ERROR_STATUS), 1 << ch);
+    assert_eq!(
+        get(&mut m, regs::CHANNEL_STATUS),
+        regs::STATUS_ERROR | (regs::CAUSE_SRC << regs::STATUS_CAUSE_SHIFT)
+    );
+    assert_eq!(get(&mut m, regs::CHANNEL_FAULT_ADDR), 0x1000_0000);
+
+    store_word(&mut m, regs::ERROR_STATUS, 1 << ch);
+    assert_eq!(load_word(&mut m, regs::ERROR_STATUS), 0);
+
+    // Destination in ROM. Starting a new transfer clears the previous error.
+    set(&mut m, regs::CHANNEL_SRC, 0);
+    set(&mut m, regs::CHANNEL_DST, ROM.base);
+    set(&mut m, regs::CHANNEL_LEN, 16);
+
+    assert_eq!(get(&mut m, regs::CHANNEL_STATUS), regs::STATUS_BUSY);
+
+    run_test_cycles(&mut m, 1000);
+
+    assert_eq!(load_word(&mut m, regs::ERROR_STATUS), 1 << ch);
+    assert_eq!(
+        get(&mut m, regs::CHANNEL_STATUS),
+        regs::STATUS_ERROR | (regs::CAUSE_DST << regs::STATUS_CAUSE_SHIFT)
+    );
+    assert_eq!(get(&mut m, regs::CHANNEL_FAULT_ADDR), ROM.base);
+
+    // Broken descriptor chain
+    store_word(&mut m, regs::ERROR_STATUS, 1 << ch);
+    m.ram[0x200 / 4..][..4].copy_from_slice(&[0x200, 0x1000, 4, 0x302]);
+    set(&mut m, regs::CHANNEL_LIST, 0x200);
+
+    let irqs = run_test_cycles(&mut m, 1000);
+
+    assert_eq!(irqs, [0; regs::CHANNEL_COUNT as usize]);
+    assert_eq!(load_word(&mut m, regs::ERROR_STATUS), 1 << ch);
+    assert_eq!(
+        get(&mut m, regs::CHANNEL_STATUS),
+        regs::STATUS_ERROR | (regs::CAUSE_DESC << regs::STATUS_CAUSE_SHIFT)
+    );
+    assert_eq!(get(&mut m, regs::CHANNEL_FAULT_ADDR), 0x302);
+    // The first descriptor still went through
+    assert_eq!(m.ram[0x1000 / 4..][..4], [0x200, 0x1000, 4, 0x302]);
+}
@@ -1,5 +1,6 @@
 use crate::{CPU_FREQ, CycleCounter, NoRa32, dma::DmaResult, fifo::Fifo, irq, sync};
 use glam::Mat4;
+use nr32_common::regs::gpu as regs;
 use std::fmt;
 
 pub struct Gpu {
@@ -15,6 +16,8 @@ pub struct Gpu {
     vertices: [Vertex; 3],
     /// Matrix used for perspective transform of vertices
     draw_mat: u8,
+    /// Distance fog configuration
+    fog: Fog,
     /// Float vertex attributes for OpenGL:
     ///
     /// [0]: X
@@ -48,6 +51,7 @@ impl Gpu {
             mat: [Mat4::IDENTITY; 8],
             vertices: [Vertex::new(); 3],
             draw_mat: 0,
+            fog: Fog::new(),
             attribs_i16: Vec::new(),
             attribs_u8: Vec::new(),
             matrices_f32: Vec::new(),
@@ -59,11 +63,12 @@ impl Gpu {
 
     fn status(&self) -> u32 {
         let mut st = 0;
-        // bit 0: Command FIFO full
-        st |= self.command_fifo.is_full() as u32;
 
-        // bits [31:24]: number of words in command FIFO
-        st |= (self.command_fifo.len() << 24) as u32;
+        if self.command_fifo.is_full() {
+            st |= regs::STATUS_FIFO_FULL;
+        }
+
+        st |= (self.command_fifo.len() as u32) << regs::STATUS_FIFO_LEN_SHIFT;
 
         st
     }
@@ -195,6 +200,41 @@ fn handle_command(m: &mut NoRa32, cmd: u32) {
             m.gpu.set_matrix_component(mindex, i, j, v);
             CommandState::Idle
         }
+        CommandState::FogColor => {
+            let b = (cmd >> 16) as u8;
+            let g = (cmd >> 8) as u8;
+            let r = cmd as u8;
+
+            let fog = Fog {
+                color: [r, g, b],
+                ..m.gpu.fog
+            };
+            set_fog(m, fog);
+
+            CommandState::Idle
+        }
+        CommandState::FogNear => CommandState::FogFar {
+            near: Fp32(cmd as i32),
+        },
+        CommandState::FogFar { near } => {
+            let fog = Fog {
+                near: near.to_f32(),
+                far: Fp32(cmd as i32).to_f32(),
+                ..m.gpu.fog
+            };
+            set_fog(m, fog);
+
+            CommandState::Idle
+        }
+    }
+}
+
+/// Change the fog configuration. Since the fog parameters are shared by every triangle in a draw
+/// call we have to flush the buffered triangles if the configuration changes.
+fn set_fog(m: &mut NoRa32, fog: Fog) {
+    if fog != m.gpu.fog {
+        do_draw(m);
+        m.gpu.fog = fog;
     }
 }
 
@@ -204,8 +244,12 @@ fn do_draw(m: &mut NoRa32) {
         return;
     }
 
-    m.callbacks
-        .draw_triangles(&m.gpu.matrices_f32, &m.gpu.attribs_i16, &m.gpu.attribs_u8);
+    m.callbacks.draw_triangles(
+        &m.gpu.matrices_f32,
+        &m.gpu.attribs_i16,
+        &m.gpu.attribs_u8,
+        &m.gpu.fog,
+    );
 
     m.gpu.attribs_i16.clear();
     m.gpu.attribs_u8.clear();
@@ -214,18 +258,15 @@ fn do_draw(m: &mut NoRa32) {
 }
 
 fn handle_new_command(m: &mut NoRa32, cmd: u32) -> CommandState {
-    let op = (cmd >> 24) as u8;
+    let op = regs::op(cmd);
 
     match op {
-        // NOP
-        0x00 => CommandState::Idle,
-        // Draw start
-        0x01 => {
+        regs::OP_NOP => CommandState::Idle,
+        regs::OP_DRAW_START => {
             m.gpu.raster_state = RasterState::Drawing;
             CommandState::Idle
         }
-        // Draw end
-        0x02 => {
+        regs::OP_DRAW_END => {
             if m.gpu.raster_state == RasterState::Drawing {
                 do_draw(m);
                 m.callbacks.display_framebuffer();
@@ -234,33 +275,32 @@ fn handle_new_command(m: &mut NoRa32, cmd: u32) -> CommandState {
             }
             CommandState::Idle
         }
-        // Draw config
-        0x03 => {
-            match (cmd >> 16) as u8 {
-                // Set draw matrix
-                0x01 => m.gpu.draw_mat = (cmd & 0xf) as u8,
-                conf => warn!("Unknown config command {}", conf),
+        regs::OP_CONFIG => match regs::sub_op(cmd) {
+            regs::CONFIG_DRAW_MATRIX => {
+                m.gpu.draw_mat = (cmd & 0xf) as u8;
+                CommandState::Idle
             }
-            CommandState::Idle
-        }
-        // Matrix
-        0x10 => {
+            regs::CONFIG_FOG_COLOR => CommandState::FogColor,
+            regs::CONFIG_FOG_RANGE => CommandState::FogNear,
+            conf => {
+                warn!("Unknown config command {}", conf);
+                CommandState::Idle
+            }
+        },
+        regs::OP_MATRIX => {
             let mindex = ((cmd >> 12) & 7) as usize;
 
-            match (cmd >> 16) & 0xff {
-                // Reset matrix to identity
-                0x00 => {
+            match regs::sub_op(cmd) {
+                regs::MATRIX_RESET => {
                     m.gpu.mat[mindex] = Mat4::IDENTITY;
                     CommandState::Idle
                 }
-                // Set single component
-                0x01 => CommandState::MatrixSetComponent {
+                regs::MATRIX_SET => CommandState::MatrixSetComponent {
                     mindex: mindex as u8,
                     i: ((cmd >> 4) & 3) as u8,
                     j: (cmd & 3) as u8,
                 },
-                // Multiply
-                0x02 => {
+                regs::MATRIX_MULTIPLY => {
                     let maindex = ((cmd >> 4) & 0x7) as usize;
                     let mbindex = (cmd & 0x7) as usize;
 
@@ -279,11 +319,8 @@ fn handle_new_command(m: &mut NoRa32, cmd: u32) -> CommandState {
                 }
             }
         }
-        // Draw triangle
-        0x40..=0x7f => {
-            let blend_mode = (op >> 1) & 7;
-
-            let gouraud = blend_mode == 2;
+        regs::OP_TRIANGLE..=regs::OP_TRIANGLE_LAST => {
+            let gouraud = regs::triangle_blend_mode(cmd) == regs::BLEND_GOURAUD;
 
             let b = (cmd >> 16) as u8;
             let g = (cmd >> 8) as u8;
@@ -301,7 +338,7 @@ fn handle_new_command(m: &mut NoRa32, cmd: u32) -> CommandState {
 
 pub fn load_word(m: &mut NoRa32, addr: u32) -> u32 {
     run(m);
-    if addr == 0 {
+    if addr == regs::COMMAND {
         m.gpu.status()
     } else {
         warn!("Unhandled GPU read at {:x}", addr);
@@ -312,7 +349,7 @@ pub fn load_word(m: &mut NoRa32, addr: u32) -> u32 {
 pub fn store_word(m: &mut NoRa32, addr: u32, v: u32) {
     run(m);
 
-    if addr == 0 {
+    if addr == regs::COMMAND {
         m.gpu.command_fifo.push(v);
     } else {
         warn!("Unhandled GPU write at {:x}", addr);
@@ -359,6 +396,9 @@ pub fn run(m: &mut NoRa32) {
 enum CommandState {
     Idle,
     MatrixSetComponent { mindex: u8, i: u8, j: u8 },
+    FogColor,
+    FogNear,
+    FogFar { near: Fp32 },
     TriangleZ { vindex: u8, gouraud: bool },
     TriangleYX { vindex: u8, gouraud: bool },
     TriangleRgb { vindex: u8, gouraud: bool },
@@ -398,6 +438,41 @@ impl fmt::LowerExp for Fp32 {
     }
 }
 
+/// Distance fog parameters. The fog is applied per-vertex based on the distance of the vertex
+/// from the eye after the draw matrix transform (i.e. the `w` clip coordinate).
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Fog {
+    /// Color the vertices fade to
+    color: [u8; 3],
+    /// Distance at which the fog starts to apply
+    near: f32,
+    /// Distance at which the vertex color is completely replaced by the fog color. If `far` is
+    /// not greater than `near` fog is disabled.
+    far: f32,
+}
+
+impl Fog {
+    fn new() -> Fog {
+        Fog {
+            color: [0; 3],
+            near: 0.,
+            far: 0.,
+        }
+    }
+
+    pub fn color(&self) -> [u8; 3] {
+        self.color
+    }
+
+    pub fn near(&self) -> f32 {
+        self.near
+    }
+
+    pub fn far(&self) -> f32 {
+        self.far
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 struct Vertex {
     color: [u8; 3],
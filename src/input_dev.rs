@@ -1,10 +1,15 @@
 use super::{CPU_FREQ, CycleCounter, NoRa32, fifo::Fifo, irq, sync};
+use nr32_common::regs::input_dev as regs;
 
+mod gamepad;
+mod memcard;
 mod touchscreen;
 
+pub use touchscreen::Touch;
+
 pub struct InputDev {
-    tx_fifo: Fifo<16, u8>,
-    rx_fifo: Fifo<16, u8>,
+    tx_fifo: Fifo<{ regs::FIFO_DEPTH }, u8>,
+    rx_fifo: Fifo<{ regs::FIFO_DEPTH }, u8>,
     /// IRQ high on TX empty
     tx_complete_irq: bool,
     /// Selected port
@@ -17,6 +22,10 @@ pub struct InputDev {
     seq: u8,
     /// Touchscreen interface
     touchscreen: touchscreen::TouchScreen,
+    /// Gamepad interface
+    gamepad: gamepad::Gamepad,
+    /// Memory card interface
+    memcard: memcard::MemoryCard,
 }
 
 impl InputDev {
@@ -30,12 +39,22 @@ impl InputDev {
             clk_count: 0,
             seq: 0,
             touchscreen: touchscreen::TouchScreen::new(),
+            gamepad: gamepad::Gamepad::new(),
+            memcard: memcard::MemoryCard::new(),
         }
     }
 
     pub fn touchscreen_mut(&mut self) -> &mut touchscreen::TouchScreen {
         &mut self.touchscreen
     }
+
+    pub fn gamepad_mut(&mut self) -> &mut gamepad::Gamepad {
+        &mut self.gamepad
+    }
+
+    pub fn memcard_mut(&mut self) -> &mut memcard::MemoryCard {
+        &mut self.memcard
+    }
 }
 
 pub fn run(m: &mut NoRa32) {
@@ -58,9 +77,17 @@ pub fn run(m: &mut NoRa32) {
 
         let mut rx_byte = 0xff;
 
-        if m.input_dev.port == 0 && m.input_dev.clk_div >= 512 {
-            // Touchscreen port
-            rx_byte &= m.input_dev.touchscreen.xmit(m.input_dev.seq, b);
+        match m.input_dev.port {
+            regs::PORT_TOUCHSCREEN if m.input_dev.clk_div >= 512 => {
+                rx_byte &= m.input_dev.touchscreen.xmit(m.input_dev.seq, b);
+            }
+            regs::PORT_GAMEPAD => {
+                rx_byte &= m.input_dev.gamepad.xmit(m.input_dev.seq, b);
+            }
+            regs::PORT_MEMCARD => {
+                rx_byte &= m.input_dev.memcard.xmit(m.input_dev.seq, b);
+            }
+            _ => (),
         }
 
         m.input_dev.rx_fifo.push(rx_byte);
@@ -84,25 +111,24 @@ pub fn run(m: &mut NoRa32) {
 pub fn store_word(m: &mut NoRa32, addr: u32, val: u32) {
     run(m);
 
-    match addr >> 2 {
-        // CONF
-        0 => {
-            if val & 1 != 0 {
+    match addr {
+        regs::CONF => {
+            if val & regs::CONF_CLEAR != 0 {
                 m.input_dev.tx_fifo.clear();
                 m.input_dev.rx_fifo.clear();
                 m.input_dev.port = PORT_SELECT_NONE;
                 m.input_dev.seq = 0;
             }
-            m.input_dev.tx_complete_irq = (val & (1 << 1)) != 0;
-            m.input_dev.clk_div = (((val >> 16) & 0xffff) + 1) as CycleCounter;
+            m.input_dev.tx_complete_irq = (val & regs::CONF_TX_COMPLETE_IRQ) != 0;
+            m.input_dev.clk_div =
+                (((val >> regs::CONF_CLK_DIV_SHIFT) & 0xffff) + 1) as CycleCounter;
             // We run at CPU_FREQ / 16
             m.input_dev.clk_div *= 16;
             // We only care about full byte transmits
             m.input_dev.clk_div *= 8;
             m.input_dev.clk_count = 0;
         }
-        // PORT
-        1 => {
+        regs::PORT => {
             let port = val as u8;
 
             if port != m.input_dev.port {
@@ -110,8 +136,7 @@ pub fn store_word(m: &mut NoRa32, addr: u32, val: u32) {
                 m.input_dev.seq = 0;
             }
         }
-        // TX
-        2 => {
+        regs::TX_RX => {
             let b = val as u8;
 
             m.input_dev.tx_fifo.push(b);
@@ -125,9 +150,8 @@ pub fn store_word(m: &mut NoRa32, addr: u32, val: u32) {
 pub fn load_word(m: &mut NoRa32, addr: u32) -> u32 {
     run(m);
 
-    match addr >> 2 {
-        // RX
-        2 => u32::from(m.input_dev.rx_fifo.pop().unwrap_or(0xff)),
+    match addr {
+        regs::TX_RX => u32::from(m.input_dev.rx_fifo.pop().unwrap_or(0xff)),
         _ => !0,
     }
 }
@@ -137,6 +161,15 @@ trait InputDevice {
     fn xmit(&mut self, seq: u8, tx_byte: u8) -> u8;
 }
 
+/// Run a whole transaction with `dev`, returning the received bytes
+#[cfg(test)]
+fn xfer(dev: &mut impl InputDevice, tx: &[u8]) -> Vec<u8> {
+    tx.iter()
+        .enumerate()
+        .map(|(seq, &b)| dev.xmit(seq as u8, b))
+        .collect()
+}
+
 const PORT_SELECT_NONE: u8 = 0xff;
 
 const IDEVSYNC: sync::SyncToken = sync::SyncToken::InputDev;
@@ -0,0 +1,109 @@
+use super::InputDevice;
+use nr32_common::regs::input_dev as regs;
+
+pub struct Gamepad {
+    /// Pressed buttons (`regs::PAD_*`)
+    buttons: u16,
+    /// Position of the left and right analog sticks, None for a digital pad
+    sticks: Option<[[u8; 2]; 2]>,
+    /// Buttons latched at the beginning of a transmission
+    latched_buttons: u16,
+    /// Sticks latched at the beginning of a transmission
+    latched_sticks: Option<[[u8; 2]; 2]>,
+    /// Set to true if a transaction is in progress
+    selected: bool,
+}
+
+impl Gamepad {
+    pub fn new() -> Gamepad {
+        Gamepad {
+            buttons: 0,
+            sticks: None,
+            latched_buttons: 0,
+            latched_sticks: None,
+            selected: false,
+        }
+    }
+
+    pub fn set_state(&mut self, buttons: u16, sticks: Option<[[u8; 2]; 2]>) {
+        self.buttons = buttons;
+        self.sticks = sticks;
+    }
+}
+
+impl InputDevice for Gamepad {
+    fn xmit(&mut self, seq: u8, tx_byte: u8) -> u8 {
+        match (seq, tx_byte, self.selected, self.latched_sticks) {
+            (0, b'P', _, _) => {
+                self.selected = true;
+                0xff
+            }
+            (1, b'S', true, _) => {
+                self.latched_buttons = self.buttons;
+                self.latched_sticks = self.sticks;
+
+                if self.sticks.is_some() {
+                    regs::PAD_ID_ANALOG
+                } else {
+                    regs::PAD_ID_DIGITAL
+                }
+            }
+            // Buttons are active low
+            (2, _, true, _) => !(self.latched_buttons >> 8) as u8,
+            (3, _, true, _) => !self.latched_buttons as u8,
+            (4..=7, _, true, Some(sticks)) => {
+                let axis = (seq - 4) as usize;
+
+                sticks[axis / 2][axis % 2]
+            }
+            _ => {
+                self.selected = false;
+                0xff
+            }
+        }
+    }
+}
+
+#[test]
+fn test_gamepad() {
+    use super::xfer;
+
+    let mut pad = Gamepad::new();
+
+    pad.set_state(regs::PAD_UP | regs::PAD_START, None);
+
+    assert_eq!(
+        xfer(&mut pad, b"PS\0\0\0\0"),
+        [0xff, regs::PAD_ID_DIGITAL, 0xfb, 0xfe, 0xff, 0xff]
+    );
+
+    pad.set_state(regs::PAD_A, Some([[0x80, 0x00], [0xff, 0x12]]));
+
+    // The state is latched by the read command
+    let mut rx = Vec::new();
+    for (seq, &b) in b"PS\0\0\0\0\0\0\0".iter().enumerate() {
+        rx.push(pad.xmit(seq as u8, b));
+
+        if seq == 1 {
+            pad.set_state(0, None);
+        }
+    }
+
+    assert_eq!(
+        rx,
+        [
+            0xff,
+            regs::PAD_ID_ANALOG,
+            0xff,
+            0xef,
+            0x80,
+            0x00,
+            0xff,
+            0x12,
+            0xff
+        ]
+    );
+
+    // Unknown command
+    assert_eq!(xfer(&mut pad, b"PX\0\0"), [0xff; 4]);
+}
@@ -0,0 +1,156 @@
+use super::InputDevice;
+use nr32_common::regs::input_dev as regs;
+
+pub struct MemoryCard {
+    /// Contents of the card, `regs::MEMCARD_SIZE` bytes
+    data: Vec<u8>,
+    /// Set to true when `data` is modified, cleared when the frontend fetches it
+    dirty: bool,
+    /// Command of the current transmission
+    command: u8,
+    /// Offset of the block being accessed in `data`
+    offset: usize,
+    /// Data received by a write command, only committed once the whole block has been received
+    write_buf: [u8; regs::MEMCARD_BLOCK_LEN],
+    /// Set to true if a transaction is in progress
+    selected: bool,
+}
+
+impl MemoryCard {
+    pub fn new() -> MemoryCard {
+        MemoryCard {
+            data: vec![0xff; regs::MEMCARD_SIZE],
+            dirty: false,
+            command: 0,
+            offset: 0,
+            write_buf: [0xff; regs::MEMCARD_BLOCK_LEN],
+            selected: false,
+        }
+    }
+
+    /// Replace the contents of the card. Missing data reads as erased, extra data is ignored.
+    pub fn load(&mut self, data: &[u8]) {
+        let n = data.len().min(regs::MEMCARD_SIZE);
+
+        if data.len() > regs::MEMCARD_SIZE {
+            warn!(
+                "Memory card image is too large ({}B), truncating to {}B",
+                data.len(),
+                regs::MEMCARD_SIZE
+            );
+        }
+
+        self.data[..n].copy_from_slice(&data[..n]);
+        self.data[n..].fill(0xff);
+        self.dirty = false;
+    }
+
+    /// Returns the contents of the card if they've been modified since the last call
+    pub fn take_changes(&mut self) -> Option<&[u8]> {
+        if self.dirty {
+            self.dirty = false;
+            Some(&self.data)
+        } else {
+            None
+        }
+    }
+}
+
+impl InputDevice for MemoryCard {
+    fn xmit(&mut self, seq: u8, tx_byte: u8) -> u8 {
+        const DATA_START: u8 = 4;
+        const DATA_END: u8 = DATA_START + regs::MEMCARD_BLOCK_LEN as u8;
+
+        match (seq, tx_byte, self.selected, self.command) {
+            (0, b'C', _, _) => {
+                self.selected = true;
+                0xff
+            }
+            (1, b'R' | b'W', true, _) => {
+                self.command = tx_byte;
+                b'c'
+            }
+            (2, _, true, _) => {
+                self.offset = usize::from(tx_byte) << 8;
+                0xff
+            }
+            (3, _, true, _) => {
+                let block = self.offset | usize::from(tx_byte);
+
+                if block >= regs::MEMCARD_BLOCK_COUNT {
+                    self.selected = false;
+                } else {
+                    self.offset = block * regs::MEMCARD_BLOCK_LEN;
+                }
+
+                0xff
+            }
+            (DATA_START..DATA_END, _, true, b'R') => {
+                self.data[self.offset + usize::from(seq - DATA_START)]
+            }
+            (DATA_START..DATA_END, _, true, b'W') => {
+                self.write_buf[usize::from(seq - DATA_START)] = tx_byte;
+                0xff
+            }
+            (DATA_END, _, true, _) => {
+                if self.command == b'W' {
+                    self.data[self.offset..][..regs::MEMCARD_BLOCK_LEN]
+                        .copy_from_slice(&self.write_buf);
+                    self.dirty = true;
+                }
+
+                self.selected = false;
+                b'k'
+            }
+            _ => {
+                self.selected = false;
+                0xff
+            }
+        }
+    }
+}
+
+#[test]
+fn test_memcard() {
+    use super::xfer;
+
+    let mut card = MemoryCard::new();
+
+    let write = b"CW\x01\x02abcdefgh\0";
+    let read = b"CR\x01\x02\0\0\0\0\0\0\0\0\0";
+
+    assert_eq!(
+        xfer(&mut card, read)[4..],
+        [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, b'k']
+    );
+    assert!(card.take_changes().is_none());
+
+    // Interrupted write, nothing is committed
+    xfer(&mut card, &write[..8]);
+    assert!(card.take_changes().is_none());
+
+    assert_eq!(
+        xfer(&mut card, write),
+        [
+            0xff, b'c', 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, b'k'
+        ]
+    );
+    assert_eq!(xfer(&mut card, read)[4..], *b"abcdefghk");
+
+    let data = card.take_changes().unwrap();
+    assert_eq!(&data[0x102 * 8..][..8], b"abcdefgh");
+    assert!(card.take_changes().is_none());
+
+    // Out of range
+    assert_eq!(xfer(&mut card, b"CW\xff\xffabcdefgh\0")[12], 0xff);
+    assert!(card.take_changes().is_none());
+
+    let mut image = vec![0; 16];
+    image[8..].copy_from_slice(b"12345678");
+    card.load(&image);
+    assert_eq!(
+        xfer(&mut card, b"CR\x00\x01\0\0\0\0\0\0\0\0\0")[4..],
+        *b"12345678k"
+    );
+    assert_eq!(xfer(&mut card, read)[4..12], [0xff; 8]);
+}
@@ -1,10 +1,21 @@
 use super::InputDevice;
+use nr32_common::regs::input_dev as regs;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Touch {
+    pub id: u8,
+    pub position: [u16; 2],
+    /// 1 (lightest) to 0xff, 0 if unknown
+    pub pressure: u8,
+}
 
 pub struct TouchScreen {
-    /// Touchscreen input (None if there's no input currently)
-    position: Option<[u16; 2]>,
-    /// Position latched at the beginning of a transmission
-    latched_position: [u16; 2],
+    /// Current touch points, at most `regs::TOUCH_MAX_POINTS`
+    touches: Vec<Touch>,
+    /// Touch points latched at the beginning of a transmission
+    latched_touches: Vec<Touch>,
+    /// Command of the current transmission
+    command: u8,
     /// Set to true if a transaction is in progress
     selected: bool,
 }
@@ -12,32 +23,66 @@ pub struct TouchScreen {
 impl TouchScreen {
     pub fn new() -> TouchScreen {
         TouchScreen {
-            position: None,
-            latched_position: [0xffff; 2],
+            touches: Vec::new(),
+            latched_touches: Vec::new(),
+            command: 0,
             selected: false,
         }
     }
 
-    pub fn set_touch(&mut self, position: Option<[u16; 2]>) {
-        self.position = position;
+    /// Set the current touch points. The first one is reported by the single touch command.
+    pub fn set_touches(&mut self, touches: &[Touch]) {
+        let n = touches.len().min(regs::TOUCH_MAX_POINTS);
+
+        self.touches.clear();
+        self.touches.extend_from_slice(&touches[..n]);
+    }
+
+    /// Position returned by the single touch command
+    fn latched_position(&self) -> [u16; 2] {
+        self.latched_touches
+            .first()
+            .map(|t| t.position)
+            .unwrap_or([0xffff, 0xffff])
     }
 }
 
 impl InputDevice for TouchScreen {
     fn xmit(&mut self, seq: u8, tx_byte: u8) -> u8 {
-        match (seq, tx_byte, self.selected) {
-            (0, b'T', _) => {
+        match (seq, tx_byte, self.selected, self.command) {
+            (0, b'T', _, _) => {
                 self.selected = true;
                 0xff
             }
-            (1, b'S', true) => {
-                self.latched_position = self.position.unwrap_or([0xffff, 0xffff]);
-                b'a'
+            (1, b'S' | b'M', true, _) => {
+                self.command = tx_byte;
+                self.latched_touches.clone_from(&self.touches);
+
+                if tx_byte == b'S' {
+                    b'a'
+                } else {
+                    self.latched_touches.len() as u8
+                }
+            }
+            (2, _, true, b'S') => (self.latched_position()[0] >> 8) as u8,
+            (3, _, true, b'S') => self.latched_position()[0] as u8,
+            (4, _, true, b'S') => (self.latched_position()[1] >> 8) as u8,
+            (5, _, true, b'S') => self.latched_position()[1] as u8,
+            (2.., _, true, b'M')
+                if usize::from(seq - 2) < self.latched_touches.len() * regs::TOUCH_POINT_LEN =>
+            {
+                let off = usize::from(seq - 2);
+                let t = &self.latched_touches[off / regs::TOUCH_POINT_LEN];
+
+                match off % regs::TOUCH_POINT_LEN {
+                    0 => t.id,
+                    1 => (t.position[0] >> 8) as u8,
+                    2 => t.position[0] as u8,
+                    3 => (t.position[1] >> 8) as u8,
+                    4 => t.position[1] as u8,
+                    _ => t.pressure,
+                }
             }
-            (2, _, true) => (self.latched_position[0] >> 8) as u8,
-            (3, _, true) => self.latched_position[0] as u8,
-            (4, _, true) => (self.latched_position[1] >> 8) as u8,
-            (5, _, true) => self.latched_position[1] as u8,
             _ => {
                 self.selected = false;
                 0xff
@@ -45,3 +90,49 @@ impl InputDevice for TouchScreen {
         }
     }
 }
+
+#[test]
+fn test_touchscreen_multi() {
+    use super::xfer;
+
+    let mut ts = TouchScreen::new();
+
+    assert_eq!(xfer(&mut ts, b"TM\0"), [0xff, 0, 0xff]);
+    assert_eq!(
+        xfer(&mut ts, b"TS\0\0\0\0"),
+        [0xff, b'a', 0xff, 0xff, 0xff, 0xff]
+    );
+
+    ts.set_touches(&[
+        Touch {
+            id: 7,
+            position: [0x123, 0x45],
+            pressure: 0,
+        },
+        Touch {
+            id: 2,
+            position: [0x400, 0x3ff],
+            pressure: 0x80,
+        },
+        // Dropped, there are too many points
+        Touch {
+            id: 3,
+            position: [0, 0],
+            pressure: 0,
+        },
+    ]);
+
+    assert_eq!(
+        xfer(
+            &mut ts,
+            &[b'T', b'M', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+        ),
+        [
+            0xff, 2, 7, 0x01, 0x23, 0x00, 0x45, 0, 2, 0x04, 0x00, 0x03, 0xff, 0x80, 0xff
+        ]
+    );
+    assert_eq!(
+        xfer(&mut ts, b"TS\0\0\0\0"),
+        [0xff, b'a', 0x01, 0x23, 0x00, 0x45]
+    );
+}
@@ -1,18 +1,9 @@
 //! A very simple interrupt controller
 
 use crate::{NoRa32, cpu};
+use nr32_common::regs::irq as regs;
 
-/// All interrupts supported by the system (minus the MTI interrupt that's directly handled by the
-/// CPU
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
-pub enum Interrupt {
-    /// Triggered by the GPU every time a frame has completed
-    VSync = 0,
-    /// Triggered when the input device interface's IRQ line has a rising edge
-    InputDev = 1,
-    /// Triggered when a DMA transfer is complete
-    DmaDone = 2,
-}
+pub use regs::Interrupt;
 
 pub struct Controller {
     pending: u32,
@@ -35,16 +26,15 @@ fn refresh_cpu_irq(m: &mut NoRa32) {
 /// Trigger the given `irq`. All interrupts are edge-driven, so this should only be called when the
 /// device's IRQ line goes from 0 to 1.
 pub fn trigger(m: &mut NoRa32, irq: Interrupt) {
-    m.irq.pending |= 1 << (irq as u32);
+    m.irq.pending |= irq.mask();
     refresh_cpu_irq(m);
 }
 
 pub fn store_word(m: &mut NoRa32, off: u32, v: u32) {
     match off {
         // Acknowledge
-        0x0 => m.irq.pending &= !v,
-        // Enable
-        0x4 => m.irq.enabled = v,
+        regs::PENDING => m.irq.pending &= !v,
+        regs::ENABLED => m.irq.enabled = v,
         _ => (),
     }
 
@@ -53,9 +43,8 @@ pub fn store_word(m: &mut NoRa32, off: u32, v: u32) {
 
 pub fn load_word(m: &mut NoRa32, off: u32) -> u32 {
     match off {
-        0x0 => m.irq.pending,
-        // Enable
-        0x4 => m.irq.enabled,
+        regs::PENDING => m.irq.pending,
+        regs::ENABLED => m.irq.enabled,
         _ => !0,
     }
 }
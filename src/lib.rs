@@ -16,9 +16,12 @@ mod systimer;
 use cfg_if::cfg_if;
 use js_sys::{Array, Function};
 use nr32_common::memmap;
+use nr32_common::regs;
 use std::panic;
 use wasm_bindgen::prelude::*;
 
+pub use spu::AudioRecording;
+
 #[wasm_bindgen(start)]
 fn main() {
     panic::set_hook(Box::new(console_error_panic_hook::hook));
@@ -57,7 +60,11 @@ impl NoRa32 {
             cpu: cpu::Cpu::new(),
             sync: sync::Synchronizer::new(),
             rom: Vec::new(),
-            ram: Box::new([0; (memmap::RAM.len >> 2) as usize]),
+            // Allocated directly on the heap to avoid putting 2MiB on the stack
+            ram: vec![0; (memmap::RAM.len >> 2) as usize]
+                .into_boxed_slice()
+                .try_into()
+                .unwrap(),
             gpu: gpu::Gpu::new(),
             systimer: systimer::Timer::new(),
             irq: irq::Controller::new(),
@@ -87,19 +94,70 @@ impl NoRa32 {
         self.callbacks.js_output_audio_samples = Some(cb);
     }
 
+    /// `touches` is an array of touch points `[id, x, y, pressure]`, with the pressure in [0.0, 1.0]
+    /// or undefined if it's unknown. `pad_buttons` is a combination of `regs::input_dev::PAD_*`.
+    /// `pad_sticks` is either undefined for a digital pad or an array `[lx, ly, rx, ry]` with values
+    /// in [-1.0, 1.0].
     #[wasm_bindgen]
-    pub fn set_inputs(&mut self, touch_pos: JsValue) {
-        let mut touch = None;
-        if touch_pos.is_array() {
-            let arr: Array = touch_pos.into();
+    pub fn set_inputs(&mut self, touches: JsValue, pad_buttons: u16, pad_sticks: JsValue) {
+        let mut touch_points = Vec::new();
+        if touches.is_array() {
+            let arr: Array = touches.into();
+
+            for t in arr.iter().filter(|t| t.is_array()) {
+                let t: Array = t.into();
+
+                let pressure = match t.get(3).as_f64() {
+                    // 0 is reserved for unknown pressure
+                    Some(p) => (p.clamp(0., 1.) * 254.).round() as u8 + 1,
+                    None => 0,
+                };
+
+                touch_points.push(input_dev::Touch {
+                    // Only the low bits matter, the frontend IDs only have to be unique
+                    id: t.get(0).as_f64().unwrap_or(0.) as u32 as u8,
+                    position: [
+                        t.get(1).as_f64().unwrap_or(0.) as u16,
+                        t.get(2).as_f64().unwrap_or(0.) as u16,
+                    ],
+                    pressure,
+                });
+            }
+        }
+
+        self.input_dev.touchscreen_mut().set_touches(&touch_points);
+
+        let mut sticks = None;
+        if pad_sticks.is_array() {
+            let arr: Array = pad_sticks.into();
 
-            let x = arr.get(0).as_f64().unwrap_or(0.) as u16;
-            let y = arr.get(1).as_f64().unwrap_or(0.) as u16;
+            let axis = |i| {
+                let v = arr.get(i).as_f64().unwrap_or(0.).clamp(-1., 1.);
 
-            touch = Some([x, y]);
+                ((v + 1.) * 127.5).round() as u8
+            };
+
+            sticks = Some([[axis(0), axis(1)], [axis(2), axis(3)]]);
         }
 
-        self.input_dev.touchscreen_mut().set_touch(touch);
+        self.input_dev.gamepad_mut().set_state(pad_buttons, sticks);
+    }
+
+    /// Replace the contents of the memory card, typically with an image saved by a previous
+    /// session
+    #[wasm_bindgen]
+    pub fn load_memory_card(&mut self, image: &[u8]) {
+        self.input_dev.memcard_mut().load(image);
+    }
+
+    /// Returns the image of the memory card if it's been written to since the last call, so that
+    /// the frontend can persist it
+    #[wasm_bindgen]
+    pub fn take_memory_card_changes(&mut self) -> Option<Vec<u8>> {
+        self.input_dev
+            .memcard_mut()
+            .take_changes()
+            .map(|d| d.to_vec())
     }
 
     #[wasm_bindgen]
@@ -189,12 +247,9 @@ impl NoRa32 {
         }
 
         if let Some(off) = memmap::DEBUG.contains(addr) {
-            if off == 0x20 {
-                // Shutdown
-                if v >> 16 == 0xd1e {
-                    info!("Shutdown requested with code {}", v & 0xffff);
-                    self.run = false;
-                }
+            if off == regs::debug::SHUTDOWN && v >> 16 == regs::debug::SHUTDOWN_MAGIC {
+                info!("Shutdown requested with code {}", v & 0xffff);
+                self.run = false;
             }
             return;
         }
@@ -240,8 +295,7 @@ impl NoRa32 {
         }
 
         if let Some(off) = memmap::DEBUG.contains(addr) {
-            if off == 0x10 {
-                // Debug console
+            if off == regs::debug::PUTCHAR {
                 if v == b'\n' {
                     self.flush_debug_console();
                 } else {
@@ -282,10 +336,18 @@ impl NoRa32 {
             return irq::load_word(self, off);
         }
 
+        if let Some(off) = memmap::DMA.contains(addr) {
+            return dma::load_word(self, off);
+        }
+
         if let Some(off) = memmap::GPU.contains(addr) {
             return gpu::load_word(self, off);
         }
 
+        if let Some(off) = memmap::SPU.contains(addr) {
+            return spu::load_word(self, off);
+        }
+
         panic!("Can't load word from {:x} {:?}", addr, self.cpu);
     }
 
@@ -341,6 +403,43 @@ impl NoRa32 {
     }
 }
 
+/// API for native frontends, not exported to JS
+impl NoRa32 {
+    /// Start recording the audio output. If `per_voice` is true the output of every SPU voice is
+    /// also recorded individually. Any recording already running is discarded.
+    pub fn start_audio_recording(&mut self, per_voice: bool) {
+        spu::run(self);
+        self.spu.start_recording(per_voice);
+    }
+
+    /// Stop the current audio recording and return it. Returns None if no recording was running.
+    pub fn stop_audio_recording(&mut self) -> Option<AudioRecording> {
+        spu::run(self);
+        self.spu.stop_recording()
+    }
+
+    /// Write the SPU register at offset `reg`. Together with `run_spu_cycles` this lets tools drive
+    /// the SPU on its own, without any code running on the CPU (e.g. to render scores offline).
+    pub fn spu_store(&mut self, reg: u32, val: u32) {
+        spu::store_word(self, reg, val);
+    }
+
+    /// Read the SPU register at offset `reg`
+    pub fn spu_load(&mut self, reg: u32) -> u32 {
+        spu::load_word(self, reg)
+    }
+
+    /// Run the SPU for `cycles` 44.1kHz audio cycles without running the CPU. The output can be
+    /// retrieved with an audio recording.
+    pub fn run_spu_cycles(&mut self, cycles: usize) {
+        for _ in 0..cycles {
+            spu::run_audio_cycle(self);
+        }
+
+        self.spu.clear_samples();
+    }
+}
+
 impl Default for NoRa32 {
     fn default() -> Self {
         Self::new()
@@ -361,15 +460,22 @@ impl Callbacks {
         matrices_f32: &[[[f32; 4]; 4]],
         attribs_i16: &[i16],
         attribs_u8: &[u8],
+        fog: &gpu::Fog,
     ) {
         if let Some(ref js_draw_triangles) = self.js_draw_triangles {
-            let args = Array::new_with_length(5);
+            let args = Array::new_with_length(8);
+
+            let [r, g, b] = fog.color();
+            let fog_color = u32::from(r) | (u32::from(g) << 8) | (u32::from(b) << 16);
 
             args.set(0, JsValue::from(matrices_f32.as_ptr()));
             args.set(1, JsValue::from(matrices_f32.len()));
             args.set(2, JsValue::from(attribs_i16.as_ptr()));
             args.set(3, JsValue::from(attribs_u8.as_ptr()));
             args.set(4, JsValue::from(attribs_i16.len() / 3));
+            args.set(5, JsValue::from(fog_color));
+            args.set(6, JsValue::from(fog.near()));
+            args.set(7, JsValue::from(fog.far()));
 
             js_draw_triangles.apply(&JsValue::NULL, &args).unwrap();
         }
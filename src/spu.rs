@@ -1,7 +1,14 @@
-use super::{CPU_FREQ, CycleCounter, NoRa32, fifo::Fifo, sync};
+use super::{CPU_FREQ, CycleCounter, NoRa32, dma::DmaResult, fifo::Fifo, irq, sync};
+use nr32_common::regs::spu as regs;
 use std::ops::{Index, IndexMut};
 
 mod fir;
+mod noise;
+mod recording;
+mod reverb;
+mod stream;
+
+pub use recording::AudioRecording;
 
 /// Offset into the SPU internal ram
 type RamIndex = u32;
@@ -14,9 +21,40 @@ pub struct Spu {
     /// The 24 voices
     voices: [Voice; 24],
     /// Main volume left
-    volume_left: i16,
+    volume_left: Volume,
     /// Main volume right
-    volume_right: i16,
+    volume_right: Volume,
+    /// Bitmask of the voices whose output is sent to the reverb unit
+    reverb_voices: u32,
+    /// Reverb unit
+    reverb: reverb::Reverb,
+    /// Bitmask of the voices outputting noise instead of their ADPCM samples
+    noise_voices: u32,
+    /// Noise generator
+    noise: noise::Noise,
+    /// Bitmask of the voices whose step is modulated by the previous voice's output
+    pitch_mod_voices: u32,
+    /// Bitmask of the voices that reached an ADPCM block with the end flag set since they were
+    /// last started
+    endx: u32,
+    /// If true `irq_index` is compared against the RAM reads of the voice decoders
+    irq_addr_enabled: bool,
+    /// Index in SPU RAM that triggers an IRQ when read by a voice
+    irq_index: RamIndex,
+    /// Bitmask of the voices that trigger an IRQ when they reach an end block
+    irq_end_voices: u32,
+    /// PCM streaming channel
+    stream: stream::Stream,
+    /// True if the capture buffers are being written
+    capture_enabled: bool,
+    /// Start of the capture area in SPU RAM
+    capture_base: RamIndex,
+    /// Current write position in each capture buffer
+    capture_pos: RamIndex,
+    /// Recording of the output for the frontend, if one is running
+    recording: Option<AudioRecording>,
+    /// Pending IRQ sources (see the `IRQ_*` constants). The IRQ line is high while this is non-0.
+    irq_status: u32,
     /// Output buffer containing samples @44.1kHz. The left/right stereo samples are interleaved.
     samples: Vec<i16>,
 }
@@ -52,8 +90,23 @@ impl Spu {
                 Voice::new(),
                 Voice::new(),
             ],
-            volume_left: 0,
-            volume_right: 0,
+            volume_left: Volume::new(),
+            volume_right: Volume::new(),
+            reverb_voices: 0,
+            reverb: reverb::Reverb::new(),
+            noise_voices: 0,
+            noise: noise::Noise::new(),
+            pitch_mod_voices: 0,
+            endx: 0,
+            irq_addr_enabled: false,
+            irq_index: 0,
+            irq_end_voices: 0,
+            stream: stream::Stream::new(),
+            capture_enabled: false,
+            capture_base: 0,
+            capture_pos: 0,
+            recording: None,
+            irq_status: 0,
             samples: Vec::new(),
         }
     }
@@ -66,6 +119,34 @@ impl Spu {
         self.samples.clear();
     }
 
+    /// Start recording the output. If `per_voice` is true the output of every voice is also
+    /// recorded individually. Any recording already running is discarded.
+    pub fn start_recording(&mut self, per_voice: bool) {
+        self.recording = Some(AudioRecording::new(per_voice));
+    }
+
+    pub fn stop_recording(&mut self) -> Option<AudioRecording> {
+        self.recording.take()
+    }
+
+    /// Returns a bitmask of the voices that are currently running
+    fn active_voices(&self) -> u32 {
+        self.voices
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| v.adsr.state != AdsrState::Stopped)
+            .fold(0, |mask, (i, _)| mask | (1 << i))
+    }
+
+    pub fn ram_load(&mut self) -> u16 {
+        let idx = self.ram_ptr as usize;
+        let v = self.ram[idx];
+
+        self.ram_ptr = self.ram_ptr.wrapping_add(1) % (SPU_RAM_SIZE as u32);
+
+        v
+    }
+
     pub fn ram_store(&mut self, v: u16) {
         let idx = self.ram_ptr as usize;
         self.ram[idx] = v;
@@ -101,8 +182,41 @@ pub fn run(m: &mut NoRa32) {
         run_audio_cycle(m);
     }
 
-    // We don't have async events in the SPU since we don't have IRQs at this point.
-    sync::next_event(m, SPUSYNC, CPU_FREQ);
+    schedule_next_event(m);
+}
+
+/// Schedule the next refresh, making sure that we'll raise the IRQs in time. The delay is relative
+/// to the last sync so it may be early by up to one audio cycle, which is harmless.
+fn schedule_next_event(m: &mut NoRa32) {
+    // We can't easily predict when a voice will reach the IRQ address or an end block, so if IRQs
+    // are enabled we just refresh often enough to keep the latency low.
+    let mut next_event = if m.spu.irq_addr_enabled || m.spu.irq_end_voices != 0 {
+        IRQ_POLL_CYCLES
+    } else {
+        CPU_FREQ
+    };
+
+    if let Some(c) = m.spu.stream.cycles_to_irq() {
+        next_event = next_event.min(c as CycleCounter * AUDIO_DIVIDER);
+    }
+
+    sync::next_event(m, SPUSYNC, next_event);
+}
+
+/// Flag the IRQ sources in `sources` as pending, triggering the SPU interrupt if none were already
+/// pending.
+fn raise_irq(m: &mut NoRa32, sources: u32) {
+    if sources == 0 {
+        return;
+    }
+
+    let was_high = m.spu.irq_status != 0;
+
+    m.spu.irq_status |= sources;
+
+    if !was_high {
+        irq::trigger(m, irq::Interrupt::Spu);
+    }
 }
 
 /// Called at 44.1kHz, must generate two new samples (left/right)
@@ -110,46 +224,142 @@ pub fn run_audio_cycle(m: &mut NoRa32) {
     let mut left = 0i32;
     let mut right = 0i32;
 
+    let mut reverb_left = 0i32;
+    let mut reverb_right = 0i32;
+
+    m.spu.noise.run_cycle();
+
     for voice in 0..24 {
         let [l, r] = run_voice_cycle(m, voice);
 
+        m.spu[voice].run_volume_cycle();
+
+        if let Some(rec) = &mut m.spu.recording {
+            rec.push_voice(voice, [l, r]);
+        }
+
         left += l;
         right += r;
+
+        if m.spu.reverb_voices & (1 << voice) != 0 {
+            reverb_left += l;
+            reverb_right += r;
+        }
+    }
+
+    let [rl, rr] = m
+        .spu
+        .reverb
+        .run_cycle(&mut m.spu.ram, [reverb_left, reverb_right]);
+
+    left += rl;
+    right += rr;
+
+    let ([sl, sr], stream_irq) = m.spu.stream.run_cycle();
+
+    left += sl;
+    right += sr;
+
+    if stream_irq {
+        raise_irq(m, regs::IRQ_STREAM);
     }
 
-    m.spu
-        .samples
-        .push(left.clamp(i16::MIN as i32, i16::MAX as i32) as i16);
-    m.spu
-        .samples
-        .push(right.clamp(i16::MIN as i32, i16::MAX as i32) as i16);
+    let left = (left * i32::from(m.spu.volume_left.level())) >> 15;
+    let right = (right * i32::from(m.spu.volume_right.level())) >> 15;
+
+    m.spu.volume_left.run_cycle();
+    m.spu.volume_right.run_cycle();
+
+    let left = left.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+    let right = right.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+
+    m.spu.samples.push(left);
+    m.spu.samples.push(right);
+
+    if let Some(rec) = &mut m.spu.recording {
+        rec.push_mix(left, right);
+    }
+
+    run_capture(m, left, right);
+}
+
+/// Write the current output to the capture buffers in SPU RAM. Like on the PSX, the capture area
+/// contains 4 consecutive ring buffers of `CAPTURE_BUFFER_LEN` samples: main output left, main
+/// output right, voice 1 and voice 3 (post-envelope, pre-volume).
+fn run_capture(m: &mut NoRa32, left: i16, right: i16) {
+    if !m.spu.capture_enabled {
+        return;
+    }
+
+    let samples = [left, right, m.spu[1].output, m.spu[3].output];
+
+    let mut irq = 0;
+
+    for (i, s) in samples.into_iter().enumerate() {
+        let idx = m.spu.capture_base + (i as RamIndex) * CAPTURE_BUFFER_LEN + m.spu.capture_pos;
+        let idx = idx % (SPU_RAM_SIZE as u32);
+
+        m.spu.ram[idx as usize] = s as u16;
+
+        if m.spu.irq_addr_enabled && m.spu.irq_index == idx {
+            irq |= regs::IRQ_ADDRESS;
+        }
+    }
+
+    m.spu.capture_pos = (m.spu.capture_pos + 1) % CAPTURE_BUFFER_LEN;
+
+    raise_irq(m, irq);
 }
 
 pub fn run_voice_cycle(m: &mut NoRa32, voice: usize) -> [i32; 2] {
     if m.spu[voice].adsr.state == AdsrState::Stopped {
+        m.spu[voice].output = 0;
         return [0, 0];
     }
 
     run_voice_decoder(m, voice);
 
+    let noise = m.spu.noise_voices & (1 << voice) != 0;
+    let noise_level = m.spu.noise.level();
+
+    // Voice 0 has no previous voice so it can't be modulated
+    let modulator = if voice > 0 && m.spu.pitch_mod_voices & (1 << voice) != 0 {
+        Some(m.spu[voice - 1].output)
+    } else {
+        None
+    };
+
     let v = &mut m.spu[voice];
 
     if v.adsr.state == AdsrState::Stopped {
         // We already checked above but it's possible that the voice decoder would have reached the
         // end of the ADPCM sample and changed the ADSR state.
+        v.output = 0;
         return [0, 0];
     }
 
-    let raw_sample = v.next_raw_sample();
+    // In noise mode the ADPCM decoder keeps running so that the end and loop flags still apply
+    let raw_sample = if noise {
+        i32::from(noise_level)
+    } else {
+        v.next_raw_sample()
+    };
 
     let sample = v.apply_envelope(raw_sample);
 
-    let left = ((v.volume_left as i32) * sample) >> 15;
-    let right = ((v.volume_right as i32) * sample) >> 15;
+    v.output = sample.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+
+    let left = (i32::from(v.volume_left.level()) * sample) >> 15;
+    let right = (i32::from(v.volume_right.level()) * sample) >> 15;
 
     v.run_envelope_cycle();
 
-    v.step();
+    let step_length = match modulator {
+        Some(modulator) => v.modulated_step_length(modulator),
+        None => v.step_length,
+    };
+
+    v.step(step_length);
 
     [left, right]
 }
@@ -158,19 +368,33 @@ pub fn run_voice_cycle(m: &mut NoRa32, voice: usize) -> [i32; 2] {
 pub fn run_voice_decoder(m: &mut NoRa32, voice: usize) {
     let v = &mut m.spu.voices[voice];
 
+    // IRQ index to check the RAM reads against, if enabled
+    let irq_index = m.spu.irq_addr_enabled.then_some(m.spu.irq_index);
+    let mut irq = 0;
+
     while v.decoder_fifo.len() < 11 {
         if v.cur_index & 7 == 0 {
             // New block
             if v.block_header.end() {
+                m.spu.endx |= 1 << voice;
+
+                if m.spu.irq_end_voices & (1 << voice) != 0 {
+                    irq |= regs::IRQ_VOICE_END;
+                }
+
                 if v.block_header.is_loop() {
                     v.cur_index = v.loop_index;
                 } else {
                     // Disable voice
                     v.adsr.stop();
-                    return;
+                    break;
                 }
             }
 
+            if irq_index == Some(v.cur_index) {
+                irq |= regs::IRQ_ADDRESS;
+            }
+
             let header = m.spu.ram[v.cur_index as usize];
             v.block_header = AdpcmHeader(header);
             if v.block_header.loop_start() {
@@ -179,70 +403,208 @@ pub fn run_voice_decoder(m: &mut NoRa32, voice: usize) {
             v.inc_index();
         }
 
+        if irq_index == Some(v.cur_index) {
+            irq |= regs::IRQ_ADDRESS;
+        }
+
         let encoded = m.spu.ram[v.cur_index as usize];
         v.inc_index();
         v.decode(encoded);
     }
+
+    raise_irq(m, irq);
+}
+
+pub fn load_word(m: &mut NoRa32, addr: u32) -> u32 {
+    run(m);
+
+    match addr {
+        regs::MAIN_VOLUME => Volume::pack(&m.spu.volume_left, &m.spu.volume_right),
+        regs::REVERB_VOICES => m.spu.reverb_voices,
+        regs::RAM_ADDR => m.spu.ram_ptr << 1,
+        regs::NOISE_VOICES => m.spu.noise_voices,
+        regs::PITCH_MOD_VOICES => m.spu.pitch_mod_voices,
+        regs::ENDX => m.spu.endx,
+        regs::ACTIVE_VOICES => m.spu.active_voices(),
+        regs::IRQ_ADDR => m.spu.irq_index << 1,
+        regs::IRQ_END_VOICES => m.spu.irq_end_voices,
+        regs::IRQ_CONTROL => u32::from(m.spu.irq_addr_enabled),
+        regs::IRQ_STATUS => m.spu.irq_status,
+        regs::STREAM_CONTROL => m.spu.stream.control(),
+        regs::STREAM_VOLUME => {
+            let (l, r) = m.spu.stream.volume();
+            (l as u16 as u32) << 16 | (r as u16 as u32)
+        }
+        regs::STREAM_LEVEL => m.spu.stream.fill_level() as u32,
+        regs::CAPTURE_CONTROL => u32::from(m.spu.capture_enabled),
+        regs::CAPTURE_BASE => m.spu.capture_base << 1,
+        regs::CAPTURE_POS => m.spu.capture_pos,
+        regs::MAIN_VOLUME_SWEEP => Volume::pack_sweep(&m.spu.volume_left, &m.spu.volume_right),
+        regs::VOICE_BASE.. => {
+            let voice = ((addr - regs::VOICE_BASE) / regs::VOICE_STRIDE) as usize;
+            if voice >= 24 {
+                warn!("Read from unknown voice {voice}");
+                return !0;
+            }
+
+            let v = &m.spu[voice];
+
+            match (addr - regs::VOICE_BASE) % regs::VOICE_STRIDE {
+                regs::VOICE_STEP => u32::from(v.step_length),
+                regs::VOICE_START_BLOCK => v.start_index >> 3,
+                regs::VOICE_VOLUME => Volume::pack(&v.volume_left, &v.volume_right),
+                regs::VOICE_ADSR => v.adsr.config.0,
+                regs::VOICE_ADSR_LEVEL => v.adsr.level as u16 as u32,
+                regs::VOICE_ADSR_STATE => v.adsr.state as u32,
+                regs::VOICE_CUR_ADDR => v.cur_index << 1,
+                regs::VOICE_LOOP_BLOCK => v.loop_index >> 3,
+                regs::VOICE_VOLUME_SWEEP => Volume::pack_sweep(&v.volume_left, &v.volume_right),
+                n => {
+                    warn!("Read from unknown SPU register {voice}.{n:x}");
+                    !0
+                }
+            }
+        }
+        n => {
+            warn!("Read from unknown SPU register {n:x}");
+            !0
+        }
+    }
+}
+
+/// Read a word from SPU RAM at `ram_ptr` for the DMA
+pub fn dma_load(m: &mut NoRa32) -> u32 {
+    let lo = m.spu.ram_load();
+    let hi = m.spu.ram_load();
+
+    u32::from(lo) | (u32::from(hi) << 16)
+}
+
+/// Write a word to SPU RAM at `ram_ptr` for the DMA
+pub fn dma_store(m: &mut NoRa32, v: u32) {
+    m.spu.ram_store(v as u16);
+    m.spu.ram_store((v >> 16) as u16);
+}
+
+/// Push a stereo frame to the stream FIFO for the DMA
+pub fn dma_stream_store(m: &mut NoRa32, v: u32) -> DmaResult {
+    if m.spu.stream.is_full() {
+        // Wait for a few frames to be consumed
+        DmaResult::Stall(AUDIO_DIVIDER * 16)
+    } else {
+        m.spu.stream.push(v);
+        schedule_next_event(m);
+        DmaResult::Ok
+    }
 }
 
 pub fn store_word(m: &mut NoRa32, addr: u32, val: u32) {
     run(m);
 
-    match addr >> 2 {
-        0 => {
-            m.spu.volume_left = (val >> 16) as i16;
-            m.spu.volume_right = val as i16;
+    match addr {
+        regs::MAIN_VOLUME => {
+            m.spu.volume_left.set_level((val >> 16) as i16);
+            m.spu.volume_right.set_level(val as i16);
         }
-        // Start
-        1 => {
+        regs::VOICE_ON => {
             for voice in 0..24 {
                 if val & (1 << voice) != 0 {
                     m.spu[voice].start();
+                    m.spu.endx &= !(1 << voice);
                 }
             }
         }
-        // Release
-        2 => {
+        regs::VOICE_OFF => {
             for voice in 0..24 {
                 if val & (1 << voice) != 0 {
                     m.spu[voice].release();
                 }
             }
         }
-        4 => {
+        regs::REVERB_VOICES => m.spu.reverb_voices = val & 0xff_ffff,
+        regs::RAM_ADDR => {
             m.spu.ram_ptr = (val >> 1) & !1;
         }
-
-        5 => {
+        regs::RAM_DATA => {
             m.spu.ram_store(val as u16);
             m.spu.ram_store((val >> 16) as u16);
         }
-        0x40.. => {
-            let voice = (((addr - 0x100) >> 5) & 0x1f) as usize;
+        regs::NOISE_VOICES => m.spu.noise_voices = val & 0xff_ffff,
+        regs::PITCH_MOD_VOICES => m.spu.pitch_mod_voices = val & 0xff_ffff,
+        regs::NOISE_CLOCK => m.spu.noise.set_clock(val),
+        regs::IRQ_ADDR => m.spu.irq_index = (val >> 1) % (SPU_RAM_SIZE as u32),
+        regs::IRQ_END_VOICES => {
+            m.spu.irq_end_voices = val & 0xff_ffff;
+            schedule_next_event(m);
+        }
+        regs::IRQ_CONTROL => {
+            m.spu.irq_addr_enabled = val & regs::IRQ_CONTROL_ADDR_ENABLE != 0;
+            schedule_next_event(m);
+        }
+        // Acknowledge
+        regs::IRQ_STATUS => m.spu.irq_status &= !val,
+        regs::REVERB_CONTROL => m
+            .spu
+            .reverb
+            .set_enabled(val & regs::REVERB_CONTROL_ENABLE != 0),
+        regs::REVERB_BASE => m.spu.reverb.set_base(val),
+        regs::REVERB_VOLUME => m.spu.reverb.set_volume((val >> 16) as i16, val as i16),
+        n @ regs::REVERB_CONFIG..=regs::REVERB_CONFIG_LAST => m
+            .spu
+            .reverb
+            .set_regs(((n - regs::REVERB_CONFIG) >> 2) as usize, val),
+        regs::STREAM_CONTROL => {
+            m.spu.stream.set_control(val);
+            schedule_next_event(m);
+        }
+        regs::STREAM_DATA => {
+            m.spu.stream.push(val);
+            schedule_next_event(m);
+        }
+        regs::STREAM_VOLUME => m.spu.stream.set_volume((val >> 16) as i16, val as i16),
+        regs::CAPTURE_CONTROL => m.spu.capture_enabled = val & regs::CAPTURE_CONTROL_ENABLE != 0,
+        regs::CAPTURE_BASE => {
+            m.spu.capture_base = (val >> 1) % (SPU_RAM_SIZE as u32);
+            m.spu.capture_pos = 0;
+        }
+        regs::MAIN_VOLUME_SWEEP => {
+            m.spu.volume_left.set_sweep((val >> 16) as u16);
+            m.spu.volume_right.set_sweep(val as u16);
+        }
+        regs::VOICE_BASE.. => {
+            let voice = ((addr - regs::VOICE_BASE) / regs::VOICE_STRIDE) as usize;
             if voice >= 24 {
-                panic!("Unknown voice {voice}");
+                warn!("Write to unknown voice {voice}");
+                return;
             }
 
             let v = &mut m.spu[voice];
 
-            match (addr >> 2) & 7 {
-                0 => {
+            match (addr - regs::VOICE_BASE) % regs::VOICE_STRIDE {
+                regs::VOICE_STEP => {
                     v.step_length = (val & 0x3fff) as u16;
                 }
-                1 => {
+                regs::VOICE_START_BLOCK => {
                     v.start_index = (val << 3) % SPU_RAM_SIZE as u32;
                 }
-                2 => {
-                    v.volume_left = (val >> 16) as i16;
-                    v.volume_right = val as i16;
+                regs::VOICE_LOOP_BLOCK => {
+                    v.loop_index = (val << 3) % SPU_RAM_SIZE as u32;
+                }
+                regs::VOICE_VOLUME => {
+                    v.volume_left.set_level((val >> 16) as i16);
+                    v.volume_right.set_level(val as i16);
                 }
-                3 => {
+                regs::VOICE_ADSR => {
                     v.adsr.set_raw(val);
                 }
-                4 => {
+                regs::VOICE_ADSR_LEVEL => {
                     v.adsr.set_level(val as i16);
                 }
-                n => panic!("Unknown SPU register {voice}.{n}"),
+                regs::VOICE_VOLUME_SWEEP => {
+                    v.volume_left.set_sweep((val >> 16) as u16);
+                    v.volume_right.set_sweep(val as u16);
+                }
+                n => warn!("Write to unknown SPU register {voice}.{n:x}"),
             }
         }
         n => panic!("Unknown SPU register {n:x}"),
@@ -250,10 +612,10 @@ pub fn store_word(m: &mut NoRa32, addr: u32, val: u32) {
 }
 
 pub struct Voice {
-    /// Voice volume left. Negative volume inverts the phase.
-    volume_left: i16,
-    /// Voice volume right. Negative volume inverts the phase.
-    volume_right: i16,
+    /// Voice volume left
+    volume_left: Volume,
+    /// Voice volume right
+    volume_right: Volume,
     /// Attack Decay Sustain Release envelope
     adsr: Adsr,
     /// This value configures how fast the samples are played on this voice, which effectively
@@ -276,13 +638,15 @@ pub struct Voice {
     last_samples: [i16; 2],
     /// FIFO containing the last decoded samples for this voice
     decoder_fifo: Fifo<16, i16>,
+    /// Last output sample (post-ADSR, pre-volume), used for pitch modulation
+    output: i16,
 }
 
 impl Voice {
     fn new() -> Voice {
         Voice {
-            volume_left: 0,
-            volume_right: 0,
+            volume_left: Volume::new(),
+            volume_right: Volume::new(),
             adsr: Adsr::new(),
             step_length: 0,
             phase: 0,
@@ -292,6 +656,7 @@ impl Voice {
             block_header: AdpcmHeader(0),
             last_samples: [0; 2],
             decoder_fifo: Fifo::new(),
+            output: 0,
         }
     }
 
@@ -310,8 +675,18 @@ impl Voice {
         self.cur_index = self.cur_index.wrapping_add(1) % (SPU_RAM_SIZE as u32);
     }
 
-    fn step(&mut self) {
-        let step = self.phase + self.step_length;
+    /// Returns `step_length` modulated by `modulator`: the step is multiplied by a factor between
+    /// 0.0 (for `i16::MIN`) and ~2.0 (for `i16::MAX`)
+    fn modulated_step_length(&self, modulator: i16) -> u16 {
+        let factor = i32::from(modulator) + 0x8000;
+
+        let step = (i32::from(self.step_length) * factor) >> 15;
+
+        step.min(0x3fff) as u16
+    }
+
+    fn step(&mut self, step_length: u16) {
+        let step = self.phase + step_length;
 
         self.phase = step & 0xfff;
 
@@ -367,6 +742,12 @@ impl Voice {
         fir::filter(phase, samples)
     }
 
+    /// Run one cycle for the volume sweeps
+    fn run_volume_cycle(&mut self) {
+        self.volume_left.run_cycle();
+        self.volume_right.run_cycle();
+    }
+
     /// Run one cycle for the ADSR envelope function
     fn run_envelope_cycle(&mut self) {
         self.adsr.run_cycle();
@@ -443,29 +824,12 @@ impl Adsr {
     fn run_cycle(&mut self) {
         let params = &self.params[self.state as usize];
 
-        let div_step = params.compute_divider_step(self.level);
-        debug_assert!(div_step > 0);
-
-        // `div_step`'s max value should be 0x8000, so the addition should never overflow
-        debug_assert!(div_step <= 0x8000);
-        self.divider += div_step;
-
-        if self.divider < 0x8000 {
+        let Some(level) = params.run_cycle(&mut self.divider, self.level) else {
             // We haven't reached the next step yet.
             return;
-        }
-
-        // Next step reached
-        self.divider = 0;
-
-        let level_step = params.compute_level_step(self.level);
-
-        self.level = self.level.wrapping_add(level_step);
+        };
 
-        if self.level < 0 {
-            // Overflow or underflow
-            self.level = if level_step > 0 { i16::max_value() } else { 0 };
-        }
+        self.level = level;
 
         if self.state == AdsrState::Decay && self.level <= self.sustain_level {
             self.state = AdsrState::Sustain;
@@ -524,6 +888,62 @@ impl EnvelopeParams {
         }
     }
 
+    /// Parameters for an envelope with `shift` and `step` values as found in the config registers.
+    /// The envelope increases by default, `decrease` inverts the step. `exp` selects the
+    /// exponential mode (which is only "pseudo-exponential" when increasing).
+    fn from_config(shift: u32, step: u32, exp: bool, decrease: bool) -> EnvelopeParams {
+        let raw_step = 7 - (step & 3);
+
+        let step = if decrease { !raw_step } else { raw_step };
+
+        let (div_step, lvl_step) = EnvelopeParams::steps(shift, step as i8);
+
+        let mode = if exp {
+            if decrease {
+                EnvelopeMode::Exponential
+            } else {
+                EnvelopeMode::smooth_mode(raw_step, div_step, lvl_step)
+            }
+        } else {
+            EnvelopeMode::Linear
+        };
+
+        EnvelopeParams {
+            divider_step: div_step,
+            level_step: lvl_step,
+            mode,
+        }
+    }
+
+    /// Advance the envelope by one cycle. `divider` counts until the next step, if the step is
+    /// reached the new level is returned.
+    fn run_cycle(&self, divider: &mut u16, level: i16) -> Option<i16> {
+        let div_step = self.compute_divider_step(level);
+        debug_assert!(div_step > 0);
+
+        // `div_step`'s max value should be 0x8000, so the addition should never overflow
+        debug_assert!(div_step <= 0x8000);
+        *divider += div_step;
+
+        if *divider < 0x8000 {
+            return None;
+        }
+
+        // Next step reached
+        *divider = 0;
+
+        let level_step = self.compute_level_step(level);
+
+        let level = level.wrapping_add(level_step);
+
+        if level < 0 {
+            // Overflow or underflow
+            Some(if level_step > 0 { i16::MAX } else { 0 })
+        } else {
+            Some(level)
+        }
+    }
+
     fn compute_divider_step(&self, cur_level: i16) -> u16 {
         match self.mode {
             EnvelopeMode::SmoothUp { divider_step, .. } => {
@@ -662,20 +1082,22 @@ impl AdsrConfig {
 
     fn sustain_params(self) -> EnvelopeParams {
         let shift = (self.0 >> 14) & 0x1f;
-        let raw_step = 7 - ((self.0 >> 19) & 3);
+        let step = (self.0 >> 19) & 3;
         let exp = (self.0 >> 12) & 1 != 0;
         let inv_step = (self.0 >> 13) & 1 != 0;
 
-        let step = if inv_step { !raw_step } else { raw_step };
+        EnvelopeParams::from_config(shift, step, exp, inv_step)
+    }
+
+    fn release_params(self) -> EnvelopeParams {
+        let shift = (self.0 >> 26) & 0x1f;
+        let step = -8;
+        let exp = (self.0 >> 25) & 1 != 0;
 
         let (div_step, lvl_step) = EnvelopeParams::steps(shift, step as i8);
 
         let mode = if exp {
-            if inv_step {
-                EnvelopeMode::Exponential
-            } else {
-                EnvelopeMode::smooth_mode(raw_step, div_step, lvl_step)
-            }
+            EnvelopeMode::Exponential
         } else {
             EnvelopeMode::Linear
         };
@@ -686,37 +1108,127 @@ impl AdsrConfig {
             mode,
         }
     }
+}
 
-    fn release_params(self) -> EnvelopeParams {
-        let shift = (self.0 >> 26) & 0x1f;
-        let step = -8;
-        let exp = (self.0 >> 25) & 1 != 0;
+/// Volume level, either fixed or sweeping
+struct Volume {
+    /// Current level. In sweep mode this is the magnitude of the volume, the phase is set by
+    /// `invert`.
+    level: i16,
+    /// Sweep parameters, None if the volume is fixed
+    sweep: Option<EnvelopeParams>,
+    /// Divider used to count until the next sweep step
+    divider: u16,
+    /// In sweep mode, inverts the phase of the output
+    invert: bool,
+    /// Sweep config register value
+    config: VolumeSweepConfig,
+}
 
-        let (div_step, lvl_step) = EnvelopeParams::steps(shift, step as i8);
+impl Volume {
+    fn new() -> Volume {
+        Volume {
+            level: 0,
+            sweep: None,
+            divider: 0,
+            invert: false,
+            config: VolumeSweepConfig(0),
+        }
+    }
 
-        let mode = if exp {
-            EnvelopeMode::Exponential
+    /// Returns the current signed volume level. Negative volume inverts the phase.
+    fn level(&self) -> i16 {
+        if self.invert { -self.level } else { self.level }
+    }
+
+    /// Set the volume level. In sweep mode this sets the starting point of the sweep (the sign is
+    /// ignored).
+    fn set_level(&mut self, level: i16) {
+        self.level = if self.sweep.is_some() {
+            level.saturating_abs()
         } else {
-            EnvelopeMode::Linear
+            level
         };
+    }
 
-        EnvelopeParams {
-            divider_step: div_step,
-            level_step: lvl_step,
-            mode,
+    fn set_sweep(&mut self, config: u16) {
+        let config = VolumeSweepConfig(config);
+
+        self.config = config;
+        self.divider = 0;
+
+        if config.enabled() {
+            // Keep the current level as the starting point
+            self.level = self.level().saturating_abs();
+            self.invert = config.invert();
+            self.sweep = Some(config.params());
+        } else {
+            self.level = self.level();
+            self.invert = false;
+            self.sweep = None;
+        }
+    }
+
+    fn run_cycle(&mut self) {
+        if let Some(params) = &self.sweep
+            && let Some(level) = params.run_cycle(&mut self.divider, self.level)
+        {
+            self.level = level;
         }
     }
+
+    /// Pack the levels of a left/right volume pair the way they're laid out in the registers
+    fn pack(left: &Volume, right: &Volume) -> u32 {
+        (u32::from(left.level() as u16) << 16) | u32::from(right.level() as u16)
+    }
+
+    /// Pack the sweep configs of a left/right volume pair the way they're laid out in the
+    /// registers
+    fn pack_sweep(left: &Volume, right: &Volume) -> u32 {
+        (u32::from(left.config.0) << 16) | u32::from(right.config.0)
+    }
+}
+
+/// Volume sweep register config
+///
+/// Layout:
+///   [15]    - Sweep enable
+///   [14]    - Sweep exponential
+///   [13]    - Sweep decrease
+///   [12]    - Invert phase
+///   [6:2]   - Sweep shift
+///   [1:0]   - Sweep step
+#[derive(Copy, Clone)]
+struct VolumeSweepConfig(u16);
+
+impl VolumeSweepConfig {
+    fn enabled(self) -> bool {
+        self.0 & regs::SWEEP_ENABLE != 0
+    }
+
+    fn invert(self) -> bool {
+        self.0 & regs::SWEEP_INVERT != 0
+    }
+
+    fn params(self) -> EnvelopeParams {
+        let shift = u32::from((self.0 >> regs::SWEEP_SHIFT_SHIFT) & 0x1f);
+        let step = u32::from(self.0 & 3);
+        let exp = self.0 & regs::SWEEP_EXPONENTIAL != 0;
+        let decrease = self.0 & regs::SWEEP_DECREASE != 0;
+
+        EnvelopeParams::from_config(shift, step, exp, decrease)
+    }
 }
 
 /// Possible ADSR states
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 enum AdsrState {
-    Attack,
-    Decay,
-    Sustain,
-    Release,
+    Attack = regs::ADSR_STATE_ATTACK as isize,
+    Decay = regs::ADSR_STATE_DECAY as isize,
+    Sustain = regs::ADSR_STATE_SUSTAIN as isize,
+    Release = regs::ADSR_STATE_RELEASE as isize,
     /// Voice isn't running
-    Stopped,
+    Stopped = regs::ADSR_STATE_STOPPED as isize,
 }
 
 /// The first two bytes of a 16-byte ADPCM block
@@ -777,5 +1289,71 @@ const AUDIO_DIVIDER: CycleCounter = (CPU_FREQ + 44_100 / 2) / 44_100;
 
 const SPUSYNC: sync::SyncToken = sync::SyncToken::Spu;
 
+/// CPU cycles needed to transfer a word to or from SPU RAM through the DMA. SPU RAM is on a 16bit
+/// bus with 4 cycles per access.
+pub const DMA_WORD_CYCLES: CycleCounter = 8;
+
+/// How often we refresh the SPU when IRQs are enabled (about every 0.7ms)
+const IRQ_POLL_CYCLES: CycleCounter = AUDIO_DIVIDER * 32;
+
+/// Length of each capture buffer, in samples
+const CAPTURE_BUFFER_LEN: RamIndex = 512;
+
 /// SPU RAM size in multiple of 16bit words
-const SPU_RAM_SIZE: usize = 256 * 1024;
+const SPU_RAM_SIZE: usize = (regs::RAM_SIZE / 2) as usize;
+
+#[test]
+fn test_volume_sweep() {
+    let mut vol = Volume::new();
+
+    vol.set_level(-0x1000);
+    assert_eq!(vol.level(), -0x1000);
+
+    // Linear increase, fastest rate, inverted phase
+    vol.set_sweep(regs::SWEEP_ENABLE | regs::SWEEP_INVERT);
+    assert_eq!(vol.level(), -0x1000);
+
+    vol.run_cycle();
+    assert_eq!(vol.level(), -(0x1000 + (7 << 11)));
+
+    for _ in 0..100 {
+        vol.run_cycle();
+    }
+    assert_eq!(vol.level(), -i16::MAX);
+
+    // Linear decrease, fastest rate
+    vol.set_sweep(regs::SWEEP_ENABLE | regs::SWEEP_DECREASE);
+    assert_eq!(vol.level(), i16::MAX);
+
+    for _ in 0..100 {
+        vol.run_cycle();
+    }
+    assert_eq!(vol.level(), 0);
+
+    // Fixed volume again
+    vol.set_level(0x1234);
+    vol.set_sweep(0);
+    vol.run_cycle();
+    assert_eq!(vol.level(), 0x1234);
+    assert_eq!(Volume::pack(&vol, &Volume::new()), 0x1234_0000);
+}
+
+#[test]
+fn test_unknown_voice_regs() {
+    let mut m = NoRa32::new();
+
+    store_word(&mut m, regs::voice_reg(3, regs::VOICE_STEP), 0x1234);
+
+    // Voices past the last one and the unused offsets of each voice block are ignored
+    store_word(&mut m, regs::voice_reg(24, regs::VOICE_STEP), 0);
+    store_word(&mut m, regs::voice_reg(27, regs::VOICE_STEP), 0);
+    store_word(&mut m, regs::voice_reg(3, 0x24), 0);
+    store_word(&mut m, regs::voice_reg(3, regs::VOICE_STRIDE - 4), 0);
+
+    assert_eq!(load_word(&mut m, regs::voice_reg(24, regs::VOICE_STEP)), !0);
+    assert_eq!(load_word(&mut m, regs::voice_reg(3, 0x24)), !0);
+    assert_eq!(
+        load_word(&mut m, regs::voice_reg(3, regs::VOICE_STEP)),
+        0x1234
+    );
+}
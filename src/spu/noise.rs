@@ -0,0 +1,93 @@
+//! Noise generator, shared by all the voices in noise mode.
+//!
+//! This is the PSX SPU's LFSR-based generator: the frequency is configured with a shift and a step
+//! value, every time the timer underflows a new bit is shifted into the noise level.
+
+pub struct Noise {
+    /// Current noise level, used as the raw sample by the voices in noise mode
+    level: i16,
+    /// Counts down until the next LFSR shift
+    timer: i32,
+    /// Frequency shift (0 to 15), higher values yield higher frequencies
+    shift: u8,
+    /// Frequency step (4 to 7)
+    step: u8,
+}
+
+impl Noise {
+    pub fn new() -> Noise {
+        Noise {
+            level: 0,
+            timer: 0,
+            shift: 0,
+            step: 4,
+        }
+    }
+
+    /// Set the noise clock configuration.
+    ///
+    /// Layout:
+    ///   [5:2] - Shift
+    ///   [1:0] - Step
+    pub fn set_clock(&mut self, val: u32) {
+        self.shift = ((val >> 2) & 0xf) as u8;
+        self.step = (val & 3) as u8 + 4;
+    }
+
+    pub fn level(&self) -> i16 {
+        self.level
+    }
+
+    /// Run one 44.1kHz audio cycle
+    pub fn run_cycle(&mut self) {
+        self.timer -= i32::from(self.step);
+
+        if self.timer >= 0 {
+            return;
+        }
+
+        let l = self.level as u16;
+        let parity = ((l >> 15) ^ (l >> 12) ^ (l >> 11) ^ (l >> 10) ^ 1) & 1;
+
+        self.level = ((l << 1) | parity) as i16;
+
+        // The timer may need to be reloaded twice for the highest frequencies
+        let reload = 0x2_0000 >> self.shift;
+        self.timer += reload;
+        if self.timer < 0 {
+            self.timer += reload;
+        }
+    }
+}
+
+#[test]
+fn test_noise_clock() {
+    let mut noise = Noise::new();
+
+    // Fastest clock: one new bit per cycle
+    noise.set_clock(0x3f);
+
+    let mut levels = Vec::new();
+    for _ in 0..4 {
+        noise.run_cycle();
+        levels.push(noise.level());
+    }
+
+    assert_eq!(levels, [1, 3, 7, 15]);
+
+    // Slowest clock: 0x2_0000 / 4 cycles between each bit
+    noise.set_clock(0);
+
+    let mut changes = 0;
+    let mut prev = noise.level();
+    for _ in 0..(0x2_0000 / 4 * 3) {
+        noise.run_cycle();
+
+        if noise.level() != prev {
+            changes += 1;
+            prev = noise.level();
+        }
+    }
+
+    assert_eq!(changes, 3);
+}
@@ -0,0 +1,124 @@
+//! Recording of the SPU output, mainly meant for native frontends and tests: the mixed output and
+//! optionally each voice's individual output are accumulated until the recording is stopped and
+//! can then be dumped as WAV files.
+
+use std::io::{self, Write};
+
+pub struct AudioRecording {
+    /// Mixed output, interleaved left/right samples @44.1kHz
+    mix: Vec<i16>,
+    /// Output of each voice post-volume and pre-mix, interleaved left/right samples @44.1kHz.
+    /// Empty if per-voice recording wasn't requested.
+    voices: Vec<Vec<i16>>,
+}
+
+impl AudioRecording {
+    pub(super) fn new(per_voice: bool) -> AudioRecording {
+        let voices = if per_voice {
+            vec![Vec::new(); 24]
+        } else {
+            Vec::new()
+        };
+
+        AudioRecording {
+            mix: Vec::new(),
+            voices,
+        }
+    }
+
+    pub(super) fn push_mix(&mut self, left: i16, right: i16) {
+        self.mix.push(left);
+        self.mix.push(right);
+    }
+
+    pub(super) fn push_voice(&mut self, voice: usize, [left, right]: [i32; 2]) {
+        if let Some(v) = self.voices.get_mut(voice) {
+            v.push(left.clamp(i16::MIN as i32, i16::MAX as i32) as i16);
+            v.push(right.clamp(i16::MIN as i32, i16::MAX as i32) as i16);
+        }
+    }
+
+    /// Mixed output, interleaved left/right samples @44.1kHz
+    pub fn mix(&self) -> &[i16] {
+        &self.mix
+    }
+
+    /// Output of `voice` before mixing, interleaved left/right samples @44.1kHz. Returns None if
+    /// per-voice recording wasn't requested.
+    pub fn voice(&self, voice: usize) -> Option<&[i16]> {
+        self.voices.get(voice).map(|v| v.as_slice())
+    }
+
+    /// Dump the mixed output as a 16bit stereo 44.1kHz WAV file
+    pub fn write_wav<W: Write>(&self, w: W) -> io::Result<()> {
+        write_wav(w, &self.mix)
+    }
+
+    /// Dump the output of `voice` as a 16bit stereo 44.1kHz WAV file. Returns an error if
+    /// per-voice recording wasn't requested.
+    pub fn write_voice_wav<W: Write>(&self, voice: usize, w: W) -> io::Result<()> {
+        match self.voice(voice) {
+            Some(samples) => write_wav(w, samples),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Voice {voice} wasn't recorded"),
+            )),
+        }
+    }
+}
+
+/// Write interleaved stereo `samples` as a 16bit 44.1kHz WAV file
+fn write_wav<W: Write>(mut w: W, samples: &[i16]) -> io::Result<()> {
+    const CHANNELS: u16 = 2;
+    const SAMPLE_RATE: u32 = 44_100;
+    const BYTES_PER_SAMPLE: u16 = 2;
+
+    let data_len = (samples.len() * usize::from(BYTES_PER_SAMPLE)) as u32;
+    let block_align = CHANNELS * BYTES_PER_SAMPLE;
+
+    w.write_all(b"RIFF")?;
+    w.write_all(&(36 + data_len).to_le_bytes())?;
+    w.write_all(b"WAVE")?;
+
+    w.write_all(b"fmt ")?;
+    w.write_all(&16u32.to_le_bytes())?;
+    // PCM
+    w.write_all(&1u16.to_le_bytes())?;
+    w.write_all(&CHANNELS.to_le_bytes())?;
+    w.write_all(&SAMPLE_RATE.to_le_bytes())?;
+    w.write_all(&(SAMPLE_RATE * u32::from(block_align)).to_le_bytes())?;
+    w.write_all(&block_align.to_le_bytes())?;
+    w.write_all(&(BYTES_PER_SAMPLE * 8).to_le_bytes())?;
+
+    w.write_all(b"data")?;
+    w.write_all(&data_len.to_le_bytes())?;
+
+    for s in samples {
+        w.write_all(&s.to_le_bytes())?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_write_wav() {
+    let mut rec = AudioRecording::new(false);
+
+    rec.push_mix(1, -1);
+    rec.push_mix(0x1234, 0);
+
+    let mut wav = Vec::new();
+    rec.write_wav(&mut wav).unwrap();
+
+    assert_eq!(wav.len(), 44 + 8);
+    assert_eq!(&wav[0..4], b"RIFF");
+    assert_eq!(&wav[4..8], &44u32.to_le_bytes());
+    assert_eq!(&wav[22..24], &2u16.to_le_bytes());
+    assert_eq!(&wav[24..28], &44_100u32.to_le_bytes());
+    assert_eq!(&wav[36..40], b"data");
+    assert_eq!(&wav[40..44], &8u32.to_le_bytes());
+    assert_eq!(&wav[44..], &[1, 0, 0xff, 0xff, 0x34, 0x12, 0, 0]);
+
+    assert!(rec.voice(0).is_none());
+    assert!(rec.write_voice_wav(0, Vec::new()).is_err());
+}
@@ -0,0 +1,299 @@
+//! Reverb unit, modeled after the PSX SPU's.
+//!
+//! The reverb works on a ring buffer (the "work area") in SPU RAM which stretches from `base` to
+//! the end of the RAM. All the buffer offsets in the configuration are in multiples of 8 bytes
+//! (4 halfwords), like on the PSX, so the PSX reverb presets can be used as-is.
+
+use super::{RamIndex, SPU_RAM_SIZE};
+
+pub struct Reverb {
+    /// True if the reverb unit is running. When disabled the work area isn't touched and can be
+    /// used to store sample data.
+    enabled: bool,
+    /// Start of the work area
+    base: RamIndex,
+    /// Current position in the work area, relative to `base`
+    pos: RamIndex,
+    /// Reverb output volume left
+    volume_left: i16,
+    /// Reverb output volume right
+    volume_right: i16,
+    /// Configuration registers, see the `R_*` constants for the layout
+    regs: [u16; 32],
+    /// Sum of the input samples since the last reverb step
+    input: [i32; 2],
+    /// Output of the last reverb step
+    output: [i32; 2],
+    /// The reverb runs at 22.05kHz so we only step every other audio cycle
+    odd_cycle: bool,
+}
+
+impl Reverb {
+    pub fn new() -> Reverb {
+        Reverb {
+            enabled: false,
+            base: 0,
+            pos: 0,
+            volume_left: 0,
+            volume_right: 0,
+            regs: [0; 32],
+            input: [0; 2],
+            output: [0; 2],
+            odd_cycle: false,
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+
+        if !enabled {
+            self.input = [0; 2];
+            self.output = [0; 2];
+        }
+    }
+
+    /// Set the start of the work area. `base` is a byte offset in SPU RAM and is truncated to 8
+    /// bytes.
+    pub fn set_base(&mut self, base: u32) {
+        self.base = ((base >> 1) & !3) % (SPU_RAM_SIZE as u32);
+        self.pos = 0;
+    }
+
+    pub fn set_volume(&mut self, left: i16, right: i16) {
+        self.volume_left = left;
+        self.volume_right = right;
+    }
+
+    /// Store the configuration register pair at `index` (the low halfword goes to register
+    /// `index * 2`, the high halfword to `index * 2 + 1`)
+    pub fn set_regs(&mut self, index: usize, val: u32) {
+        self.regs[index * 2] = val as u16;
+        self.regs[index * 2 + 1] = (val >> 16) as u16;
+    }
+
+    /// Run one 44.1kHz audio cycle: `input` is the mix of all the voices with reverb enabled.
+    /// Returns the reverb output to be mixed with the dry signal.
+    pub fn run_cycle(&mut self, ram: &mut [u16], input: [i32; 2]) -> [i32; 2] {
+        if !self.enabled {
+            return [0, 0];
+        }
+
+        self.input[0] += input[0];
+        self.input[1] += input[1];
+
+        self.odd_cycle = !self.odd_cycle;
+
+        if !self.odd_cycle {
+            let [l, r] = self.input;
+            self.input = [0; 2];
+
+            // Average the two input samples to get the 22.05kHz input
+            self.step(ram, [l >> 1, r >> 1]);
+        }
+
+        let [l, r] = self.output;
+
+        [
+            (l * i32::from(self.volume_left)) >> 15,
+            (r * i32::from(self.volume_right)) >> 15,
+        ]
+    }
+
+    /// Run one 22.05kHz reverb step
+    fn step(&mut self, ram: &mut [u16], input: [i32; 2]) {
+        let lin = mul(input[0], self.vol(R_V_LIN));
+        let rin = mul(input[1], self.vol(R_V_RIN));
+
+        let v_iir = self.vol(R_V_IIR);
+        let v_wall = self.vol(R_V_WALL);
+
+        // Same side reflection
+        let lsame = self.load(ram, R_M_LSAME, -1);
+        let v = mul(
+            lin + mul(self.load(ram, R_D_LSAME, 0), v_wall) - lsame,
+            v_iir,
+        ) + lsame;
+        self.store(ram, R_M_LSAME, v);
+
+        let rsame = self.load(ram, R_M_RSAME, -1);
+        let v = mul(
+            rin + mul(self.load(ram, R_D_RSAME, 0), v_wall) - rsame,
+            v_iir,
+        ) + rsame;
+        self.store(ram, R_M_RSAME, v);
+
+        // Different side reflection
+        let ldiff = self.load(ram, R_M_LDIFF, -1);
+        let v = mul(
+            lin + mul(self.load(ram, R_D_RDIFF, 0), v_wall) - ldiff,
+            v_iir,
+        ) + ldiff;
+        self.store(ram, R_M_LDIFF, v);
+
+        let rdiff = self.load(ram, R_M_RDIFF, -1);
+        let v = mul(
+            rin + mul(self.load(ram, R_D_LDIFF, 0), v_wall) - rdiff,
+            v_iir,
+        ) + rdiff;
+        self.store(ram, R_M_RDIFF, v);
+
+        // Early echo (comb filter)
+        let comb = |reverb: &Reverb, ram: &[u16], m: [usize; 4]| -> i32 {
+            mul(reverb.load(ram, m[0], 0), reverb.vol(R_V_COMB1))
+                + mul(reverb.load(ram, m[1], 0), reverb.vol(R_V_COMB2))
+                + mul(reverb.load(ram, m[2], 0), reverb.vol(R_V_COMB3))
+                + mul(reverb.load(ram, m[3], 0), reverb.vol(R_V_COMB4))
+        };
+
+        let lout = comb(self, ram, [R_M_LCOMB1, R_M_LCOMB2, R_M_LCOMB3, R_M_LCOMB4]);
+        let rout = comb(self, ram, [R_M_RCOMB1, R_M_RCOMB2, R_M_RCOMB3, R_M_RCOMB4]);
+
+        // Late reverb (all pass filters)
+        let lout = self.all_pass(ram, lout, R_M_LAPF1, R_D_APF1, R_V_APF1);
+        let rout = self.all_pass(ram, rout, R_M_RAPF1, R_D_APF1, R_V_APF1);
+
+        let lout = self.all_pass(ram, lout, R_M_LAPF2, R_D_APF2, R_V_APF2);
+        let rout = self.all_pass(ram, rout, R_M_RAPF2, R_D_APF2, R_V_APF2);
+
+        self.output = [clamp16(lout), clamp16(rout)];
+
+        // Move to the next position in the work area
+        let len = SPU_RAM_SIZE as u32 - self.base;
+        self.pos = (self.pos + 1) % len;
+    }
+
+    fn all_pass(&self, ram: &mut [u16], input: i32, m: usize, d: usize, v: usize) -> i32 {
+        let v = self.vol(v);
+        let delayed = self.load(ram, m, -4 * i32::from(self.regs[d]));
+
+        let out = input - mul(delayed, v);
+        self.store(ram, m, out);
+
+        mul(out, v) + delayed
+    }
+
+    /// Returns the signed volume in config register `reg`
+    fn vol(&self, reg: usize) -> i16 {
+        self.regs[reg] as i16
+    }
+
+    /// Returns the index in SPU RAM of the offset in config register `reg`, plus `delta`
+    /// halfwords
+    fn index(&self, reg: usize, delta: i32) -> usize {
+        let len = SPU_RAM_SIZE as i32 - self.base as i32;
+        let off = (self.regs[reg] as i32) * 4 + delta;
+
+        let rel = (self.pos as i32 + off).rem_euclid(len);
+
+        (self.base as i32 + rel) as usize
+    }
+
+    fn load(&self, ram: &[u16], reg: usize, delta: i32) -> i32 {
+        i32::from(ram[self.index(reg, delta)] as i16)
+    }
+
+    fn store(&self, ram: &mut [u16], reg: usize, v: i32) {
+        ram[self.index(reg, 0)] = clamp16(v) as u16;
+    }
+}
+
+/// Multiply `a` by the signed 1.15 fixed point value `v`
+fn mul(a: i32, v: i16) -> i32 {
+    (a * i32::from(v)) >> 15
+}
+
+fn clamp16(v: i32) -> i32 {
+    v.clamp(i16::MIN as i32, i16::MAX as i32)
+}
+
+// Configuration registers, in the same order as on the PSX. The `R_D_*` and `R_M_*` registers
+// contain offsets in the work area, the `R_V_*` are signed volumes.
+const R_D_APF1: usize = 0;
+const R_D_APF2: usize = 1;
+const R_V_IIR: usize = 2;
+const R_V_COMB1: usize = 3;
+const R_V_COMB2: usize = 4;
+const R_V_COMB3: usize = 5;
+const R_V_COMB4: usize = 6;
+const R_V_WALL: usize = 7;
+const R_V_APF1: usize = 8;
+const R_V_APF2: usize = 9;
+const R_M_LSAME: usize = 10;
+const R_M_RSAME: usize = 11;
+const R_M_LCOMB1: usize = 12;
+const R_M_RCOMB1: usize = 13;
+const R_M_LCOMB2: usize = 14;
+const R_M_RCOMB2: usize = 15;
+const R_D_LSAME: usize = 16;
+const R_D_RSAME: usize = 17;
+const R_M_LDIFF: usize = 18;
+const R_M_RDIFF: usize = 19;
+const R_M_LCOMB3: usize = 20;
+const R_M_RCOMB3: usize = 21;
+const R_M_LCOMB4: usize = 22;
+const R_M_RCOMB4: usize = 23;
+const R_D_LDIFF: usize = 24;
+const R_D_RDIFF: usize = 25;
+const R_M_LAPF1: usize = 26;
+const R_M_RAPF1: usize = 27;
+const R_M_LAPF2: usize = 28;
+const R_M_RAPF2: usize = 29;
+const R_V_LIN: usize = 30;
+const R_V_RIN: usize = 31;
+
+#[test]
+fn test_reverb_echo() {
+    let mut ram = vec![0u16; SPU_RAM_SIZE];
+    let mut reverb = Reverb::new();
+
+    // Simple single echo: the input is written 10 * 4 steps ahead of the comb filter tap, then
+    // each all pass filter adds 1 * 4 steps of delay without feedback.
+    let mut regs = [0u16; 32];
+    regs[R_V_LIN] = 0x7fff;
+    regs[R_V_RIN] = 0x7fff;
+    regs[R_V_IIR] = 0x7fff;
+    regs[R_V_COMB1] = 0x7fff;
+    regs[R_M_LSAME] = 10;
+    regs[R_M_LCOMB1] = 0;
+    regs[R_M_RSAME] = 30;
+    regs[R_M_RCOMB1] = 20;
+    regs[R_M_LDIFF] = 200;
+    regs[R_M_RDIFF] = 300;
+    regs[R_D_APF1] = 1;
+    regs[R_D_APF2] = 1;
+    regs[R_M_LAPF1] = 1000;
+    regs[R_M_RAPF1] = 2000;
+    regs[R_M_LAPF2] = 3000;
+    regs[R_M_RAPF2] = 4000;
+
+    for (i, r) in regs.chunks_exact(2).enumerate() {
+        reverb.set_regs(i, u32::from(r[0]) | (u32::from(r[1]) << 16));
+    }
+
+    reverb.set_base(0x7_0000);
+    reverb.set_volume(0x4000, -0x4000);
+
+    // The reverb is disabled, nothing should happen
+    assert_eq!(reverb.run_cycle(&mut ram, [0x1000, 0x1000]), [0, 0]);
+    assert!(ram.iter().all(|&v| v == 0));
+
+    reverb.set_enabled(true);
+
+    let mut echoes = Vec::new();
+
+    for cycle in 0..200 {
+        let input = if cycle < 2 { [0x1000, 0x1000] } else { [0, 0] };
+
+        let out = reverb.run_cycle(&mut ram, input);
+
+        if out != [0, 0] {
+            echoes.push((cycle, out));
+        }
+    }
+
+    // 48 reverb steps at 22.05kHz, the output is held for two cycles
+    assert_eq!(echoes, [(97, [2046, -2047]), (98, [2046, -2047])]);
+
+    // Nothing should have been written outside of the work area
+    assert!(ram[..(0x7_0000 >> 1)].iter().all(|&v| v == 0));
+}
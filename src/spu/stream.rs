@@ -0,0 +1,163 @@
+//! PCM streaming channel: the CPU (or the DMA) pushes raw 16bit stereo samples into a FIFO that's
+//! mixed in at 44.1kHz, bypassing SPU RAM and the ADPCM decoders entirely.
+
+use crate::fifo::Fifo;
+use nr32_common::regs::spu as regs;
+
+pub struct Stream {
+    /// True if the stream is being played. When disabled the FIFO isn't consumed.
+    enabled: bool,
+    /// True if we should raise an IRQ when the FIFO drops below half full
+    irq_enabled: bool,
+    /// Stream volume left
+    volume_left: i16,
+    /// Stream volume right
+    volume_right: i16,
+    /// Sample FIFO. Each entry is a stereo frame with the left sample in the high halfword.
+    fifo: Fifo<STREAM_FIFO_LEN, u32>,
+}
+
+impl Stream {
+    pub fn new() -> Stream {
+        Stream {
+            enabled: false,
+            irq_enabled: false,
+            volume_left: 0,
+            volume_right: 0,
+            fifo: Fifo::new(),
+        }
+    }
+
+    pub fn set_control(&mut self, val: u32) {
+        self.enabled = val & regs::STREAM_CONTROL_ENABLE != 0;
+        self.irq_enabled = val & regs::STREAM_CONTROL_IRQ_ENABLE != 0;
+
+        if val & regs::STREAM_CONTROL_CLEAR != 0 {
+            self.fifo.clear();
+        }
+    }
+
+    pub fn control(&self) -> u32 {
+        let mut ctrl = 0;
+
+        if self.enabled {
+            ctrl |= regs::STREAM_CONTROL_ENABLE;
+        }
+
+        if self.irq_enabled {
+            ctrl |= regs::STREAM_CONTROL_IRQ_ENABLE;
+        }
+
+        ctrl
+    }
+
+    pub fn set_volume(&mut self, left: i16, right: i16) {
+        self.volume_left = left;
+        self.volume_right = right;
+    }
+
+    pub fn volume(&self) -> (i16, i16) {
+        (self.volume_left, self.volume_right)
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.fifo.is_full()
+    }
+
+    /// Number of frames in the FIFO
+    pub fn fill_level(&self) -> usize {
+        self.fifo.len()
+    }
+
+    /// Push a stereo frame (left sample in the high halfword). Frames pushed while the FIFO is full
+    /// are dropped.
+    pub fn push(&mut self, frame: u32) {
+        self.fifo.push(frame);
+    }
+
+    /// Returns the number of audio cycles until the FIFO drops below half full, if the IRQ is
+    /// enabled and it hasn't already.
+    pub fn cycles_to_irq(&self) -> Option<usize> {
+        if !self.enabled || !self.irq_enabled {
+            return None;
+        }
+
+        let len = self.fifo.len();
+
+        (len >= STREAM_FIFO_HALF).then(|| len - STREAM_FIFO_HALF + 1)
+    }
+
+    /// Run one 44.1kHz audio cycle. Returns the stream's output and true if the half-empty IRQ
+    /// should be raised.
+    ///
+    /// If the FIFO underruns the stream outputs silence.
+    pub fn run_cycle(&mut self) -> ([i32; 2], bool) {
+        if !self.enabled {
+            return ([0, 0], false);
+        }
+
+        let Some(frame) = self.fifo.pop() else {
+            return ([0, 0], false);
+        };
+
+        let left = i32::from((frame >> 16) as i16);
+        let right = i32::from(frame as i16);
+
+        let irq = self.irq_enabled && self.fifo.len() == STREAM_FIFO_HALF - 1;
+
+        (
+            [
+                (left * i32::from(self.volume_left)) >> 15,
+                (right * i32::from(self.volume_right)) >> 15,
+            ],
+            irq,
+        )
+    }
+}
+
+/// Stream FIFO length in stereo frames (about 23ms of audio)
+const STREAM_FIFO_LEN: usize = 1024;
+const STREAM_FIFO_HALF: usize = STREAM_FIFO_LEN / 2;
+
+#[test]
+fn test_stream_half_empty_irq() {
+    let mut stream = Stream::new();
+
+    stream.set_volume(i16::MAX, i16::MAX);
+
+    for i in 0..STREAM_FIFO_LEN {
+        stream.push(((i as u32) << 16) | 0xffff);
+    }
+
+    assert!(stream.is_full());
+
+    // Stream disabled: nothing is consumed
+    assert_eq!(stream.run_cycle(), ([0, 0], false));
+    assert_eq!(stream.fill_level(), STREAM_FIFO_LEN);
+
+    stream.set_control(regs::STREAM_CONTROL_ENABLE | regs::STREAM_CONTROL_IRQ_ENABLE);
+
+    assert_eq!(stream.cycles_to_irq(), Some(STREAM_FIFO_HALF + 1));
+
+    let mut irqs = Vec::new();
+
+    for cycle in 0..(STREAM_FIFO_LEN + 10) {
+        let (out, irq) = stream.run_cycle();
+
+        if cycle == 2 {
+            assert_eq!(out, [1, -1]);
+        }
+
+        if cycle >= STREAM_FIFO_LEN {
+            // Underrun
+            assert_eq!(out, [0, 0]);
+        }
+
+        if irq {
+            irqs.push(cycle);
+        }
+    }
+
+    assert_eq!(irqs, [STREAM_FIFO_HALF]);
+    assert_eq!(stream.cycles_to_irq(), None);
+}
@@ -1,5 +1,6 @@
 //! RISC-V system timer, running at 48kHz
 use crate::{CPU_FREQ, CycleCounter, NoRa32, cpu, sync};
+use nr32_common::regs::sys_timer as regs;
 
 pub struct Timer {
     /// Counter incrementing every MTIME tick. Considered to never overflow.
@@ -55,10 +56,10 @@ pub fn load_word(m: &mut NoRa32, off: u32) -> u32 {
     let t = &m.systimer;
 
     match off {
-        0x0 => t.mtime as u32,
-        0x4 => (t.mtime >> 32) as u32,
-        0x8 => t.mtimecmp as u32,
-        0xc => (t.mtimecmp >> 32) as u32,
+        regs::MTIME_L => t.mtime as u32,
+        regs::MTIME_H => (t.mtime >> 32) as u32,
+        regs::MTIMECMP_L => t.mtimecmp as u32,
+        regs::MTIMECMP_H => (t.mtimecmp >> 32) as u32,
         _ => !0,
     }
 }
@@ -69,19 +70,19 @@ pub fn store_word(m: &mut NoRa32, off: u32, v: u32) {
     let t = &mut m.systimer;
 
     match off {
-        0x0 => {
+        regs::MTIME_L => {
             t.mtime &= !0xffff_ffffu64;
             t.mtime |= u64::from(v);
         }
-        0x4 => {
+        regs::MTIME_H => {
             t.mtime &= !(0xffff_ffffu64 << 32);
             t.mtime |= u64::from(v) << 32;
         }
-        0x8 => {
+        regs::MTIMECMP_L => {
             t.mtimecmp &= !0xffff_ffffu64;
             t.mtimecmp |= u64::from(v);
         }
-        0xc => {
+        regs::MTIMECMP_H => {
             t.mtimecmp &= !(0xffff_ffffu64 << 32);
             t.mtimecmp |= u64::from(v) << 32;
         }